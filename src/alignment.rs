@@ -1,15 +1,170 @@
+use std::fmt::Debug;
+use std::ops::{Add, BitAnd, Deref, DerefMut, Not, Rem, Sub};
 
-pub fn align_up(value: u64, align: u64) -> u64 {
-    assert!(align > 0);
-    assert!(value <= u64::MAX - align);
+/// Unsigned integer types values can be aligned in, e.g. u64 offsets and usize buffer lengths
+pub trait Unsigned:
+    Copy + Debug + Ord + Add<Output = Self> + Sub<Output = Self> + Rem<Output = Self>
+    + BitAnd<Output = Self> + Not<Output = Self>
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const MAX: Self;
 
-    if value % align == 0 { value }
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn is_power_of_two(self) -> bool;
+}
+
+macro_rules! impl_unsigned {
+    ($($type:ty),*) => {
+        $(
+            impl Unsigned for $type {
+                const ZERO: Self = 0;
+                const ONE: Self = 1;
+                const MAX: Self = <$type>::MAX;
+
+                fn checked_add(self, other: Self) -> Option<Self> {
+                    <$type>::checked_add(self, other)
+                }
+
+                fn is_power_of_two(self) -> bool {
+                    <$type>::is_power_of_two(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128, usize);
+
+/// Rounds `value` up to the next multiple of `align`, which doesn't have to be a power of two
+pub fn align_up<T: Unsigned>(value: T, align: T) -> T {
+    assert!(align > T::ZERO);
+    assert!(value <= T::MAX - align);
+
+    if value % align == T::ZERO { value }
     else { value + (align - (value % align)) }
 }
 
-pub fn align_down(value: u64, align: u64) -> u64 {
-    assert!(align > 0);
+/// Like [align_up], but masks instead of dividing if `align` is a power of two, as partition
+/// alignments and logical block sizes are. Other alignments take the path of [align_up].
+pub fn align_up_pow2<T: Unsigned>(value: T, align: T) -> T {
+    if !align.is_power_of_two() {
+        return align_up(value, align)
+    }
+    assert!(value <= T::MAX - align);
 
-    if value % align == 0 { value }
+    let mask = align - T::ONE;
+    (value + mask) & !mask
+}
+
+/// Rounds `value` down to the previous multiple of `align`
+pub fn align_down<T: Unsigned>(value: T, align: T) -> T {
+    assert!(align > T::ZERO);
+
+    if value % align == T::ZERO { value }
     else { value - (value % align) }
 }
+
+/// Like [align_up], but returns `None` instead of panicking if the result doesn't fit into `T`
+pub fn checked_align_up<T: Unsigned>(value: T, align: T) -> Option<T> {
+    if align == T::ZERO {
+        return None
+    }
+
+    match value % align {
+        remainder if remainder == T::ZERO => Some(value),
+        remainder => value.checked_add(align - remainder),
+    }
+}
+
+/// Like [align_down], but returns `None` instead of panicking if `align` is zero
+pub fn checked_align_down<T: Unsigned>(value: T, align: T) -> Option<T> {
+    if align == T::ZERO {
+        return None
+    }
+
+    Some(value - (value % align))
+}
+
+/// Zeroed heap buffer whose start is aligned to `align` bytes, as required by O_DIRECT
+pub struct AlignedBuffer {
+    storage: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize, align: usize) -> Self {
+        assert!(align > 0);
+
+        let storage = vec![0_u8; len + align];
+        let offset = storage.as_ptr().align_offset(align);
+        AlignedBuffer { storage, offset, len }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligns_u64_to_any_alignment() {
+        assert_eq!(align_up(0_u64, 3), 0);
+        assert_eq!(align_up(10_u64, 3), 12);
+        assert_eq!(align_up(12_u64, 3), 12);
+        assert_eq!(align_up(1_000_001_u64, 1000), 1_001_000);
+        assert_eq!(align_down(10_u64, 3), 9);
+        assert_eq!(align_down(999_u64, 1000), 0);
+        assert_eq!(checked_align_up(u64::MAX - 1, 3), Some(u64::MAX));
+        assert_eq!(checked_align_up(u64::MAX, 10), None);
+        assert_eq!(checked_align_up(10_u64, 0), None);
+        assert_eq!(checked_align_down(10_u64, 6), Some(6));
+    }
+
+    #[test]
+    fn aligns_usize_to_any_alignment() {
+        assert_eq!(align_up(5_usize, 6), 6);
+        assert_eq!(align_up(4097_usize, 4096), 8192);
+        assert_eq!(align_up(100_usize, 12), 108);
+        assert_eq!(align_down(100_usize, 12), 96);
+        assert_eq!(checked_align_up(usize::MAX, 7), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn align_up_rejects_a_zero_alignment() {
+        align_up(10_u64, 0);
+    }
+
+    #[test]
+    fn align_up_pow2_agrees_with_align_up() {
+        // Every alignment up to 4 KiB, powers of two or not, against values around its multiples
+        for align in 1..=4096_u64 {
+            for multiple in [0, 1, 2, 7, 1000] {
+                for offset in [0, 1, align / 2, align - 1] {
+                    let value = multiple * align + offset;
+                    assert_eq!(align_up_pow2(value, align), align_up(value, align));
+                    let value = value as usize;
+                    let align = align as usize;
+                    assert_eq!(align_up_pow2(value, align), align_up(value, align));
+                }
+            }
+        }
+        let value = u64::MAX - 4096;
+        assert_eq!(align_up_pow2(value, 4096), align_up(value, 4096));
+    }
+}
@@ -0,0 +1,55 @@
+use std::io;
+use std::io::Read;
+
+/// Start of the header of vbmeta images, see libavb/avb_vbmeta_image.h in AOSP
+const AVB_MAGIC: &[u8; 4] = b"AVB0";
+/// The flags are a big-endian u32 at offset 120, all flags live in its last byte
+const FLAGS_OFFSET: u64 = 123;
+
+/// The bootloader doesn't verify the images described by vbmeta
+pub const FLAG_VERIFICATION_DISABLED: u32 = 0x1;
+/// dm-verity isn't set up for the partitions described by vbmeta
+pub const FLAG_HASHTREE_DISABLED: u32 = 0x2;
+
+/// Whether a partition holds the top-level vbmeta image, whose flags the bootloader reads.
+/// Chained images like vbmeta_system are left alone, like fastboot does.
+pub fn is_vbmeta_partition(name: &str) -> bool {
+    matches!(name, "vbmeta" | "vbmeta_a" | "vbmeta_b")
+}
+
+/// Checks that `reader` starts with the header of a vbmeta image
+pub fn check_vbmeta(mut reader: impl Read) -> io::Result<Result<(), String>> {
+    let mut magic = [0u8; 4];
+    match reader.read_exact(&mut magic) {
+        Ok(()) if &magic == AVB_MAGIC => Ok(Ok(())),
+        Ok(()) => Ok(Err("it doesn't start with the AVB0 magic of vbmeta images".into())),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof =>
+            Ok(Err("it is smaller than the AVB header".into())),
+        Err(err) => Err(err),
+    }
+}
+
+/// Sets `flags` in the header of the vbmeta image read through it, the source stays untouched
+pub struct FlagPatcher<R> {
+    reader: R,
+    flags: u32,
+    position: u64,
+}
+
+impl<R: Read> FlagPatcher<R> {
+    pub fn new(reader: R, flags: u32) -> Self {
+        FlagPatcher { reader, flags, position: 0 }
+    }
+}
+
+impl<R: Read> Read for FlagPatcher<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        let start = self.position;
+        self.position += read as u64;
+        if (start..self.position).contains(&FLAGS_OFFSET) {
+            buf[(FLAGS_OFFSET - start) as usize] |= self.flags as u8;
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+use sha2::{Digest, Sha256};
+
+const SHA256_DIGEST_SIZE: usize = 32;
+
+/// Expected digest of a source image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Sha256(Vec<u8>),
+}
+
+impl Checksum {
+    /// Parses a hex encoded digest for the given algorithm, e.g. "sha256" and "e3b0c442…"
+    pub fn parse(algorithm: &str, digest: &str) -> Result<Self, String> {
+        let bytes = decode_hex(digest)
+            .ok_or_else(|| format!("Invalid {} digest: {}", algorithm, digest))?;
+        match algorithm.to_ascii_lowercase().as_str() {
+            "sha256" if bytes.len() == SHA256_DIGEST_SIZE => Ok(Checksum::Sha256(bytes)),
+            "sha256" => Err(format!(
+                "Invalid sha256 digest, expected {} hex characters: {}",
+                SHA256_DIGEST_SIZE * 2, digest
+            )),
+            _ => Err(format!("Unsupported checksum algorithm: {}", algorithm)),
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Checksum::Sha256(_) => "sha256",
+        }
+    }
+
+    /// Hashes the file at `path` with the same algorithm as this checksum
+    pub fn compute(&self, path: impl AsRef<Path>) -> io::Result<Checksum> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0_u8; 1024 * 1024];
+        match self {
+            Checksum::Sha256(_) => {
+                let mut hasher = Sha256::new();
+                loop {
+                    let read = file.read(&mut buf)?;
+                    if read == 0 {
+                        break
+                    }
+                    hasher.update(&buf[..read]);
+                }
+                Ok(Checksum::Sha256(hasher.finalize().to_vec()))
+            },
+        }
+    }
+}
+
+/// Checksums are serialized as their hex digest
+impl Serialize for Checksum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Checksums are deserialized from their hex digest, which has to be a SHA-256 one
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let digest = String::deserialize(deserializer)?;
+        Checksum::parse("sha256", &digest).map_err(D::Error::custom)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::Sha256(digest) => {
+                for byte in digest {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None
+    }
+    (0..hex.len()).step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect()
+}
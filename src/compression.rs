@@ -0,0 +1,257 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Deflate compresses at most this much, so a gzip file expands to at most that many times
+/// its size
+const DEFLATE_MAX_RATIO: u64 = 1032;
+/// The ISIZE trailer of gzip only stores the size modulo 2^32
+const GZIP_ISIZE_LIMIT: u64 = 1 << 32;
+
+// https://tukaani.org/xz/xz-file-format.txt
+const XZ_HEADER_SIZE: u64 = 12;
+const XZ_FOOTER_SIZE: u64 = 12;
+const XZ_FOOTER_MAGIC: [u8; 2] = [b'Y', b'Z'];
+
+// https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md
+const ZSTD_SKIPPABLE_MAGIC_MASK: u32 = 0xfffffff0;
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184d2a50;
+const ZSTD_BLOCK_HEADER_SIZE: i64 = 3;
+const ZSTD_BLOCK_TYPE_RLE: u32 = 1;
+const ZSTD_CHECKSUM_SIZE: i64 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+/// Detects the compression of a source image by its suffix or, failing that, its magic bytes
+pub fn detect_compression(path: impl AsRef<Path>) -> io::Result<Compression> {
+    let path = path.as_ref();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => return Ok(Compression::Gzip),
+        Some("xz") => return Ok(Compression::Xz),
+        Some("zst") | Some("zstd") => return Ok(Compression::Zstd),
+        _ => {}
+    }
+
+    let mut magic = [0_u8; XZ_MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => {},
+        // Too short to be compressed
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(Compression::None),
+        Err(err) => return Err(err),
+    }
+
+    if magic.starts_with(&GZIP_MAGIC) {
+        Ok(Compression::Gzip)
+    } else if magic == XZ_MAGIC {
+        Ok(Compression::Xz)
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Ok(Compression::Zstd)
+    } else {
+        Ok(Compression::None)
+    }
+}
+
+/// Returns the size of the image once decompressed.
+///
+/// gzip only stores the size modulo 2^32 and only for its last member, so small gzip files
+/// are decompressed to count their bytes. Files that could expand to 4 GiB or more are
+/// rejected, their size has to be given. For xz the sizes recorded in the index of every
+/// stream are summed up, for zstd the content sizes of all frame headers.
+pub fn uncompressed_size(path: impl AsRef<Path>, compression: Compression) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    match compression {
+        Compression::None => Ok(file.metadata()?.len()),
+        Compression::Gzip => {
+            if file.metadata()?.len().saturating_mul(DEFLATE_MAX_RATIO) >= GZIP_ISIZE_LIMIT {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "gzip doesn't record sizes of 4 GiB or more and this file may be that large",
+                ))
+            }
+            io::copy(&mut MultiGzDecoder::new(BufReader::new(file)), &mut io::sink())
+        }
+        Compression::Xz => xz_uncompressed_size(&mut file),
+        Compression::Zstd => zstd_uncompressed_size(&mut file),
+    }
+}
+
+/// Opens a source image for reading, decompressing it on the fly if necessary
+pub fn open_decompressed(
+    path: impl AsRef<Path>,
+    compression: Compression
+) -> io::Result<Box<dyn Read + Send>> {
+    let file = File::open(path)?;
+    Ok(match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(BufReader::new(file))),
+        Compression::Zstd => Box::new(ZstdDecoder::new(file)?),
+    })
+}
+
+fn invalid_xz(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid xz file: {}", message))
+}
+
+fn read_xz_vli(bytes: &mut impl Iterator<Item = u8>) -> io::Result<u64> {
+    let mut value = 0_u64;
+    // A variable-length integer is at most 9 bytes long
+    for i in 0..9 {
+        let byte = bytes.next().ok_or_else(|| invalid_xz("truncated index"))?;
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+    Err(invalid_xz("integer in index is too long"))
+}
+
+/// Walks the streams of an xz file backwards, starting at the last stream footer,
+/// and sums up the uncompressed sizes of all blocks recorded in their indexes.
+fn xz_uncompressed_size(file: &mut File) -> io::Result<u64> {
+    let mut stream_end = file.seek(SeekFrom::End(0))?;
+    let mut total_size = 0_u64;
+
+    while stream_end > 0 {
+        let mut footer = [0_u8; XZ_FOOTER_SIZE as usize];
+        if stream_end < XZ_HEADER_SIZE + XZ_FOOTER_SIZE {
+            return Err(invalid_xz("stream is too short"))
+        }
+        file.seek(SeekFrom::Start(stream_end - XZ_FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        // Skip stream padding, which consists of null bytes in multiples of four
+        if footer[8..12] == [0; 4] {
+            stream_end -= 4;
+            continue
+        }
+        if footer[10..12] != XZ_FOOTER_MAGIC {
+            return Err(invalid_xz("missing stream footer"))
+        }
+
+        let backward_size =
+            (u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]) as u64 + 1) * 4;
+        let index_start = (stream_end - XZ_FOOTER_SIZE).checked_sub(backward_size)
+            .ok_or_else(|| invalid_xz("index is larger than the stream"))?;
+
+        let mut index = vec![0_u8; backward_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+
+        let mut bytes = index.into_iter();
+        if bytes.next() != Some(0x00) {
+            return Err(invalid_xz("missing index indicator"))
+        }
+        let record_count = read_xz_vli(&mut bytes)?;
+        let mut blocks_size = 0_u64;
+        for _ in 0..record_count {
+            let unpadded_size = read_xz_vli(&mut bytes)?;
+            let uncompressed_size = read_xz_vli(&mut bytes)?;
+            // Blocks are padded to a multiple of four bytes
+            blocks_size = blocks_size.saturating_add((unpadded_size + 3) & !3);
+            total_size = total_size.checked_add(uncompressed_size)
+                .ok_or_else(|| invalid_xz("uncompressed size overflows"))?;
+        }
+
+        stream_end = index_start.checked_sub(blocks_size + XZ_HEADER_SIZE)
+            .ok_or_else(|| invalid_xz("blocks are larger than the stream"))?;
+    }
+
+    Ok(total_size)
+}
+
+fn invalid_zstd(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid zstd file: {}", message))
+}
+
+/// Walks all frames of a zstd file and sums up the content sizes stored in their headers.
+/// Fails if any frame doesn't record its content size.
+fn zstd_uncompressed_size(file: &mut File) -> io::Result<u64> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    let mut file = BufReader::new(file);
+    let mut position = file.seek(SeekFrom::Start(0))?;
+    let mut total_size = 0_u64;
+
+    while position < file_size {
+        let mut magic = [0_u8; 4];
+        file.read_exact(&mut magic)?;
+        let magic = u32::from_le_bytes(magic);
+
+        if magic & ZSTD_SKIPPABLE_MAGIC_MASK == ZSTD_SKIPPABLE_MAGIC {
+            let mut frame_size = [0_u8; 4];
+            file.read_exact(&mut frame_size)?;
+            file.seek_relative(u32::from_le_bytes(frame_size) as i64)?;
+            position = file.stream_position()?;
+            continue
+        }
+        if magic.to_le_bytes() != ZSTD_MAGIC {
+            return Err(invalid_zstd("unknown frame magic"))
+        }
+
+        let mut descriptor = [0_u8; 1];
+        file.read_exact(&mut descriptor)?;
+        let descriptor = descriptor[0];
+        let content_size_flag = descriptor >> 6;
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let dictionary_id_size = match descriptor & 0x03 {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 4,
+        };
+        let content_size_size = match (content_size_flag, single_segment) {
+            (0, false) => return Err(invalid_zstd("frame doesn't record its content size")),
+            (0, true) => 1,
+            (1, _) => 2,
+            (2, _) => 4,
+            _ => 8,
+        };
+
+        // Window descriptor is only present for multi-segment frames
+        let window_descriptor_size = if single_segment { 0 } else { 1 };
+        file.seek_relative(window_descriptor_size + dictionary_id_size)?;
+
+        let mut content_size = [0_u8; 8];
+        file.read_exact(&mut content_size[..content_size_size])?;
+        let mut content_size = u64::from_le_bytes(content_size);
+        if content_size_size == 2 {
+            content_size += 256;
+        }
+        total_size = total_size.checked_add(content_size)
+            .ok_or_else(|| invalid_zstd("uncompressed size overflows"))?;
+
+        // Skip all blocks to get to the next frame
+        loop {
+            let mut block_header = [0_u8; 4];
+            file.read_exact(&mut block_header[..ZSTD_BLOCK_HEADER_SIZE as usize])?;
+            let block_header = u32::from_le_bytes(block_header);
+            let last_block = block_header & 1 != 0;
+            let block_type = (block_header >> 1) & 0x03;
+            let block_size = if block_type == ZSTD_BLOCK_TYPE_RLE { 1 } else { block_header >> 3 };
+            file.seek_relative(block_size as i64)?;
+            if last_block {
+                break
+            }
+        }
+        if has_checksum {
+            file.seek_relative(ZSTD_CHECKSUM_SIZE)?;
+        }
+        position = file.stream_position()?;
+    }
+
+    Ok(total_size)
+}
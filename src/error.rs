@@ -0,0 +1,282 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::checksum::Checksum;
+use crate::mounts::Mount;
+use crate::sizes::BinarySize;
+
+/// Errors that can occur while flashing or formatting a destination
+#[derive(Debug, thiserror::Error)]
+pub enum FlashError {
+    /// The destination could not be opened, read or written
+    #[error("Could not access {}: {source}", .path.to_string_lossy())]
+    DeviceAccess { path: PathBuf, source: io::Error },
+    /// Information about the destination device could not be determined
+    #[error("Failed to get device info for {}: {message}", .path.to_string_lossy())]
+    DeviceInfo { path: PathBuf, message: String },
+    /// Overwriting a block device needs confirmation, but there is nobody to ask
+    #[error(
+        "Refusing to overwrite {} without confirmation, pass --yes to skip the prompt",
+        .path.to_string_lossy()
+    )]
+    NotConfirmed { path: PathBuf },
+    /// Asking the user for confirmation failed
+    #[error("Failed to ask for confirmation: {0}")]
+    Prompt(#[source] io::Error),
+    /// The user declined to overwrite the destination
+    #[error("Aborted by user")]
+    Aborted,
+    /// Filesystems on the destination are mounted
+    #[error(
+        "Destination {} is mounted ({}), unmount it first or pass --force",
+        .path.to_string_lossy(), mount_list(.mounts)
+    )]
+    DestinationMounted { path: PathBuf, mounts: Vec<Mount> },
+    /// The destination holds the running system
+    #[error(
+        "Refusing to overwrite {}, it holds {} of the running system \
+        (pass --force if you really want to)",
+        .path.to_string_lossy(), .mount_point.to_string_lossy()
+    )]
+    SystemDisk { path: PathBuf, mount_point: PathBuf },
+    /// A source image does not exist
+    #[error("Source file {} does not exist", .path.to_string_lossy())]
+    SourceMissing { path: PathBuf },
+    /// A source image could not be opened or read
+    #[error("Could not read source file {}: {source}", .path.to_string_lossy())]
+    SourceAccess { path: PathBuf, source: io::Error },
+    /// The size of a source image could not be determined
+    #[error(
+        "Failed to determine size of source file {}, \
+        specify it explicitly using name:file:size: {source}",
+        .path.to_string_lossy()
+    )]
+    SourceSize { path: PathBuf, source: io::Error },
+    /// A source image doesn't match its expected checksum
+    #[error(
+        "Checksum mismatch for source file {}: expected {} {expected}, got {actual}",
+        .path.to_string_lossy(), .expected.algorithm()
+    )]
+    ChecksumMismatch { path: PathBuf, expected: Checksum, actual: Checksum },
+    /// A partition does not fit onto the destination anymore
+    #[error("Could not add partition {partition}, size {}: {source}", rounded(*.size))]
+    LayoutTooLarge { partition: String, size: u64, source: io::Error },
+    /// The partitions are larger than the destination, `required` is `None` if they exceed a u64
+    #[error("{}", layout_does_not_fit(.path, *.required, *.available))]
+    LayoutDoesNotFit { path: PathBuf, required: Option<u64>, available: u64 },
+    /// No size has been given for an image file destination
+    #[error("{}", missing_image_size(.path, *.required))]
+    MissingImageSize { path: PathBuf, required: Option<u64> },
+    /// A size is too large to be aligned to the required boundary
+    #[error("Size of partition {partition} ({size} bytes) is too large")]
+    SizeOverflow { partition: String, size: u64 },
+    /// A source image is larger than the partition it is written to. The size of the image
+    /// isn't always known before it has been read.
+    #[error("{}", image_too_large(.partition, .path, *.size, *.available))]
+    ImageTooLarge { partition: String, path: PathBuf, size: Option<u64>, available: u64 },
+    /// The partition table could not be read
+    #[error("Failed to read partition table of {}: {source}", .path.to_string_lossy())]
+    GptRead { path: PathBuf, source: io::Error },
+    /// The partition table could not be created or written
+    #[error("Failed to write partition table to {}: {source}", .path.to_string_lossy())]
+    GptWrite { path: PathBuf, source: io::Error },
+    /// The partition table that has been written is not consistent when read back
+    #[error("Partition table of {} is invalid: {message}", .path.to_string_lossy())]
+    InvalidPartitionTable { path: PathBuf, message: String },
+    /// A partition could not be found in the partition table
+    #[error("Could not find partition {name}")]
+    PartitionNotFound { name: String },
+    /// A partition to keep is not part of the existing partition table
+    #[error("Partition {name} to keep does not exist on {}", .path.to_string_lossy())]
+    KeptPartitionMissing { path: PathBuf, name: String },
+    /// A partition to keep overlaps a partition of the new layout or has the same name
+    #[error("{}", kept_partition_conflict(.kept, .partition))]
+    KeptPartitionConflict { kept: String, partition: String },
+    /// A partition to keep reaches into the backup partition table of the destination
+    #[error("Partition {kept} to keep doesn't fit onto {} anymore", .path.to_string_lossy())]
+    KeptPartitionBeyondEnd { path: PathBuf, kept: String },
+    /// The space to reserve at the end of the destination is larger than the destination
+    #[error("Can't reserve {}, the destination isn't that large", rounded(*.reserve))]
+    ReservedSpaceTooLarge { reserve: u64 },
+    /// A partition ends in the space reserved at the end of the destination
+    #[error(
+        "Partition {partition} reaches into the {} reserved at the end of the destination",
+        rounded(*.reserve)
+    )]
+    ReservedSpaceUsed { partition: String, reserve: u64 },
+    /// A partition to delete is mounted
+    #[error(
+        "Partition {name} is mounted on {}, unmount it first", .mount_point.to_string_lossy()
+    )]
+    PartitionMounted { name: String, mount_point: PathBuf },
+    /// A partition to append has the same name as one in the existing partition table
+    #[error(
+        "Partition {name} already exists on {}, pass --replace {name} to replace it",
+        .path.to_string_lossy()
+    )]
+    PartitionExists { path: PathBuf, name: String },
+    /// A partition to replace while appending doesn't exist or isn't being written
+    #[error("Can't replace partition {name}: {message}")]
+    ReplacedPartitionInvalid { name: String, message: String },
+    /// A partition can't be resized to the size it has been given
+    #[error("Can't resize partition {name}: {message}")]
+    ResizeInvalid { name: String, message: String },
+    /// A partition can't be placed at the start it has been given
+    #[error("Partition {partition} can't start at {start:#x}: {message}")]
+    PartitionStartInvalid { partition: String, start: u64, message: String },
+    /// The alignment of a partition is not a multiple of the logical block size
+    #[error(
+        "Alignment of partition {partition} ({alignment} bytes) must be a multiple of \
+        {lba_size} bytes"
+    )]
+    PartitionAlignmentInvalid { partition: String, alignment: u64, lba_size: u64 },
+    /// A partition with a fixed start overlaps another partition
+    #[error("Partition {partition} overlaps partition {other}")]
+    PartitionOverlap { partition: String, other: String },
+    /// A U-Boot stage can't be put into an idbloader
+    #[error("Can't build an idbloader from {}: {message}", .path.to_string_lossy())]
+    BootStageInvalid { path: PathBuf, message: String },
+    /// The idbloader doesn't look like one, the boot ROM wouldn't boot from it
+    #[error(
+        "{} is not an idbloader: {message} (pass --skip-idbloader-check if it really is one)",
+        .path.to_string_lossy()
+    )]
+    IdbloaderInvalid { path: PathBuf, message: String },
+    /// The flags of an image that isn't a vbmeta image were to be patched
+    #[error(
+        "Can't disable verity or verification in {}, {message}", .path.to_string_lossy()
+    )]
+    VbmetaInvalid { path: PathBuf, message: String },
+    /// A partition can't get an entry in the MBR
+    #[error("Partition {partition} can't be added to the MBR: {message}")]
+    MbrEntryInvalid { partition: String, message: String },
+    /// Two partitions have been given the same unique GUID
+    #[error("Partitions {first} and {second} can't both have the GUID {guid}")]
+    DuplicatePartitionGuid { guid: String, first: String, second: String },
+    /// A partition can't be read from stdin the way it has been given
+    #[error("Partition {partition} can't be read from stdin: {message}")]
+    StdinSourceInvalid { partition: String, message: String },
+    /// Stdin went on after the size given for the partition it is written to
+    #[error("Stdin is longer than the {} given for partition {partition}", rounded(*.size))]
+    StdinTooLong { partition: String, size: u64 },
+    /// Reading a source image failed while writing it to its partition
+    #[error(
+        "Failed to read source file {} of partition {partition}: {source}",
+        .path.to_string_lossy()
+    )]
+    SourceReadFailed { partition: String, path: PathBuf, source: io::Error },
+    /// Writing to a partition failed
+    #[error("Failed to write partition {partition} at offset {offset:#x}: {source}")]
+    WriteFailed { partition: String, offset: u64, source: io::Error },
+    /// Reading a partition back failed
+    #[error("Failed to read back partition {partition} at offset {offset:#x}: {source}")]
+    ReadFailed { partition: String, offset: u64, source: io::Error },
+    /// The contents of some partitions differ from their source images
+    #[error("Verification failed for partition(s) {}", .partitions.join(", "))]
+    VerifyFailed { partitions: Vec<String> },
+    /// Partition devices didn't show up in time
+    #[error("Timed out waiting for {} to appear, retries: {retries}", path_list(.paths))]
+    DeviceTimeout { paths: Vec<PathBuf>, retries: u32 },
+    /// The destination could not be powered off
+    #[error("Failed to eject {}: {message}", .path.to_string_lossy())]
+    EjectFailed { path: PathBuf, message: String },
+    /// Creating a filesystem failed
+    #[error("Failed to format partition {partition} using mkfs.{filesystem}: {message}")]
+    MkfsFailed { partition: String, filesystem: String, message: String },
+    /// Programs needed for the requested operation are not installed
+    #[error("Missing programs, install them or add them to PATH: {}", .tools.join(", "))]
+    MissingTools { tools: Vec<String> },
+    /// rkdeveloptool couldn't talk to the board or reported an error
+    #[error("{message}")]
+    RockusbFailed { message: String },
+    /// Copying a directory onto a new filesystem failed
+    #[error("Failed to populate partition {partition}: {message}")]
+    PopulateFailed { partition: String, message: String },
+    /// The block size for writing images is not a multiple of the logical block size
+    #[error(
+        "Invalid block size {size}, must be a multiple of {lba_size} bytes and at least 1 KiB"
+    )]
+    InvalidBlockSize { size: usize, lba_size: u64 },
+    /// The region to flash at an offset doesn't fit the destination or isn't block aligned
+    #[error("Can't flash at offset {offset:#x}: {message}")]
+    OffsetInvalid { offset: u64, message: String },
+    /// Only logical block sizes of 512 and 4096 bytes are supported
+    #[error("Unsupported logical block size {size}, must be 512 or 4096 bytes")]
+    UnsupportedLbaSize { size: u64 },
+    /// The operation is not supported on this platform
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+impl FlashError {
+    /// Distinguishes missing source images from other errors while opening them
+    pub fn source_access(path: PathBuf, source: io::Error) -> Self {
+        if source.kind() == io::ErrorKind::NotFound {
+            FlashError::SourceMissing { path }
+        } else {
+            FlashError::SourceAccess { path, source }
+        }
+    }
+}
+
+fn rounded(size: u64) -> impl std::fmt::Display {
+    BinarySize::from(size).rounded()
+}
+
+fn mount_list(mounts: &[Mount]) -> String {
+    mounts.iter()
+        .map(|mount| format!("{} on {}", mount.source, mount.mount_point.display()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn path_list(paths: &[PathBuf]) -> String {
+    paths.iter()
+        .map(|path| path.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn layout_does_not_fit(path: &Path, required: Option<u64>, available: u64) -> String {
+    match required {
+        Some(required) => format!(
+            "Partitions need {} but {} is {}",
+            rounded(required), path.to_string_lossy(), rounded(available)
+        ),
+        None => format!(
+            "Partitions don't fit into {} ({})", path.to_string_lossy(), rounded(available)
+        ),
+    }
+}
+
+fn missing_image_size(path: &Path, required: Option<u64>) -> String {
+    match required {
+        Some(required) => format!(
+            "No size given for image file {}, pass --size with at least {}",
+            path.to_string_lossy(), rounded(required)
+        ),
+        None => format!("No size given for image file {}, pass --size", path.to_string_lossy()),
+    }
+}
+
+fn image_too_large(partition: &str, path: &Path, size: Option<u64>, available: u64) -> String {
+    match size {
+        Some(size) => format!(
+            "Image {} ({}) is {} larger than partition {} ({})",
+            path.to_string_lossy(), rounded(size), rounded(size.saturating_sub(available)),
+            partition, rounded(available)
+        ),
+        None => format!(
+            "Image {} does not fit into partition {} ({})",
+            path.to_string_lossy(), partition, rounded(available)
+        ),
+    }
+}
+
+fn kept_partition_conflict(kept: &str, partition: &str) -> String {
+    match kept == partition {
+        true => format!("Partition {} can't be kept and written at the same time", kept),
+        false => format!(
+            "Partition {} overlaps partition {}, which should be kept", partition, kept
+        ),
+    }
+}
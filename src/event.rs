@@ -0,0 +1,157 @@
+use std::path::PathBuf;
+use serde::Serialize;
+use crate::checksum::Checksum;
+use crate::mbr::MbrEntry;
+use crate::table::PartitionInfo;
+
+/// Progress reported by [crate::flash] while it works through a [crate::FlashPlan]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FlashEvent {
+    /// The plan contains neither partitions nor an IDBLoader
+    NothingToFlash,
+    /// The destination has been probed
+    Destination { path: PathBuf, size: u64, is_block_device: bool },
+    /// The checksum of a source image is being computed
+    VerifyingChecksum { name: String },
+    ChecksumVerified { name: String },
+    /// A filesystem of the destination is unmounted before it is overwritten, with force
+    Unmounting { source: String, mount_point: PathBuf },
+    Unmounted { mount_point: PathBuf },
+    /// Unmounting failed, the destination is overwritten anyway
+    UnmountFailed { mount_point: PathBuf, message: String },
+    /// Old loaders at the beginning of a block device are being erased
+    ErasingBeginning { size: u64 },
+    EraseProgress { erased: u64 },
+    ErasedBeginning,
+    /// The whole destination is being zeroed for a full wipe
+    WipingDestination { size: u64 },
+    WipeProgress { wiped: u64 },
+    DestinationWiped,
+    CreatingProtectiveMbr,
+    /// No protective MBR is written, the first block of the destination is left as it is
+    SkippingProtectiveMbr,
+    /// A partition is being added to the partition table
+    AddingPartition { name: String, size: u64 },
+    /// An image is written to a fixed offset without a partition table entry
+    AddingRawImage { name: String, offset: u64, size: u64 },
+    /// A partition of the existing partition table is left untouched
+    KeepingPartition { name: String, first_lba: u64, last_lba: u64 },
+    /// Space at the end of the destination is left unpartitioned
+    ReservingSpace { size: u64, first_lba: u64, last_lba: u64 },
+    /// The remaining space is being filled with a partition, userdata by default
+    AddingFillPartition { name: String, size: u64 },
+    /// A partition is grown into the remaining space instead of adding the fill partition
+    GrowingPartition { name: String, size: u64 },
+    /// Space after the last partition is left free, because no fill partition is wanted or
+    /// it is capped
+    LeavingFreeSpace { size: u64 },
+    /// A partition is removed from the existing partition table
+    DeletingPartition { name: String, first_lba: u64, last_lba: u64 },
+    /// The end of a partition in the existing partition table is moved
+    ResizingPartition { name: String, size: u64, new_size: u64 },
+    /// A partition gets the legacy BIOS bootable attribute
+    MarkingBootable { name: String },
+    WritingPartitionTable,
+    /// The partition table has been written and looks like this
+    PartitionTableWritten { disk_guid: String, lba_size: u64, partitions: Vec<PartitionInfo> },
+    /// A DOS partition table has been written instead of a GPT and looks like this
+    MbrPartitionTableWritten { disk_signature: String, lba_size: u64, entries: Vec<MbrEntry> },
+    /// The protective MBR has been replaced by a hybrid one with these entries
+    HybridMbrWritten { lba_size: u64, entries: Vec<MbrEntry> },
+    /// The destination doesn't support O_DIRECT, images are written through the page cache
+    DirectIoUnsupported { path: PathBuf },
+    /// Writing to a partition starts, `size` is only known if there is a source image
+    WritingPartition { name: String, size: Option<u64> },
+    /// Bytes of the source image written to the partition so far
+    WriteProgress { name: String, written: u64, total: u64 },
+    /// Writing to a partition failed, the destination is reopened and the write is retried
+    RetryingWrite { name: String, offset: u64, retries_left: u32, message: String },
+    /// The rest of the partition after the image is being zeroed
+    ClearingPartition { name: String, remaining: u64 },
+    ClearProgress { name: String, cleared: u64, total: u64 },
+    /// Buffered writes to the partition are being flushed to the destination
+    SyncingPartition { name: String },
+    /// The source image has been written to the partition, `sha256` is the checksum of the
+    /// data written, which is what the source decompresses to
+    PartitionWritten {
+        name: String,
+        written: u64,
+        offset: u64,
+        source_file: PathBuf,
+        sha256: Checksum,
+    },
+    /// A partition without source image has been cleared
+    PartitionCleared { name: String },
+    /// The space of a deleted partition is being cleared, with [FlashEvent::ClearProgress]
+    WipingPartition { name: String, size: u64 },
+    /// The space of a deleted partition has been cleared
+    PartitionWiped { name: String },
+    WriteFinished,
+    /// The written range of a partition is being read back, `size` bytes in total
+    VerifyingPartition { name: String, size: u64 },
+    VerifyProgress { name: String, verified: u64, total: u64 },
+    PartitionVerified { name: String, verified: u64 },
+    /// The data read back differs from the source image
+    PartitionVerifyFailed { name: String, mismatch: Mismatch },
+    /// A partition of the manifest is not part of the partition table
+    PartitionMissing { name: String },
+    VerifyFinished,
+    /// SPI NOR flash, or the flash of a board over rockusb, is being erased before it is written
+    ErasingFlash { size: u64 },
+    /// The flat image with the idbloader and U-Boot is being written to SPI flash
+    WritingSpiImage { size: u64 },
+    /// The idbloader is laid out for SD cards and is spread out for SPI flash
+    SpreadingIdbloader,
+    /// A loader is being downloaded to a board in maskrom mode, so its flash can be written
+    DownloadingLoader { path: PathBuf },
+    /// The layout built in an image file is being written to a board over USB
+    WritingRockusb { size: u64 },
+    RockusbProgress { written: u64 },
+    RockusbWritten,
+    /// The board is being reset after it has been written over USB
+    ResettingDevice,
+    /// The layout built in an image file is being copied to the region at `offset` of the
+    /// destination
+    WritingAtOffset { offset: u64, size: u64 },
+    OffsetProgress { written: u64 },
+    OffsetWritten,
+    /// Everything written is being flushed to the destination
+    FlushingDestination { path: PathBuf },
+    /// Flushing the destination failed and is retried
+    RetryingFlush { path: PathBuf, retries_left: u32, message: String },
+    /// The destination is being powered off
+    Ejecting { path: PathBuf },
+    /// The destination has been powered off and can be removed
+    Ejected { path: PathBuf },
+    FlashComplete,
+    /// The kernel is being asked to re-read the partition table
+    ProbingPartitions,
+    /// Re-reading the partition table failed, formatting is attempted anyway
+    ProbeFailed { message: String },
+    /// A partition device hasn't appeared yet, the partition table is reread until it does
+    WaitingForDevice { path: PathBuf },
+    /// A filesystem is being created on a partition
+    Formatting { name: String, filesystem: String, part_uuid: String },
+    /// The partition name is too long to be used as filesystem label, so it was shortened
+    LabelTruncated { name: String, filesystem: String, label: String },
+    /// A filesystem has been created on a partition
+    PartitionFormatted { name: String, filesystem: String },
+    /// Output of a failed mkfs run
+    MkfsOutput { filesystem: String, status: i32, stdout: String, stderr: String },
+    /// The contents of a directory are being copied onto a new filesystem
+    Populating { name: String, source: PathBuf },
+    /// A new filesystem holds the contents of its directory and has been unmounted again
+    PartitionPopulated { name: String },
+}
+
+/// How the data read back from a partition differs from what has been written to it
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Mismatch {
+    /// The first byte that differs, `offset` is counted from the start of the partition
+    Byte { offset: u64, expected: u8, actual: u8 },
+    /// Only the checksums are known to differ, as the source can't be read again, e.g. stdin
+    /// or the partitions of a manifest
+    Checksum { expected: Checksum, actual: Checksum },
+}
@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use crate::FIRST_PART_ALIGNMENT;
+
+/// Ranges of `file` that hold data, the holes of a sparse file are left out
+pub(crate) fn data_ranges(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut ranges = vec![];
+    let mut offset = 0;
+    while offset < len {
+        let data_start = unsafe { libc::lseek(file.as_raw_fd(), offset as _, libc::SEEK_DATA) };
+        if data_start < 0 {
+            match io::Error::last_os_error() {
+                // There is only a hole left
+                err if err.raw_os_error() == Some(libc::ENXIO) => break,
+                err => return Err(err),
+            }
+        }
+        let data_end = unsafe { libc::lseek(file.as_raw_fd(), data_start, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        ranges.push((data_start as u64, data_end as u64));
+        offset = data_end as u64;
+    }
+
+    Ok(ranges)
+}
+
+/// Merges the start of the flash or region into `ranges`, so old loaders there are overwritten
+/// with zeros like on a block device
+pub(crate) fn with_beginning(ranges: Vec<(u64, u64)>, len: u64) -> Vec<(u64, u64)> {
+    let beginning = FIRST_PART_ALIGNMENT.min(len);
+    let mut merged = vec![(0, beginning)];
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
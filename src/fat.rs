@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use fatfs::{format_volume, FormatVolumeOptions};
+use uuid::Uuid;
+use crate::partition::FormatPartitionDefinition;
+
+/// Filesystem names that mkfs.vfat would be run for
+const FAT_FILESYSTEMS: [&str; 2] = ["vfat", "fat"];
+const LABEL_LENGTH: usize = 11;
+
+/// The range of a partition in the destination, so a filesystem can be created in it without
+/// a device for the partition
+struct PartitionSlice {
+    file: File,
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+impl Read for PartitionSlice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let len = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = self.file.read_at(&mut buf[..len], self.start + self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for PartitionSlice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let len = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        if len == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "end of partition reached"))
+        }
+        let written = self.file.write_at(&buf[..len], self.start + self.position)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for PartitionSlice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, "seek to a negative or overflowing position"
+        ))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Whether the filesystem of `partition_to_format` is created without running mkfs. That's
+/// FAT without mkfs options, which would have to be understood otherwise.
+pub(crate) fn is_builtin_format(partition_to_format: &FormatPartitionDefinition) -> bool {
+    FAT_FILESYSTEMS.contains(&partition_to_format.format_as.as_str())
+        && partition_to_format.mkfs_args.is_empty()
+}
+
+/// Creates a FAT filesystem in the `size` bytes at `offset` of `destination`. The FAT type is
+/// chosen by size like mkfs.vfat does.
+pub(crate) fn format_fat(
+    destination: &Path,
+    offset: u64,
+    size: u64,
+    sector_size: u64,
+    label: &str,
+) -> io::Result<()> {
+    let file = File::options().read(true).write(true).open(destination)?;
+    let slice = PartitionSlice { file, start: offset, len: size, position: 0 };
+
+    let mut volume_label = [b' '; LABEL_LENGTH];
+    for (label_byte, byte) in volume_label.iter_mut().zip(label.bytes()) {
+        *label_byte = byte;
+    }
+    let total_sectors = u32::try_from(size / sector_size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "partition is too large"))?;
+    let options = FormatVolumeOptions::new()
+        .bytes_per_sector(sector_size as u16)
+        .total_sectors(total_sectors)
+        // Serial number shown as UUID in /dev/disk/by-uuid, mkfs.vfat picks a random one too
+        .volume_id(Uuid::new_v4().as_fields().0)
+        .volume_label(volume_label);
+
+    format_volume(slice, options)
+}
@@ -0,0 +1,340 @@
+use std::fs::{read, File};
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use sha2::{Digest, Sha256};
+use crate::alignment::align_up;
+use crate::error::FlashError;
+use crate::sizes::BinarySize;
+
+// Layout of the image mkimage creates with -T rksd, see tools/rkcommon.c in U-Boot.
+// Everything is counted in blocks of 512 bytes, the stages are padded to 2 KiB.
+const BLOCK_SIZE: usize = 512;
+const STAGE_ALIGNMENT: u64 = 2048;
+/// The header takes up the first 4 blocks, the first stage follows right after
+const INIT_OFFSET: usize = 4;
+/// Room for the second stage if the first one is the only one
+const MAX_BOOT_SIZE: usize = 512 * 1024;
+const HEADER_MAGIC: u32 = 0x0ff0aa55;
+/// Start of the unencrypted version 2 header of newer SoCs
+const HEADER_V2_MAGIC: &[u8; 4] = b"RKNS";
+/// The version 2 header takes up the same 4 blocks, its own hash starts at this offset
+const HEADER_V2_HASH_OFFSET: usize = 1536;
+/// Offset of the first of the entries describing the stages, each this large
+const HEADER_V2_ENTRIES_OFFSET: usize = 120;
+const HEADER_V2_ENTRY_SIZE: usize = 88;
+/// Hash type of the header and the stages
+const HEADER_V2_SHA256: u32 = 1;
+/// Start of files that are easily mistaken for an idbloader
+const FIT_MAGIC: &[u8; 4] = &[0xd0, 0x0d, 0xfe, 0xed];
+const LOADER_MAGIC: &[u8; 4] = b"LDR ";
+/// The header is always encrypted, the stages only on some older SoCs
+const RC4_KEY: [u8; 16] = [124, 78, 3, 4, 85, 5, 9, 7, 45, 44, 123, 56, 23, 13, 23, 17];
+
+/// Rockchip SoC the idbloader is built for
+#[derive(Clone, Debug)]
+pub struct Soc {
+    pub name: &'static str,
+    /// Replaces the first 4 bytes of the first stage, the boot ROM checks it. Not used with
+    /// the version 2 header.
+    pub magic: &'static [u8; 4],
+    /// Largest first stage the boot ROM loads into its SRAM
+    pub max_init_size: usize,
+    /// Whether the boot ROM expects the stages to be RC4 encrypted
+    pub rc4: bool,
+    /// Whether the boot ROM reads the version 2 header, which holds SHA-256 hashes of the
+    /// stages instead of the magic and is never encrypted
+    pub header_v2: bool,
+}
+
+const fn soc(
+    name: &'static str,
+    magic: &'static [u8; 4],
+    max_init_size: usize,
+    rc4: bool,
+) -> Soc {
+    Soc { name, magic, max_init_size, rc4, header_v2: false }
+}
+
+const fn soc_v2(name: &'static str, max_init_size: usize) -> Soc {
+    Soc { name, magic: b"RK35", max_init_size, rc4: false, header_v2: true }
+}
+
+/// SoCs an idbloader can be built for
+pub const SOCS: &[Soc] = &[
+    soc("px30", b"RK33", 0x2800, false),
+    soc("rk3036", b"RK30", 0x1000, false),
+    soc("rk3066", b"RK30", 0x8000 - 0x800, true),
+    soc("rk3128", b"RK31", 0x1800, false),
+    soc("rk3188", b"RK31", 0x8000 - 0x800, true),
+    soc("rk322x", b"RK32", 0x8000 - 0x1000, false),
+    soc("rk3288", b"RK32", 0x8000, false),
+    soc("rk3308", b"RK33", 0x40000 - 0x1000, false),
+    soc("rk3328", b"RK32", 0x8000 - 0x1000, false),
+    soc("rk3368", b"RK33", 0x8000 - 0x1000, false),
+    soc("rk3399", b"RK33", 0x30000 - 0x2000, false),
+    soc_v2("rk3588", 0x100000 - 0x1000),
+    soc("rv1108", b"RK11", 0x1800, false),
+    soc("rv1126", b"110B", 0x10000 - 0x1000, false),
+];
+
+/// Other SoCs that read the version 2 header, which isn't built for them yet
+const V2_SOCS: &[&str] = &["rk3528", "rk3562", "rk3568", "rk3576"];
+
+/// Looks up a SoC by name, e.g. rk3399
+pub fn find_soc(name: &str) -> Result<&'static Soc, String> {
+    let name = name.to_ascii_lowercase();
+    if let Some(soc) = SOCS.iter().find(|soc| soc.name == name) {
+        return Ok(soc)
+    }
+    if V2_SOCS.contains(&name.as_str()) {
+        return Err(format!(
+            "Building an idbloader for {} is not supported yet, use mkimage and pass \
+            the result using --idbloader", name
+        ))
+    }
+
+    let names: Vec<&str> = SOCS.iter().map(|soc| soc.name).collect();
+    Err(format!("Unknown SoC {}, supported are: {}", name, names.join(", ")))
+}
+
+/// Encrypts or decrypts `data` in place, RC4 is symmetric
+fn rc4(data: &mut [u8]) {
+    let mut state: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(RC4_KEY[i % RC4_KEY.len()]);
+        state.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    for byte in data {
+        i = i.wrapping_add(1);
+        j = j.wrapping_add(state[i as usize]);
+        state.swap(i as usize, j as usize);
+        *byte ^= state[state[i as usize].wrapping_add(state[j as usize]) as usize];
+    }
+}
+
+fn read_stage(path: &Path) -> Result<Vec<u8>, FlashError> {
+    read(path).map_err(|err| FlashError::source_access(path.into(), err))
+}
+
+/// Size of a stage padded to 2 KiB in blocks
+fn stage_blocks(path: &Path, size: usize, max_size: usize) -> Result<u16, FlashError> {
+    if size > max_size {
+        return Err(FlashError::BootStageInvalid {
+            path: path.into(),
+            message: format!(
+                "it is {} large, at most {} fit",
+                BinarySize::from(size as u64).rounded(),
+                BinarySize::from(max_size as u64).rounded()
+            ),
+        })
+    }
+
+    Ok((align_up(size as u64, STAGE_ALIGNMENT) / BLOCK_SIZE as u64) as u16)
+}
+
+/// Builds an idbloader from the TPL and SPL of U-Boot, like `mkimage -n <soc> -T rksd -d
+/// tpl:spl` does. Without a TPL the SPL is the first stage the boot ROM loads.
+pub fn build_idbloader(
+    soc: &Soc,
+    tpl: Option<&Path>,
+    spl: &Path,
+) -> Result<Vec<u8>, FlashError> {
+    if soc.header_v2 {
+        return build_idbloader_v2(soc, tpl, spl)
+    }
+    let (init_path, boot_path) = match tpl {
+        Some(tpl) => (tpl, Some(spl)),
+        None => (spl, None),
+    };
+
+    let mut init = read_stage(init_path)?;
+    let init_blocks = stage_blocks(init_path, init.len(), soc.max_init_size)?;
+    if init.len() < soc.magic.len() {
+        return Err(FlashError::BootStageInvalid {
+            path: init_path.into(),
+            message: "it is too small to hold the boot magic".into(),
+        })
+    }
+    init[..soc.magic.len()].copy_from_slice(soc.magic);
+
+    let mut image = vec![0u8; INIT_OFFSET * BLOCK_SIZE];
+    image.extend_from_slice(&init);
+    image.resize(image.len() + (usize::from(init_blocks) * BLOCK_SIZE - init.len()), 0);
+
+    let init_boot_blocks = match boot_path {
+        Some(boot_path) => {
+            let boot = read_stage(boot_path)?;
+            // Both stages together have to fit into the 16 bit size of the header
+            let max_size = usize::from(u16::MAX - init_blocks) * BLOCK_SIZE
+                - (STAGE_ALIGNMENT as usize - BLOCK_SIZE);
+            let boot_blocks = stage_blocks(boot_path, boot.len(), max_size)?;
+            image.extend_from_slice(&boot);
+            image.resize(image.len() + (usize::from(boot_blocks) * BLOCK_SIZE - boot.len()), 0);
+            init_blocks + boot_blocks
+        },
+        None => init_blocks + (MAX_BOOT_SIZE / BLOCK_SIZE) as u16,
+    };
+
+    let header = &mut image[..BLOCK_SIZE];
+    header[0..4].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+    header[8..12].copy_from_slice(&u32::from(!soc.rc4).to_le_bytes());
+    header[12..14].copy_from_slice(&(INIT_OFFSET as u16).to_le_bytes());
+    header[506..508].copy_from_slice(&init_blocks.to_le_bytes());
+    header[508..510].copy_from_slice(&init_boot_blocks.to_le_bytes());
+    rc4(header);
+
+    if soc.rc4 {
+        image[INIT_OFFSET * BLOCK_SIZE..].chunks_mut(BLOCK_SIZE).for_each(rc4);
+    }
+
+    Ok(image)
+}
+
+/// Builds an idbloader with the version 2 header, like mkimage does for the rk3588. The TPL,
+/// which is the DDR init blob of Rockchip on these SoCs, is required.
+fn build_idbloader_v2(soc: &Soc, tpl: Option<&Path>, spl: &Path) -> Result<Vec<u8>, FlashError> {
+    let tpl = tpl.ok_or_else(|| FlashError::BootStageInvalid {
+        path: spl.into(),
+        message: format!("{} needs a TPL, e.g. the DDR init blob, pass it with --tpl", soc.name),
+    })?;
+
+    // Sizes are counted in 16 bit, in blocks
+    let max_boot_size = usize::from(u16::MAX) * BLOCK_SIZE / STAGE_ALIGNMENT as usize
+        * STAGE_ALIGNMENT as usize;
+    let mut image = vec![0u8; INIT_OFFSET * BLOCK_SIZE];
+    let mut entries = vec![];
+    for (path, max_size) in [(tpl, soc.max_init_size), (spl, max_boot_size)] {
+        let stage = read_stage(path)?;
+        let blocks = stage_blocks(path, stage.len(), max_size)?;
+        let offset = image.len() / BLOCK_SIZE;
+        image.extend_from_slice(&stage);
+        image.resize(image.len() + (usize::from(blocks) * BLOCK_SIZE - stage.len()), 0);
+        // The hash covers the padding as well
+        let hash = Sha256::digest(&image[offset * BLOCK_SIZE..]);
+        entries.push((blocks, offset as u16, hash));
+    }
+
+    let header = &mut image[..INIT_OFFSET * BLOCK_SIZE];
+    header[0..4].copy_from_slice(HEADER_V2_MAGIC);
+    let size_and_count = ((entries.len() << 16) | (HEADER_V2_HASH_OFFSET / 4)) as u32;
+    header[8..12].copy_from_slice(&size_and_count.to_le_bytes());
+    header[12..16].copy_from_slice(&HEADER_V2_SHA256.to_le_bytes());
+    for (index, (blocks, offset, hash)) in entries.into_iter().enumerate() {
+        let entry = &mut header[HEADER_V2_ENTRIES_OFFSET + index * HEADER_V2_ENTRY_SIZE..]
+            [..HEADER_V2_ENTRY_SIZE];
+        let size_and_offset = (u32::from(blocks) << 16) | u32::from(offset);
+        entry[0..4].copy_from_slice(&size_and_offset.to_le_bytes());
+        // The load address is taken from the boot ROM's defaults
+        entry[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        entry[12..16].copy_from_slice(&(index as u32 + 1).to_le_bytes());
+        entry[24..24 + hash.len()].copy_from_slice(&hash);
+    }
+    let header_hash = Sha256::digest(&header[..HEADER_V2_HASH_OFFSET]);
+    header[HEADER_V2_HASH_OFFSET..][..header_hash.len()].copy_from_slice(&header_hash);
+
+    Ok(image)
+}
+
+/// Checks that `path` starts with a header the boot ROM accepts, so that e.g. U-Boot itself
+/// isn't written where the boot ROM looks for the idbloader
+pub fn check_idbloader(path: &Path) -> Result<(), FlashError> {
+    let invalid = |message: &str| FlashError::IdbloaderInvalid {
+        path: path.into(),
+        message: message.into(),
+    };
+
+    let mut header = [0u8; BLOCK_SIZE];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("it is smaller than the header"),
+            _ => FlashError::source_access(path.into(), err),
+        })?;
+
+    let magic = &header[..4];
+    if magic == HEADER_V2_MAGIC {
+        return Ok(())
+    }
+    if magic == FIT_MAGIC {
+        return Err(invalid("it is a FIT image like u-boot.itb, which belongs into a partition"))
+    }
+    if magic == LOADER_MAGIC {
+        return Err(invalid("it is a loader for rkdeveloptool like *_loader.bin"))
+    }
+    rc4(&mut header);
+    if header[..4] == HEADER_MAGIC.to_le_bytes() {
+        return Ok(())
+    }
+
+    Err(invalid("it doesn't start with a Rockchip boot header"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempfile::TempFile;
+
+    fn stage(len: usize, byte: u8) -> TempFile {
+        let file = TempFile::new("test-stage").unwrap();
+        std::fs::write(file.path(), vec![byte; len]).unwrap();
+        file
+    }
+
+    fn word(image: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_describes_both_stages() {
+        let (tpl, spl) = (stage(3000, 0x11), stage(5000, 0x22));
+        let soc = find_soc("rk3399").unwrap();
+        let image = build_idbloader(soc, Some(tpl.path()), spl.path()).unwrap();
+
+        // TPL padded to 2 KiB is 8 blocks, the SPL 12 blocks
+        assert_eq!(image.len(), (INIT_OFFSET + 8 + 12) * BLOCK_SIZE);
+        let mut header = image[..BLOCK_SIZE].to_vec();
+        rc4(&mut header);
+        assert_eq!(word(&header, 0), HEADER_MAGIC);
+        assert_eq!(word(&header, 8), 1);
+        assert_eq!(u16::from_le_bytes([header[12], header[13]]), INIT_OFFSET as u16);
+        assert_eq!(u16::from_le_bytes([header[506], header[507]]), 8);
+        assert_eq!(u16::from_le_bytes([header[508], header[509]]), 20);
+
+        let init = &image[INIT_OFFSET * BLOCK_SIZE..];
+        assert_eq!(&init[..4], b"RK33");
+        assert!(init[4..3000].iter().all(|byte| *byte == 0x11));
+        assert!(init[3000..8 * BLOCK_SIZE].iter().all(|byte| *byte == 0));
+        assert!(init[8 * BLOCK_SIZE..][..5000].iter().all(|byte| *byte == 0x22));
+    }
+
+    #[test]
+    fn header_v2_holds_entries_and_hashes() {
+        let (tpl, spl) = (stage(3000, 0x11), stage(5000, 0x22));
+        let soc = find_soc("rk3588").unwrap();
+        let image = build_idbloader(soc, Some(tpl.path()), spl.path()).unwrap();
+
+        assert_eq!(image.len(), (INIT_OFFSET + 8 + 12) * BLOCK_SIZE);
+        assert_eq!(&image[..4], HEADER_V2_MAGIC);
+        assert_eq!(word(&image, 8), (2 << 16) | (HEADER_V2_HASH_OFFSET / 4) as u32);
+        assert_eq!(word(&image, 12), HEADER_V2_SHA256);
+
+        let stages = [(INIT_OFFSET, 8), (INIT_OFFSET + 8, 12)];
+        for (index, (offset, blocks)) in stages.into_iter().enumerate() {
+            let entry = &image[HEADER_V2_ENTRIES_OFFSET + index * HEADER_V2_ENTRY_SIZE..]
+                [..HEADER_V2_ENTRY_SIZE];
+            assert_eq!(word(entry, 0), ((blocks << 16) | offset) as u32);
+            assert_eq!(word(entry, 4), u32::MAX);
+            assert_eq!(word(entry, 12), index as u32 + 1);
+            let data = &image[offset * BLOCK_SIZE..][..blocks * BLOCK_SIZE];
+            assert_eq!(entry[24..56], Sha256::digest(data)[..]);
+        }
+        let header_hash = Sha256::digest(&image[..HEADER_V2_HASH_OFFSET]);
+        assert_eq!(image[HEADER_V2_HASH_OFFSET..][..32], header_hash[..]);
+        // Unlike the version 1 header, the first stage is left as it is
+        assert!(image[INIT_OFFSET * BLOCK_SIZE..][..3000].iter().all(|byte| *byte == 0x11));
+    }
+}
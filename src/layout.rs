@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::sizes::ParsedSize;
+
+/// Size as written in a layout file, either a number of bytes or a string like "64MiB"
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum LayoutSize {
+    Bytes(u64),
+    Text(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LayoutFile {
+    destination: Option<PathBuf>,
+    idbloader: Option<PathBuf>,
+    uboot: Option<PathBuf>,
+    trust: Option<PathBuf>,
+    size: Option<LayoutSize>,
+    #[serde(default)]
+    partition: Vec<LayoutFilePartition>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LayoutFilePartition {
+    name: String,
+    source: Option<PathBuf>,
+    size: Option<LayoutSize>,
+    #[serde(rename = "type")]
+    partition_type: Option<String>,
+    format: Option<String>,
+    mkfs_options: Option<String>,
+    populate: Option<PathBuf>,
+    sha256: Option<String>,
+    uuid: Option<String>,
+    flags: Option<String>,
+    start: Option<LayoutSize>,
+    align: Option<LayoutSize>,
+    slots: Option<String>,
+}
+
+/// Disk layout read from a layout file, with paths resolved relative to the file
+#[derive(Clone, Debug, Default)]
+pub struct Layout {
+    /// Disk or image file to write to, used when none is given on the command line
+    pub destination: Option<PathBuf>,
+    pub idbloader: Option<PathBuf>,
+    /// Written to a fixed offset like the idbloader
+    pub uboot: Option<PathBuf>,
+    pub trust: Option<PathBuf>,
+    pub size: Option<u64>,
+    pub partitions: Vec<LayoutPartition>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LayoutPartition {
+    pub name: String,
+    pub source: Option<PathBuf>,
+    pub size: Option<u64>,
+    pub partition_type: Option<String>,
+    pub format: Option<String>,
+    /// Extra arguments for mkfs, as they would be written in a shell
+    pub mkfs_options: Option<String>,
+    /// Directory whose contents are copied onto the new filesystem
+    pub populate: Option<PathBuf>,
+    pub sha256: Option<String>,
+    /// Unique GUID (PARTUUID) of the partition
+    pub uuid: Option<String>,
+    /// GPT attribute flags, a number or a list of named bits
+    pub flags: Option<String>,
+    /// Offset of the partition in bytes
+    pub start: Option<u64>,
+    /// Boundary the start of the partition is aligned to in bytes
+    pub align: Option<u64>,
+    /// "ab" to create the partition once per slot
+    pub slots: Option<String>,
+}
+
+fn parse_layout_size(size: LayoutSize, key: &str, location: &str) -> Result<u64, String> {
+    match size {
+        LayoutSize::Bytes(bytes) => Ok(bytes),
+        LayoutSize::Text(text) => text.parse::<ParsedSize>()
+            .map(|size| size.bytes)
+            .map_err(|e| format!(
+                "Invalid value for key '{}' in {} ({}): {}", key, location, text, e
+            )),
+    }
+}
+
+/// Reads and validates a layout file
+pub fn read_layout(path: impl AsRef<Path>) -> Result<Layout, String> {
+    let path = path.as_ref();
+    let contents = read_to_string(path)
+        .map_err(|err| format!(
+            "Could not read layout file {}: {}", path.to_str().unwrap_or("<invalid path>"), err
+        ))?;
+    let layout_file: LayoutFile = toml::from_str(&contents)
+        .map_err(|err| format!(
+            "Invalid layout file {}: {}", path.to_str().unwrap_or("<invalid path>"), err
+        ))?;
+
+    // Relative paths are relative to the layout file, not the working directory
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    let size = layout_file.size
+        .map(|size| parse_layout_size(size, "size", "the top-level table"))
+        .transpose()?;
+
+    let mut names = HashSet::new();
+    let partitions = layout_file.partition.into_iter()
+        .enumerate()
+        .map(|(index, partition)| {
+            let location = format!("[[partition]] #{} ({})", index + 1, partition.name);
+
+            if partition.name.is_empty() {
+                return Err(format!("Key 'name' in {} must not be empty", location))
+            }
+            if !names.insert(partition.name.clone()) {
+                return Err(format!("Duplicate partition name in {}", location))
+            }
+            if partition.source.is_none() && partition.size.is_none() {
+                return Err(format!(
+                    "{} needs at least one of the keys 'source' or 'size'", location
+                ))
+            }
+            if partition.format.is_none() && partition.mkfs_options.is_some() {
+                return Err(format!("Key 'mkfs_options' in {} needs the key 'format'", location))
+            }
+            if partition.format.is_none() && partition.populate.is_some() {
+                return Err(format!("Key 'populate' in {} needs the key 'format'", location))
+            }
+            if partition.format.is_some() && partition.slots.is_some() {
+                return Err(format!("Key 'slots' in {} can't be used with 'format'", location))
+            }
+            if partition.source.is_none() && partition.sha256.is_some() {
+                return Err(format!("Key 'sha256' in {} needs the key 'source'", location))
+            }
+
+            Ok(LayoutPartition {
+                name: partition.name,
+                source: partition.source.map(|source| base_dir.join(source)),
+                size: partition.size
+                    .map(|size| parse_layout_size(size, "size", &location))
+                    .transpose()?,
+                partition_type: partition.partition_type,
+                format: partition.format,
+                mkfs_options: partition.mkfs_options,
+                populate: partition.populate.map(|populate| base_dir.join(populate)),
+                sha256: partition.sha256,
+                uuid: partition.uuid,
+                flags: partition.flags,
+                start: partition.start
+                    .map(|start| parse_layout_size(start, "start", &location))
+                    .transpose()?,
+                align: partition.align
+                    .map(|align| parse_layout_size(align, "align", &location))
+                    .transpose()?,
+                slots: partition.slots,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(Layout {
+        destination: layout_file.destination.map(|destination| base_dir.join(destination)),
+        idbloader: layout_file.idbloader.map(|idbloader| base_dir.join(idbloader)),
+        uboot: layout_file.uboot.map(|uboot| base_dir.join(uboot)),
+        trust: layout_file.trust.map(|trust| base_dir.join(trust)),
+        size,
+        partitions,
+    })
+}
@@ -0,0 +1,3321 @@
+use std::collections::BTreeMap;
+use std::fs::{File, metadata, OpenOptions, read_to_string};
+use std::io;
+use std::io::{copy, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::mpsc;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+use block_utils::{get_device_info, is_block_device};
+use gpt::disk::LogicalBlockSize;
+use gpt::partition::Partition;
+use gpt::partition_types;
+use gpt::GptDisk;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::alignment::{align_up_pow2, checked_align_up, AlignedBuffer};
+use crate::checksum::Checksum;
+use crate::compression::{Compression, open_decompressed};
+use crate::manifest::Manifest;
+use crate::mbr::{HybridMbrPartition, MbrEntry};
+use crate::partition::is_stdin;
+use crate::progress::ProgressReader;
+use crate::table::PartitionInfo;
+use crate::sparse::SparseReader;
+use crate::throttle::RateLimiter;
+
+pub use crate::error::FlashError;
+pub use crate::event::{FlashEvent, Mismatch};
+pub use crate::partition::{
+    FillPartition, FormatPartitionDefinition, PartitionDefinition, RawImage, Slot
+};
+
+pub mod alignment;
+pub mod avb;
+pub mod checksum;
+pub mod compression;
+pub mod error;
+pub mod event;
+pub mod idbloader;
+pub mod layout;
+pub mod manifest;
+pub mod mbr;
+pub mod mounts;
+pub mod offset;
+pub mod partition;
+pub mod progress;
+pub mod rockusb;
+pub mod sizes;
+pub mod sparse;
+pub mod spi;
+pub mod table;
+pub mod tempfile;
+pub mod throttle;
+
+mod extents;
+mod fat;
+
+const PART_ALIGNMENT: u64 = 1 * 1024 * 1024;
+const FIRST_PART_ALIGNMENT: u64 = 8 * 1024 * 1024;
+
+// https://opensource.rock-chips.com/wiki_Boot_option#The_Pre-bootloader.28IDBLoader.29
+// The boot ROM looks for it at sector 0x40 of 512 bytes, regardless of the logical block size
+const IDBLOADER_ALIGNMENT: u64 = 0x40 * 512;
+
+const IDBLOADER_PARTNAME: &'static str = "idbloader";
+
+// Where the Rockchip miniloader and SPL look for U-Boot and the trust image, sectors 0x4000
+// and 0x6000 of 512 bytes
+pub const DEFAULT_UBOOT_OFFSET: u64 = 0x4000 * 512;
+pub const DEFAULT_TRUST_OFFSET: u64 = 0x6000 * 512;
+
+// 128 partition entries of 128 bytes, at the start of the disk after the protective MBR and
+// the GPT header and at the end of the disk before the backup GPT header
+const GPT_ENTRIES_SIZE: u64 = 128 * 128;
+
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+pub const DEFAULT_BUFFER_COUNT: usize = 2;
+pub const DEFAULT_WRITE_RETRIES: u32 = 3;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The delay doubles with every retry of the same write, up to this
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// O_DIRECT buffers must be aligned to the logical block size of the device, which is at most
+// a page. Aligning to 4 KiB works for all of them without having to ask the device.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+// linux/fs.h, _IO(0x12, 119) and _IO(0x12, 127)
+const BLKDISCARD: u64 = 0x1277;
+const BLKZEROOUT: u64 = 0x127f;
+// linux/fs.h, _IO(0x12, 95), _IO(0x12, 97) and _IO(0x12, 104)
+const BLKRRPART: u64 = 0x125f;
+const BLKFLSBUF: u64 = 0x1261;
+const BLKSSZGET: u64 = 0x1268;
+
+/// GPT attribute bit 2, legacy BIOS bootable
+const LEGACY_BOOT_FLAG: u64 = 1 << 2;
+/// Bit 7 of the status of an MBR partition record marks it as active
+const MBR_BOOT_INDICATOR: u8 = 0x80;
+
+/// Kind of partition table written to a destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PartitionTableType {
+    /// GUID partition table
+    #[default]
+    Gpt,
+    /// Classic DOS partition table with up to 4 primary partitions, for bootroms and
+    /// small eMMC modules that can't use a GPT
+    Mbr,
+}
+
+/// How much of the destination is cleared before the partition table is written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WipeMode {
+    /// Only the first 8 MiB of block devices, where old loaders and partition tables live
+    #[default]
+    Quick,
+    /// The whole destination, so no signatures of an earlier layout are left anywhere
+    Full,
+}
+
+/// Everything that should end up on a destination
+#[derive(Clone, Debug)]
+pub struct FlashPlan {
+    /// Disk or image file to write to
+    pub destination: PathBuf,
+    /// Size of the image file to create, ignored for block devices
+    pub size: u64,
+    pub partitions: Vec<PartitionDefinition>,
+    pub partitions_to_format: Vec<FormatPartitionDefinition>,
+    pub idbloader: Option<PathBuf>,
+    /// Write the idbloader to sector 0x40 without a partition table entry for it
+    pub idbloader_raw: bool,
+    /// Refuse an idbloader that doesn't start with a Rockchip boot header
+    pub check_idbloader: bool,
+    /// Images written to fixed offsets, which the partitions are laid out around
+    pub raw_images: Vec<RawImage>,
+    /// Read back all written images and compare them to their source
+    pub verify: bool,
+    /// Size of the chunks images are written in, a multiple of the logical block size
+    pub block_size: usize,
+    /// Number of chunks that are read ahead while writing, at least one is used
+    pub buffer_count: usize,
+    /// Logical block size of the partition table, 512 or 4096 bytes.
+    /// Block devices are asked for theirs if not set, image files use 512 bytes.
+    pub lba_size: Option<u64>,
+    /// Bypass the page cache using O_DIRECT
+    pub direct: bool,
+    /// How often failed writes are retried per partition, e.g. when a USB card reader resets
+    pub write_retries: u32,
+    /// Time to wait before reopening the destination and retrying a failed write,
+    /// doubled for every further retry of the same write
+    pub retry_delay: Duration,
+    /// Limit for the average write rate in bytes per second, `None` or 0 for no limit
+    pub max_write_rate: Option<u64>,
+    /// Write to the destination even if it holds the running system
+    pub force: bool,
+    /// Power off the destination at the end so it can be removed, ignored for image files
+    pub eject: bool,
+    /// Derive the disk and partition GUIDs from this instead of generating random ones
+    pub guid_seed: Option<String>,
+    /// Don't write blocks of the source images that are all zeros, the partitions are
+    /// zeroed beforehand instead where the destination can do that without writing zeros
+    pub sparse_copy: bool,
+    /// Names of partitions in the existing partition table that are left untouched
+    pub keep: Vec<String>,
+    /// Names of partitions to mark as legacy BIOS bootable, including the idbloader
+    /// and userdata partitions created by rockflasher
+    pub bootable: Vec<String>,
+    /// Set the boot indicator of the partition in the protective MBR
+    pub bootable_pmbr: bool,
+    /// Write a protective MBR in front of the GPT. Without one, the first block is left as it
+    /// is for boot ROMs that need it, but tools may not recognize the GPT.
+    pub protective_mbr: bool,
+    /// Partitions to mirror in a hybrid MBR, which replaces the protective MBR if not empty.
+    /// Their boot indicators are set for the partitions in `bootable`.
+    pub hybrid_mbr: Vec<HybridMbrPartition>,
+    /// Partitions are laid out the same way for both types, kept partitions and a hybrid MBR
+    /// need a GPT though
+    pub table_type: PartitionTableType,
+    /// Add the partitions to the existing partition table instead of replacing it. Nothing but
+    /// the new partitions and the partition table is written then.
+    pub append: bool,
+    /// Partitions of the existing partition table that are replaced by new ones with the same
+    /// name when appending
+    pub replace: Vec<String>,
+    /// Types of partitions by their lowercase name, used instead of the ones derived from
+    /// the name for partitions without an explicit type
+    pub type_map: BTreeMap<String, partition_types::Type>,
+    /// How much of the destination is cleared first, a full wipe can't keep or append
+    pub wipe: WipeMode,
+    /// Clear what follows the image in each partition. Partitions that are formatted
+    /// afterwards or filled by their image don't need it.
+    pub clear_tail: bool,
+    /// Bytes at the end of the destination that are left unpartitioned, before the backup
+    /// partition table
+    pub reserve: u64,
+    /// Partition that fills the space after the last partition unless there is one like it
+    /// already, userdata by default. The space is left free without one.
+    pub fill_partition: Option<FillPartition>,
+    /// Partition that is grown into the space after it instead of creating the fill partition,
+    /// up to the next partition or the end of the destination
+    pub grow_partition: Option<String>,
+    /// Slot whose A/B partitions are marked as active, the partitions get no slot flags
+    /// without one
+    pub active_slot: Option<Slot>,
+    /// AVB flags set in the header of the vbmeta partitions as they are written, e.g.
+    /// [avb::FLAG_HASHTREE_DISABLED]. The images are written as they are with 0.
+    pub vbmeta_flags: u32,
+}
+
+impl FlashPlan {
+    pub fn new(destination: PathBuf) -> Self {
+        FlashPlan {
+            destination,
+            size: 0,
+            partitions: vec![],
+            partitions_to_format: vec![],
+            idbloader: None,
+            idbloader_raw: false,
+            check_idbloader: true,
+            raw_images: vec![],
+            verify: false,
+            block_size: DEFAULT_BLOCK_SIZE,
+            buffer_count: DEFAULT_BUFFER_COUNT,
+            lba_size: None,
+            direct: false,
+            write_retries: DEFAULT_WRITE_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            max_write_rate: None,
+            force: false,
+            eject: false,
+            guid_seed: None,
+            sparse_copy: true,
+            keep: vec![],
+            bootable: vec![],
+            bootable_pmbr: false,
+            protective_mbr: true,
+            hybrid_mbr: vec![],
+            table_type: PartitionTableType::Gpt,
+            append: false,
+            replace: vec![],
+            type_map: BTreeMap::new(),
+            wipe: WipeMode::Quick,
+            clear_tail: true,
+            reserve: 0,
+            fill_partition: Some(FillPartition::default()),
+            grow_partition: None,
+            active_slot: None,
+            vbmeta_flags: 0,
+        }
+    }
+
+    /// Whether there is nothing to write to the destination
+    pub fn is_empty(&self) -> bool {
+        self.partitions.is_empty() && self.idbloader.is_none() && self.raw_images.is_empty()
+    }
+}
+
+/// What [flash] has written, for callers that don't want to collect it from the events
+#[derive(Clone, Debug, Default)]
+pub struct FlashSummary {
+    /// GUID of the disk, for a GPT
+    pub disk_guid: Option<String>,
+    /// The partition table as it has been written, with the GUIDs and locations of all
+    /// partitions including kept and appended ones
+    pub partitions: Vec<PartitionInfo>,
+    /// Entries of the DOS partition table or hybrid MBR
+    pub mbr_entries: Vec<MbrEntry>,
+}
+
+impl FlashSummary {
+    fn record(&mut self, event: &FlashEvent) {
+        match event {
+            FlashEvent::PartitionTableWritten { disk_guid, partitions, .. } => {
+                self.disk_guid = Some(disk_guid.clone());
+                self.partitions = partitions.clone();
+            },
+            FlashEvent::MbrPartitionTableWritten { entries, .. } =>
+                self.mbr_entries = entries.clone(),
+            _ => {},
+        }
+    }
+}
+
+/// The existing partition table new partitions are appended to
+#[derive(Clone, Debug, Default)]
+struct AppendedTable {
+    /// Partitions that stay as they are, by their number
+    partitions: BTreeMap<u32, Partition>,
+    /// Numbers of the partitions that are replaced by new ones, by name
+    replaced: BTreeMap<String, u32>,
+}
+
+#[derive(Clone, Debug)]
+struct CreatedPartition {
+    def: Option<PartitionDefinition>,
+    partition: Partition,
+    /// Written to where `partition` is, without an entry in the partition table
+    raw: bool,
+}
+
+/// A source image that has been written to its partition
+#[derive(Clone, Debug)]
+struct WrittenImage {
+    name: String,
+    offset: u64,
+    bytes_written: u64,
+    /// Checksum of the data written, which is what the source decompresses to
+    checksum: Checksum,
+    /// Where the data came from, if it can be read again to find what differs
+    source: Option<ImageSource>,
+}
+
+/// Source of a written image and how it was read
+#[derive(Clone, Debug)]
+struct ImageSource {
+    def: PartitionDefinition,
+    path: PathBuf,
+    /// AVB flags patched into vbmeta images
+    patched_flags: u32,
+}
+
+/// Writes the partition table and all images of `plan` to its destination and formats
+/// the requested partitions afterwards.
+///
+/// Block devices are overwritten without asking, confirming this is up to the caller.
+/// Progress is reported through `on_event`, the partition table that has been written is
+/// returned as well.
+pub fn flash(
+    plan: FlashPlan,
+    mut on_event: impl FnMut(FlashEvent),
+) -> Result<FlashSummary, FlashError> {
+    let mut summary = FlashSummary::default();
+    let mut on_event = |event: FlashEvent| {
+        summary.record(&event);
+        on_event(event)
+    };
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+
+    check_mkfs_tools(&plan.partitions_to_format)?;
+    let lba = logical_block_size(&plan.destination, plan.lba_size)?;
+    let mbr_entries = write_plan(&plan, lba, on_event)?;
+    format_partitions(
+        plan.destination.clone(), plan.partitions_to_format.clone(), plan.table_type,
+        &mbr_entries, lba, on_event
+    )?;
+    if plan.is_empty() && plan.partitions_to_format.is_empty() {
+        return Ok(summary)
+    }
+
+    // Pulling the card right after the last write must not cost the backup partition table
+    flush_destination(&plan.destination, plan.write_retries, plan.retry_delay, on_event)?;
+    if plan.eject && is_block_device(&plan.destination).unwrap_or(false) {
+        eject_destination(&plan.destination, on_event)?;
+    }
+
+    if !plan.is_empty() {
+        on_event(FlashEvent::FlashComplete);
+    }
+    Ok(summary)
+}
+
+/// Reads back the partitions recorded in `manifest` from the destination and compares them
+/// to the checksums of the data that has been written, without writing anything.
+///
+/// Partitions are looked up by their unique GUID first and by their name otherwise.
+pub fn verify_manifest(
+    destination: PathBuf,
+    manifest: &Manifest,
+    mut on_event: impl FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+
+    let lba = logical_block_size(&destination, Some(manifest.lba_size))?;
+    let partitions = table::read_partition_table(&destination, lba)?;
+
+    let mut images = vec![];
+    let mut missing_partitions = vec![];
+    for entry in &manifest.partitions {
+        let (Some(checksum), Some(bytes_written)) = (&entry.sha256, entry.bytes_written) else {
+            continue
+        };
+        let recorded = &entry.partition;
+        let partition = partitions.iter()
+            .find(|partition| partition.unique_guid.eq_ignore_ascii_case(&recorded.unique_guid))
+            .or_else(|| partitions.iter().find(|partition| partition.name == recorded.name));
+        match partition {
+            Some(partition) => images.push(WrittenImage {
+                name: partition.name.clone(),
+                offset: partition.first_lba * u64::from(lba),
+                bytes_written,
+                checksum: checksum.clone(),
+                source: None,
+            }),
+            None => {
+                on_event(FlashEvent::PartitionMissing { name: recorded.name.clone() });
+                missing_partitions.push(recorded.name.clone());
+            },
+        }
+    }
+
+    match verify_images(destination, images, on_event) {
+        Ok(()) if missing_partitions.is_empty() => Ok(()),
+        Ok(()) => Err(FlashError::VerifyFailed { partitions: missing_partitions }),
+        Err(FlashError::VerifyFailed { mut partitions }) => {
+            partitions.extend(missing_partitions);
+            Err(FlashError::VerifyFailed { partitions })
+        },
+        Err(err) => Err(err),
+    }
+}
+
+/// Removes the partitions called `names` from the partition table of `destination`, leaving
+/// all other partitions and the MBR as they are. With `wipe_contents`, the space the removed
+/// partitions took up is cleared too.
+///
+/// Nothing is changed if one of the partitions doesn't exist or is mounted.
+pub fn delete_partitions(
+    destination: PathBuf,
+    names: &[String],
+    wipe_contents: bool,
+    lba_size: Option<u64>,
+    mut on_event: impl FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+
+    let lba = logical_block_size(&destination, lba_size)?;
+    let is_block_device = is_block_device(&destination).unwrap_or(false);
+    let cfg = gpt::GptConfig::new()
+        .initialized(true)
+        .writable(true)
+        .logical_block_size(lba);
+    let mut disk = cfg.open(destination.clone())
+        .map_err(|source| FlashError::GptRead { path: destination.clone(), source })?;
+
+    let mut partitions = disk.partitions().clone();
+    let mut deleted_partitions = vec![];
+    for name in names {
+        let part_id = partitions.iter()
+            .find(|(_, partition)| partition.is_used() && partition.name == *name)
+            .map(|(part_id, _)| *part_id)
+            .ok_or_else(|| FlashError::PartitionNotFound { name: name.clone() })?;
+        // The kernel numbers partitions by their entry in the partition table, like gpt does
+        if is_block_device {
+            let mounts = mounts::find_partition_mounts(&destination, part_id)
+                .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+            if let Some(mount) = mounts.into_iter().next() {
+                return Err(FlashError::PartitionMounted {
+                    name: name.clone(),
+                    mount_point: mount.mount_point,
+                })
+            }
+        }
+        deleted_partitions.extend(partitions.remove(&part_id));
+    }
+
+    for partition in &deleted_partitions {
+        on_event(FlashEvent::DeletingPartition {
+            name: partition.name.clone(),
+            first_lba: partition.first_lba,
+            last_lba: partition.last_lba,
+        });
+    }
+    disk.update_partitions(partitions)
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    on_event(FlashEvent::WritingPartitionTable);
+    let disk_guid = disk.guid().to_string().to_uppercase();
+    disk.write()
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    table::validate_partition_table(&destination, lba)?;
+    // The remaining partitions have moved up in the table
+    on_event(FlashEvent::PartitionTableWritten {
+        disk_guid,
+        lba_size: u64::from(lba),
+        partitions: table::read_partition_table(&destination, lba)?,
+    });
+
+    if wipe_contents {
+        let file = open_write(destination.clone(), false)
+            .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+        for partition in &deleted_partitions {
+            wipe_partition(&file, is_block_device, partition, lba, on_event)?;
+        }
+    }
+
+    flush_destination(&destination, DEFAULT_WRITE_RETRIES, DEFAULT_RETRY_DELAY, on_event)
+}
+
+/// Moves the end of the partition called `name` in the partition table of `destination`,
+/// leaving everything else as it is, and formats `partitions_to_format` afterwards.
+///
+/// Without a `size`, the partition grows into the free space after it. It never grows into
+/// the next partition or the backup partition table. Shrinking can cut off the filesystem in
+/// the partition, so it needs `force`.
+pub fn resize_partition(
+    destination: PathBuf,
+    name: &str,
+    size: Option<u64>,
+    force: bool,
+    partitions_to_format: Vec<FormatPartitionDefinition>,
+    lba_size: Option<u64>,
+    mut on_event: impl FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+
+    check_mkfs_tools(&partitions_to_format)?;
+    let lba = logical_block_size(&destination, lba_size)?;
+    let is_block_device = is_block_device(&destination).unwrap_or(false);
+    let cfg = gpt::GptConfig::new()
+        .initialized(true)
+        .writable(true)
+        .logical_block_size(lba);
+    let mut disk = cfg.open(destination.clone())
+        .map_err(|source| FlashError::GptRead { path: destination.clone(), source })?;
+
+    let mut partitions = disk.partitions().clone();
+    let (part_id, partition) = partitions.iter()
+        .find(|(_, partition)| partition.is_used() && partition.name == name)
+        .map(|(part_id, partition)| (*part_id, partition.clone()))
+        .ok_or_else(|| FlashError::PartitionNotFound { name: name.into() })?;
+    let invalid = |message: &str| FlashError::ResizeInvalid {
+        name: name.into(),
+        message: message.into(),
+    };
+
+    let last_usable = disk.primary_header()
+        .map(|header| header.last_usable)
+        .ok_or_else(|| invalid("the partition table has no header"))?;
+    let next_partition = partitions.values()
+        .filter(|other| other.is_used() && other.first_lba > partition.last_lba)
+        .min_by_key(|other| other.first_lba);
+    let max_last_lba = next_partition
+        .map_or(last_usable, |next| last_usable.min(next.first_lba - 1));
+    let last_lba = match size {
+        Some(size) => size.div_ceil(u64::from(lba)).checked_sub(1)
+            .and_then(|length| partition.first_lba.checked_add(length))
+            .ok_or_else(|| invalid("the size must not be 0"))?,
+        None => max_last_lba,
+    };
+    if last_lba > max_last_lba {
+        return Err(match next_partition {
+            Some(next) if last_lba >= next.first_lba => FlashError::PartitionOverlap {
+                partition: name.into(),
+                other: next.name.clone(),
+            },
+            _ => invalid("it would end beyond the end of the destination"),
+        })
+    }
+    if last_lba < partition.last_lba && !force {
+        return Err(invalid(
+            "shrinking it can cut off the filesystem in it, pass --force to shrink anyway"
+        ))
+    }
+    // The kernel can't pick up the new size of a mounted partition
+    if is_block_device {
+        let mounts = mounts::find_partition_mounts(&destination, part_id)
+            .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+        if let Some(mount) = mounts.into_iter().next() {
+            return Err(FlashError::PartitionMounted {
+                name: name.into(),
+                mount_point: mount.mount_point,
+            })
+        }
+    }
+
+    on_event(FlashEvent::ResizingPartition {
+        name: name.into(),
+        size: (partition.last_lba + 1 - partition.first_lba) * u64::from(lba),
+        new_size: (last_lba + 1 - partition.first_lba) * u64::from(lba),
+    });
+    partitions.insert(part_id, Partition { last_lba, ..partition });
+    disk.update_partitions(partitions)
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    on_event(FlashEvent::WritingPartitionTable);
+    let disk_guid = disk.guid().to_string().to_uppercase();
+    disk.write()
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    table::validate_partition_table(&destination, lba)?;
+    on_event(FlashEvent::PartitionTableWritten {
+        disk_guid,
+        lba_size: u64::from(lba),
+        partitions: table::read_partition_table(&destination, lba)?,
+    });
+
+    // Formatting probes the partitions itself
+    if partitions_to_format.is_empty() {
+        if is_block_device {
+            probe_partitions(&destination, on_event);
+        }
+    } else {
+        format_partitions(
+            destination.clone(), partitions_to_format, PartitionTableType::Gpt, &[], lba, on_event
+        )?;
+    }
+
+    flush_destination(&destination, DEFAULT_WRITE_RETRIES, DEFAULT_RETRY_DELAY, on_event)
+}
+
+/// Creates filesystems in `partitions_to_format` of the existing partition table of
+/// `destination`, leaving the partition table and all other partitions as they are.
+///
+/// Nothing is formatted if one of the partitions doesn't exist or is mounted.
+pub fn format_existing_partitions(
+    destination: PathBuf,
+    partitions_to_format: Vec<FormatPartitionDefinition>,
+    lba_size: Option<u64>,
+    mut on_event: impl FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+
+    check_mkfs_tools(&partitions_to_format)?;
+    let lba = logical_block_size(&destination, lba_size)?;
+    let is_block_device = is_block_device(&destination).unwrap_or(false);
+    let cfg = gpt::GptConfig::new()
+        .initialized(true)
+        .writable(false)
+        .logical_block_size(lba);
+    let disk = cfg.open(destination.clone())
+        .map_err(|source| FlashError::GptRead { path: destination.clone(), source })?;
+
+    for partition_to_format in &partitions_to_format {
+        let name = &partition_to_format.partition_name;
+        let part_id = disk.partitions().iter()
+            .find(|(_, partition)| partition.is_used() && partition.name == *name)
+            .map(|(part_id, _)| *part_id)
+            .ok_or_else(|| FlashError::PartitionNotFound { name: name.clone() })?;
+        if is_block_device {
+            let mounts = mounts::find_partition_mounts(&destination, part_id)
+                .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+            if let Some(mount) = mounts.into_iter().next() {
+                return Err(FlashError::PartitionMounted {
+                    name: name.clone(),
+                    mount_point: mount.mount_point,
+                })
+            }
+        }
+    }
+
+    format_partitions(
+        destination.clone(), partitions_to_format, PartitionTableType::Gpt, &[], lba, on_event
+    )?;
+
+    flush_destination(&destination, DEFAULT_WRITE_RETRIES, DEFAULT_RETRY_DELAY, on_event)
+}
+
+/// Clears everything `partition` contains, by discarding it if possible
+fn wipe_partition(
+    file: &File,
+    is_block_device: bool,
+    partition: &Partition,
+    lba: LogicalBlockSize,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let name = partition.name.clone();
+    let start = partition.first_lba * u64::from(lba);
+    let len = (partition.last_lba + 1 - partition.first_lba) * u64::from(lba);
+    on_event(FlashEvent::WipingPartition { name: name.clone(), size: len });
+
+    if clear_range(file, is_block_device, start, len).is_err() {
+        let buffer = vec![0; DEFAULT_BLOCK_SIZE];
+        for offset in (0..len).step_by(buffer.len()) {
+            // Partitions end on a block boundary, so this stays aligned
+            let chunk_len = (len - offset).min(buffer.len() as u64) as usize;
+            file.write_all_at(&buffer[..chunk_len], start + offset)
+                .map_err(|source| FlashError::WriteFailed {
+                    partition: name.clone(),
+                    offset: start + offset,
+                    source,
+                })?;
+            on_event(FlashEvent::ClearProgress {
+                name: name.clone(),
+                cleared: offset + chunk_len as u64,
+                total: len,
+            });
+        }
+    }
+    file.sync_data().map_err(|source| FlashError::WriteFailed {
+        partition: name.clone(),
+        offset: start,
+        source,
+    })?;
+
+    on_event(FlashEvent::PartitionWiped { name });
+    Ok(())
+}
+
+/// Returns the logical block size to use for the destination: `requested` if set, otherwise
+/// the one of the block device or 512 bytes for image files
+pub fn logical_block_size(
+    destination: impl AsRef<Path>,
+    requested: Option<u64>,
+) -> Result<LogicalBlockSize, FlashError> {
+    let destination = destination.as_ref();
+    let size = match requested {
+        Some(size) => size,
+        None => match is_block_device(destination) {
+            Ok(true) => File::open(destination).ok()
+                .and_then(|device| device_logical_block_size(&device))
+                .unwrap_or(512),
+            _ => 512,
+        },
+    };
+
+    LogicalBlockSize::try_from(size).map_err(|_| FlashError::UnsupportedLbaSize { size })
+}
+
+/// Asks the block device behind `file` for its logical block size
+fn device_logical_block_size(file: &File) -> Option<u64> {
+    let mut size: libc::c_int = 0;
+    match unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET as _, &mut size) } {
+        0 => u64::try_from(size).ok(),
+        _ => None,
+    }
+}
+
+/// Returns the size of the destination if it is a block device
+pub fn device_size(destination: impl AsRef<Path>) -> Result<Option<u64>, FlashError> {
+    let destination = destination.as_ref();
+    match is_block_device(destination) {
+        Ok(true) => match get_device_info(destination) {
+            Ok(device) => Ok(Some(device.capacity)),
+            Err(err) => Err(FlashError::DeviceInfo {
+                path: destination.into(),
+                message: err.to_string(),
+            })
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Writes the partition table and images of `plan`, returning the entries of the DOS partition
+/// table if one has been written instead of a GPT
+fn write_plan(
+    plan: &FlashPlan,
+    lba: LogicalBlockSize,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<Vec<MbrEntry>, FlashError> {
+    let lba_size = u64::from(lba);
+    if plan.is_empty() {
+        on_event(FlashEvent::NothingToFlash);
+        return Ok(vec![])
+    }
+
+    if plan.table_type == PartitionTableType::Mbr
+        && (!plan.keep.is_empty() || !plan.hybrid_mbr.is_empty() || plan.bootable_pmbr
+            || plan.append) {
+        return Err(FlashError::Unsupported(
+            "Keeping partitions, appending, hybrid and protective MBRs need a GPT".into()
+        ))
+    }
+    if !plan.protective_mbr && (plan.table_type == PartitionTableType::Mbr
+        || !plan.hybrid_mbr.is_empty() || plan.bootable_pmbr) {
+        return Err(FlashError::Unsupported(
+            "Without a protective MBR there is no MBR to make bootable or hybrid".into()
+        ))
+    }
+    if plan.append && (!plan.keep.is_empty() || !plan.hybrid_mbr.is_empty() || plan.bootable_pmbr) {
+        return Err(FlashError::Unsupported(
+            "Appending leaves all existing partitions and the MBR as they are".into()
+        ))
+    }
+    if plan.wipe == WipeMode::Full && (!plan.keep.is_empty() || plan.append) {
+        return Err(FlashError::Unsupported(
+            "A full wipe leaves no existing partitions to keep or append to".into()
+        ))
+    }
+
+    // Partition starts are block aligned, the writes must be too
+    if plan.block_size < 1024 || !(plan.block_size as u64).is_multiple_of(lba_size) {
+        return Err(FlashError::InvalidBlockSize { size: plan.block_size, lba_size })
+    }
+    if let Some(idbloader_path) = plan.idbloader.as_ref().filter(|_| plan.check_idbloader) {
+        idbloader::check_idbloader(idbloader_path)?;
+    }
+
+    // Both slots of A/B partitions are laid out and written like separate partitions
+    let plan_partitions = partition::expand_slots(plan.partitions.clone(), plan.active_slot);
+    check_stdin_sources(&plan_partitions, plan.vbmeta_flags)?;
+
+    let destination = plan.destination.clone();
+    let (size, is_block_device) = match device_size(destination.clone())? {
+        Some(size) => (size, true),
+        None => (plan.size, false),
+    };
+
+    let kept_partitions = read_kept_partitions(&destination, &plan.keep, lba)?;
+    for kept_partition in &kept_partitions {
+        if plan_partitions.iter().any(|def| def.partition_name == kept_partition.name) {
+            return Err(FlashError::KeptPartitionConflict {
+                kept: kept_partition.name.clone(),
+                partition: kept_partition.name.clone(),
+            })
+        }
+    }
+    // Appending keeps everything but the partitions that are replaced
+    let appended_to = match plan.append {
+        true => {
+            let mut new_names: Vec<&str> = plan_partitions.iter()
+                .map(|def| def.partition_name.as_str())
+                .collect();
+            if plan.idbloader.is_some() && !plan.idbloader_raw {
+                new_names.push(IDBLOADER_PARTNAME);
+            }
+            Some(read_appended_table(&destination, &new_names, &plan.replace, lba)?)
+        },
+        false => None,
+    };
+    let untouched_partitions: Vec<Partition> = kept_partitions.iter().cloned()
+        .chain(appended_to.iter().flat_map(|table| table.partitions.values().cloned()))
+        .collect();
+    check_partition_guids(&plan_partitions, &untouched_partitions)?;
+    // An image file that is reflashed keeps its size unless a new one is given
+    let keeps_contents = !kept_partitions.is_empty() || plan.append;
+    let size = match (is_block_device, size, keeps_contents) {
+        (false, 0, true) => metadata(&destination)
+            .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?
+            .len(),
+        _ => size,
+    };
+
+    if is_block_device && !plan.force {
+        let system_mount = mounts::find_system_mount(&destination)
+            .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+        if let Some(mount) = system_mount {
+            return Err(FlashError::SystemDisk { path: destination, mount_point: mount.mount_point })
+        }
+    }
+    // Filesystems that are still mounted would write over the new partitions. They are only
+    // unmounted with force and once the layout is known to fit.
+    let destination_mounts = match is_block_device {
+        true => mounts::find_mounts(&destination)
+            .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?,
+        false => vec![],
+    };
+    if !destination_mounts.is_empty() && !plan.force {
+        return Err(FlashError::DestinationMounted { path: destination, mounts: destination_mounts })
+    }
+
+    on_event(FlashEvent::Destination { path: destination.clone(), size, is_block_device });
+
+    // Nothing must be touched if the partitions don't fit anyway
+    let partitions = partition::reorder_partitions(
+        partition::apply_type_map(plan_partitions, &plan.type_map)
+    );
+    let misaligned = partitions.iter()
+        .filter_map(|def| Some((def, def.alignment?)))
+        .find(|(_, alignment)| *alignment == 0 || alignment % lba_size != 0);
+    if let Some((def, alignment)) = misaligned {
+        return Err(FlashError::PartitionAlignmentInvalid {
+            partition: def.partition_name.clone(),
+            alignment,
+            lba_size,
+        })
+    }
+    let idbloader_size = match &plan.idbloader {
+        Some(idbloader) => Some(idbloader_size(idbloader)?),
+        None => None,
+    };
+    let raw_images = plan.raw_images.iter()
+        .map(|image| raw_image_definition(image, lba_size))
+        .collect::<Result<Vec<_>, _>>()?;
+    let required_size = required_size(
+        &[partitions.as_slice(), raw_images.as_slice()].concat(), idbloader_size, lba_size
+    );
+    if !is_block_device && size == 0 {
+        return Err(FlashError::MissingImageSize { path: destination, required: required_size })
+    }
+    if required_size.is_none_or(|required_size| required_size > size) {
+        return Err(FlashError::LayoutDoesNotFit {
+            path: destination,
+            required: required_size,
+            available: size,
+        })
+    }
+
+    // The backup partition table at the end of the destination must not overwrite them
+    let last_usable = (size / lba_size).saturating_sub(GPT_ENTRIES_SIZE / lba_size + 2);
+    for kept_partition in &kept_partitions {
+        if kept_partition.last_lba > last_usable {
+            return Err(FlashError::KeptPartitionBeyondEnd {
+                path: destination,
+                kept: kept_partition.name.clone(),
+            })
+        }
+    }
+
+    // A corrupt download must not leave a half-flashed destination behind
+    verify_checksums(&plan.partitions, on_event)?;
+    if plan.vbmeta_flags != 0 {
+        check_vbmeta_images(&partitions)?;
+    }
+
+    // Appending reads the partition table of an image file, which has to have its size for that
+    if !is_block_device && plan.append {
+        create_sparse_file(destination.clone(), size, true)?;
+    }
+
+    // Old loaders must go, kept partitions in the first few MiB must stay
+    let erase_size = kept_partitions.iter()
+        .map(|partition| partition.first_lba * lba_size)
+        .fold(FIRST_PART_ALIGNMENT, u64::min);
+    let keeps_partitions = !kept_partitions.is_empty();
+
+    // Partitions that don't fit or overlap kept ones must be found before anything is erased
+    let disk = open_layout_disk(&destination, size, is_block_device, plan.append, lba)?;
+    let (mut disk, created_partitions) = layout_partition_table(
+        plan, disk, partitions, raw_images, kept_partitions, appended_to, on_event
+    )?;
+    // Entries of the MBR that replaces the protective one or the GPT altogether
+    let mbr_entries = match plan.table_type {
+        PartitionTableType::Gpt if plan.hybrid_mbr.is_empty() => vec![],
+        PartitionTableType::Gpt =>
+            mbr::hybrid_mbr_entries(disk.partitions(), &plan.hybrid_mbr, &plan.bootable)?,
+        PartitionTableType::Mbr => mbr::mbr_entries(disk.partitions(), &plan.bootable)?,
+    };
+    // Only now that the layout fits, the image file is created or truncated
+    if !is_block_device && !plan.append {
+        create_sparse_file(destination.clone(), size, keeps_contents)?;
+        let file = OpenOptions::new().read(true).write(true).open(&destination)
+            .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+        disk.update_disk_device(Box::new(file), true);
+    }
+    unmount_all(destination_mounts, on_event);
+    // Images are only written after this, so this doesn't wipe a raw idbloader either
+    match plan.wipe {
+        WipeMode::Full => wipe_destination(
+            destination.clone(), size, is_block_device, plan.block_size, on_event
+        )?,
+        WipeMode::Quick if !plan.append && (is_block_device || keeps_partitions) => {
+            let erase_start = if plan.protective_mbr { 0 } else { lba_size };
+            erase_beginning(destination.clone(), erase_start, erase_size, on_event)?
+        },
+        WipeMode::Quick => {},
+    }
+    if !plan.protective_mbr && !plan.append {
+        on_event(FlashEvent::SkippingProtectiveMbr);
+    }
+    match plan.table_type {
+        PartitionTableType::Gpt => {
+            // A hybrid MBR replaces the protective one once the GPT is written
+            if plan.protective_mbr && !plan.append && mbr_entries.is_empty() {
+                on_event(FlashEvent::CreatingProtectiveMbr);
+                create_protective_mbr(destination.clone(), size, lba, plan.bootable_pmbr)?;
+            }
+            write_partition_table(destination.clone(), disk, lba, &mbr_entries, on_event)?
+        },
+        PartitionTableType::Mbr =>
+            write_mbr_partition_table(destination.clone(), &disk, lba, &mbr_entries, on_event)?,
+    }
+
+    let written_images =
+        write_images(plan, created_partitions, is_block_device, lba, on_event)?;
+
+    if plan.verify {
+        verify_images(destination, written_images, on_event)?;
+    }
+
+    Ok(match plan.table_type {
+        PartitionTableType::Gpt => vec![],
+        PartitionTableType::Mbr => mbr_entries,
+    })
+}
+
+fn verify_checksums(
+    partitions: &Vec<PartitionDefinition>,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    for partition in partitions {
+        if let (Some(expected), Some(source_file)) = (&partition.checksum, &partition.source_file) {
+            on_event(FlashEvent::VerifyingChecksum { name: partition.partition_name.clone() });
+            let actual = expected.compute(source_file)
+                .map_err(|err| FlashError::source_access(source_file.clone(), err))?;
+            if actual != *expected {
+                return Err(FlashError::ChecksumMismatch {
+                    path: source_file.clone(),
+                    expected: expected.clone(),
+                    actual,
+                })
+            }
+            on_event(FlashEvent::ChecksumVerified { name: partition.partition_name.clone() });
+        }
+    }
+    Ok(())
+}
+
+/// Stdin can only be read once, while writing, so nothing can look at it beforehand
+fn check_stdin_sources(
+    partitions: &[PartitionDefinition],
+    vbmeta_flags: u32,
+) -> Result<(), FlashError> {
+    let stdin_partitions = partitions.iter()
+        .filter(|def| def.source_file.as_deref().is_some_and(is_stdin));
+    for (index, def) in stdin_partitions.enumerate() {
+        let message = if index > 0 {
+            "stdin can only be written to a single partition"
+        } else if def.checksum.is_some() {
+            "its checksum can't be verified before writing it"
+        } else if vbmeta_flags != 0 && avb::is_vbmeta_partition(&def.partition_name) {
+            "it must be checked to be a vbmeta image before its flags are patched"
+        } else {
+            continue
+        };
+        return Err(FlashError::StdinSourceInvalid {
+            partition: def.partition_name.clone(),
+            message: message.into(),
+        })
+    }
+    Ok(())
+}
+
+/// Checks that the images of the vbmeta partitions are vbmeta images, before their flags are
+/// patched while writing them
+fn check_vbmeta_images(partitions: &[PartitionDefinition]) -> Result<(), FlashError> {
+    let vbmeta_images = partitions.iter()
+        .filter(|def| avb::is_vbmeta_partition(&def.partition_name))
+        .filter_map(|def| Some((def, def.source_file.as_ref()?)));
+    for (def, source_file) in vbmeta_images {
+        open_source(def, source_file.clone())
+            .and_then(avb::check_vbmeta)
+            .map_err(|err| FlashError::source_access(source_file.clone(), err))?
+            .map_err(|message| FlashError::VbmetaInvalid { path: source_file.clone(), message })?;
+    }
+    Ok(())
+}
+
+/// Reads the partitions named `names` from the existing partition table of the destination
+fn read_kept_partitions(
+    destination: &Path,
+    names: &[String],
+    lba: LogicalBlockSize,
+) -> Result<Vec<Partition>, FlashError> {
+    if names.is_empty() {
+        return Ok(vec![])
+    }
+
+    let cfg = gpt::GptConfig::new()
+        .initialized(true)
+        .writable(false)
+        .logical_block_size(lba);
+    let disk = cfg.open(destination)
+        .map_err(|source| FlashError::GptRead { path: destination.into(), source })?;
+
+    names.iter()
+        .map(|name| disk.partitions().values()
+            .find(|partition| partition.is_used() && partition.name == *name)
+            .cloned()
+            .ok_or_else(|| FlashError::KeptPartitionMissing {
+                path: destination.into(),
+                name: name.clone(),
+            })
+        )
+        .collect()
+}
+
+/// Reads the existing partition table that new partitions are appended to. Any partition with
+/// the name of a new one is an error, unless it is replaced.
+fn read_appended_table(
+    destination: &Path,
+    new_names: &[&str],
+    replace: &[String],
+    lba: LogicalBlockSize,
+) -> Result<AppendedTable, FlashError> {
+    let cfg = gpt::GptConfig::new()
+        .initialized(true)
+        .writable(false)
+        .logical_block_size(lba);
+    let disk = cfg.open(destination)
+        .map_err(|source| FlashError::GptRead { path: destination.into(), source })?;
+    let existing_partitions: BTreeMap<u32, Partition> = disk.partitions().iter()
+        .filter(|(_, partition)| partition.is_used())
+        .map(|(part_id, partition)| (*part_id, partition.clone()))
+        .collect();
+
+    for name in replace {
+        if !existing_partitions.values().any(|partition| partition.name == *name) {
+            return Err(FlashError::ReplacedPartitionInvalid {
+                name: name.clone(),
+                message: format!("it doesn't exist on {}", destination.to_string_lossy()),
+            })
+        }
+        if !new_names.contains(&name.as_str()) {
+            return Err(FlashError::ReplacedPartitionInvalid {
+                name: name.clone(),
+                message: "no new partition has that name".into(),
+            })
+        }
+    }
+    for name in new_names {
+        let exists = existing_partitions.values().any(|partition| partition.name == *name);
+        if exists && !replace.iter().any(|replaced| replaced == name) {
+            return Err(FlashError::PartitionExists {
+                path: destination.into(),
+                name: name.to_string(),
+            })
+        }
+    }
+
+    let (replaced, partitions): (BTreeMap<u32, Partition>, BTreeMap<u32, Partition>) =
+        existing_partitions.into_iter()
+            .partition(|(_, partition)| replace.contains(&partition.name));
+    Ok(AppendedTable {
+        partitions,
+        replaced: replaced.into_iter()
+            .map(|(part_id, partition)| (partition.name, part_id))
+            .collect(),
+    })
+}
+
+fn open_write_sync(path: PathBuf, direct: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true).write(true)
+        .custom_flags(
+            if cfg!(unix) {
+                libc::O_SYNC | if direct { libc::O_DIRECT } else { 0 }
+            } else {
+                0
+            }
+        )
+        .open(path)
+}
+
+/// Opens the destination for buffered writes, which need to be flushed using sync_data
+fn open_write(path: PathBuf, direct: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true).write(true)
+        .custom_flags(if cfg!(unix) && direct { libc::O_DIRECT } else { 0 })
+        .open(path)
+}
+
+/// Writes a protective MBR covering the `size` bytes of the device or image file at `path`
+fn create_protective_mbr(
+    path: PathBuf,
+    size: u64,
+    lba: LogicalBlockSize,
+    bootable: bool,
+) -> Result<(), FlashError> {
+    let mut file = open_write_sync(path.clone(), false)
+        .map_err(|source| FlashError::DeviceAccess { path: path.clone(), source })?;
+
+    let mut mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
+        u32::try_from((size / u64::from(lba)) - 1).unwrap_or(0xFF_FF_FF_FF));
+    if bootable {
+        // Some firmware only boots from disks with an active MBR partition
+        if let Some(mut record) = mbr.partition(0) {
+            record.boot_indicator = MBR_BOOT_INDICATOR;
+            mbr.set_partition(0, record);
+        }
+    }
+    mbr.overwrite_lba0(&mut file)
+        .map_err(|source| FlashError::GptWrite { path: path.clone(), source })?;
+
+    Ok(())
+}
+
+/// Describes a raw image like a partition at its offset, sized to the whole blocks it covers
+fn raw_image_definition(
+    image: &RawImage,
+    lba_size: u64,
+) -> Result<PartitionDefinition, FlashError> {
+    let source_size = metadata(&image.source_file)
+        .map_err(|err| FlashError::source_access(image.source_file.clone(), err))?
+        .len();
+    let size = checked_align_up(source_size, lba_size)
+        .ok_or(FlashError::SizeOverflow { partition: image.name.clone(), size: source_size })?;
+
+    Ok(PartitionDefinition {
+        partition_name: image.name.clone(),
+        partition_type: None,
+        source_file: Some(image.source_file.clone()),
+        compression: Compression::None,
+        sparse: false,
+        size,
+        checksum: None,
+        part_guid: None,
+        flags: None,
+        start: Some(image.offset),
+        alignment: None,
+        ab_slots: false,
+        declared_size: None,
+    })
+}
+
+/// Size of the idbloader partition, which is padded to its alignment
+fn idbloader_size(idbloader: &Path) -> Result<u64, FlashError> {
+    let source_size = metadata(idbloader)
+        .map_err(|err| FlashError::source_access(idbloader.into(), err))?
+        .len();
+    checked_align_up(source_size, IDBLOADER_ALIGNMENT)
+        .ok_or(FlashError::SizeOverflow { partition: IDBLOADER_PARTNAME.into(), size: source_size })
+}
+
+/// Minimum size of a destination that fits the partitions in the order they are created,
+/// or `None` if that doesn't even fit into a u64
+fn required_size(
+    partitions: &[PartitionDefinition],
+    idbloader_size: Option<u64>,
+    lba_size: u64,
+) -> Option<u64> {
+    let mut end = 2 * lba_size + GPT_ENTRIES_SIZE;
+    if let Some(idbloader_size) = idbloader_size {
+        end = checked_align_up(end, IDBLOADER_ALIGNMENT)?.checked_add(idbloader_size)?;
+    }
+    // Partitions with a fixed start may leave gaps the others fill, this is only a lower bound then
+    let mut fixed_end = 0;
+    let mut is_first = true;
+    for partition_def in partitions {
+        match partition_def.start {
+            Some(start) => fixed_end = fixed_end.max(start.checked_add(partition_def.size)?),
+            None => {
+                let part_alignment = partition_def.effective_alignment(is_first);
+                is_first = false;
+                end = checked_align_up(end, part_alignment)?.checked_add(partition_def.size)?;
+            },
+        }
+    }
+
+    end.max(fixed_end).checked_add(GPT_ENTRIES_SIZE + lba_size)
+}
+
+/// Makes sure no two partitions end up with the same pinned or kept GUID
+fn check_partition_guids(
+    partitions: &[PartitionDefinition],
+    kept_partitions: &[Partition],
+) -> Result<(), FlashError> {
+    let kept_guids = kept_partitions.iter()
+        .map(|partition| (partition.part_guid, partition.name.as_str()));
+    let pinned_guids = partitions.iter()
+        .filter_map(|def| def.part_guid.map(|guid| (guid, def.partition_name.as_str())));
+
+    let mut guids: Vec<(Uuid, &str)> = vec![];
+    for (guid, name) in kept_guids.chain(pinned_guids) {
+        if let Some((_, first)) = guids.iter().find(|(other_guid, _)| *other_guid == guid) {
+            return Err(FlashError::DuplicatePartitionGuid {
+                guid: guid.to_string().to_uppercase(),
+                first: first.to_string(),
+                second: name.into(),
+            })
+        }
+        guids.push((guid, name));
+    }
+
+    Ok(())
+}
+
+/// Derives a GUID from the seed, so the same layout results in the same GUIDs.
+///
+/// Like systemd-repart, this uses the first half of a SHA-256 hash as a version 4 UUID.
+fn seeded_guid(guid_seed: &str, partition_name: Option<&str>) -> Uuid {
+    let mut hasher = Sha256::new();
+    hasher.update(guid_seed.as_bytes());
+    if let Some(partition_name) = partition_name {
+        hasher.update([0]);
+        hasher.update(partition_name.as_bytes());
+    }
+    let hash = hasher.finalize();
+
+    let mut bytes = [0_u8; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+/// Adds a partition at exactly `start` bytes, which must not overlap the partition table
+/// or any partition that has been added already
+fn place_partition(
+    disk: &mut GptDisk,
+    partition_def: &PartitionDefinition,
+    start: u64,
+    lba: LogicalBlockSize,
+) -> Result<Partition, FlashError> {
+    let lba_size = u64::from(lba);
+    let name = partition_def.partition_name.clone();
+    let invalid_start = |message: String| FlashError::PartitionStartInvalid {
+        partition: name.clone(),
+        start,
+        message,
+    };
+
+    if !start.is_multiple_of(lba_size) {
+        return Err(invalid_start(format!("not a multiple of {} bytes", lba_size)))
+    }
+    let (first_usable, last_usable) = disk.primary_header()
+        .map(|header| (header.first_usable, header.last_usable))
+        .ok_or_else(|| invalid_start("the partition table has no header".into()))?;
+    let first_lba = start / lba_size;
+    let last_lba = partition_def.size.div_ceil(lba_size).checked_sub(1)
+        .and_then(|length| first_lba.checked_add(length))
+        .ok_or_else(|| invalid_start("the partition is empty or too large".into()))?;
+    if first_lba < first_usable {
+        return Err(invalid_start("overlaps the partition table".into()))
+    }
+    if last_lba > last_usable {
+        return Err(invalid_start("the partition ends beyond the end of the destination".into()))
+    }
+    let overlapping = disk.partitions().values()
+        .find(|other| other.first_lba <= last_lba && first_lba <= other.last_lba);
+    if let Some(other) = overlapping {
+        return Err(FlashError::PartitionOverlap { partition: name, other: other.name.clone() })
+    }
+
+    let partition = Partition {
+        part_type_guid: partition_def.effective_type(),
+        part_guid: Uuid::new_v4(),
+        first_lba,
+        last_lba,
+        flags: partition_def.effective_flags(),
+        name: name.clone(),
+    };
+    insert_partition(disk, partition.clone()).map_err(|source| FlashError::LayoutTooLarge {
+        partition: name,
+        size: partition_def.size,
+        source,
+    })?;
+
+    Ok(partition)
+}
+
+/// Adds a partition exactly where it is, unlike [GptDisk::add_partition] which takes the
+/// first free space that is large enough
+fn insert_partition(disk: &mut GptDisk, partition: Partition) -> io::Result<u32> {
+    log::debug!(
+        "Inserting partition {} at LBAs {} to {}",
+        partition.name, partition.first_lba, partition.last_lba
+    );
+    let mut partitions = disk.partitions().clone();
+    let part_id = partitions.keys().max().map_or(1, |id| id + 1);
+    partitions.insert(part_id, partition);
+    disk.update_partitions(partitions)?;
+
+    Ok(part_id)
+}
+
+/// Lays out the partition table in memory on `disk`, as opened by [open_layout_disk], nothing
+/// is written to the destination yet.
+///
+/// `partitions` are the ones of `plan` with their slots expanded, the idbloader and the
+/// options for placing and growing partitions are taken from `plan` directly.
+/// When appending, the new partitions are placed into the free space `appended_to` leaves.
+/// Its partitions keep their numbers and new partitions take over the numbers of the ones
+/// they replace.
+fn layout_partition_table(
+    plan: &FlashPlan,
+    mut disk: GptDisk<'static>,
+    partitions: Vec<PartitionDefinition>,
+    raw_images: Vec<PartitionDefinition>,
+    kept_partitions: Vec<Partition>,
+    appended_to: Option<AppendedTable>,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(GptDisk<'static>, Vec<CreatedPartition>), FlashError> {
+    let lba = *disk.logical_block_size();
+    let lba_size = u64::from(lba);
+    let destination = plan.destination.clone();
+    let idbloader_raw = plan.idbloader_raw;
+    let guid_seed = plan.guid_seed.as_deref();
+    let fill_partition = plan.fill_partition.as_ref();
+    let grow_partition = plan.grow_partition.as_deref();
+    let reserve = plan.reserve;
+    let mut created_partitions = vec![];
+
+    // Make sure there are no partitions, besides the ones that are appended to
+    let appending = appended_to.is_some();
+    let AppendedTable { partitions: existing_partitions, replaced } =
+        appended_to.unwrap_or_default();
+    disk.update_partitions(existing_partitions.clone())
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+
+    if let Some(idbloader) = &plan.idbloader {
+        let loader_size = idbloader_size(idbloader)?;
+        match idbloader_raw {
+            true => on_event(FlashEvent::AddingRawImage {
+                name: IDBLOADER_PARTNAME.into(),
+                offset: IDBLOADER_ALIGNMENT,
+                size: loader_size,
+            }),
+            false => on_event(FlashEvent::AddingPartition {
+                name: IDBLOADER_PARTNAME.into(),
+                size: loader_size,
+            }),
+        }
+        // A raw idbloader is part of the layout until the end, so nothing is placed on top of it
+        let part_id = disk.add_partition(
+            IDBLOADER_PARTNAME,
+            loader_size,
+            partition_types::ANDROID_BOOTLOADER,
+            partition::REQUIRED_FLAG,
+            Some(IDBLOADER_ALIGNMENT / lba_size)
+        ).map_err(|source| FlashError::LayoutTooLarge {
+            partition: IDBLOADER_PARTNAME.into(),
+            size: loader_size,
+            source,
+        })?;
+
+        let partition = disk.partitions().get(&part_id)
+            .ok_or(FlashError::PartitionNotFound { name: IDBLOADER_PARTNAME.into() })?;
+        log::debug!(
+            "Added partition {} at LBAs {} to {}",
+            IDBLOADER_PARTNAME, partition.first_lba, partition.last_lba
+        );
+
+        created_partitions.push(
+            CreatedPartition {
+                def: Some(PartitionDefinition {
+                    partition_name: IDBLOADER_PARTNAME.into(),
+                    partition_type: None,
+                    source_file: Some(idbloader.clone()),
+                    compression: Compression::None,
+                    sparse: false,
+                    size: loader_size,
+                    checksum: None,
+                    part_guid: None,
+                    flags: None,
+                    start: None,
+                    alignment: None,
+                    ab_slots: false,
+                    declared_size: None,
+                }),
+                partition: partition.clone(),
+                raw: idbloader_raw,
+            }
+        );
+    }
+
+    // Like a raw idbloader, raw images are part of the layout until the partitions are numbered
+    for raw_image in &raw_images {
+        let start = raw_image.start.unwrap_or_default();
+        on_event(FlashEvent::AddingRawImage {
+            name: raw_image.partition_name.clone(),
+            offset: start,
+            size: raw_image.size,
+        });
+        let partition = place_partition(&mut disk, raw_image, start, lba)?;
+        created_partitions.push(CreatedPartition {
+            def: Some(raw_image.clone()),
+            partition,
+            raw: true,
+        });
+    }
+
+    // Partitions with a fixed start go first, so the others are laid out around them
+    let mut placed_partitions: Vec<Option<Partition>> = vec![None; partitions.len()];
+    for (index, partition_def) in partitions.iter().enumerate() {
+        if let Some(start) = partition_def.start {
+            on_event(FlashEvent::AddingPartition {
+                name: partition_def.partition_name.clone(),
+                size: partition_def.size,
+            });
+            placed_partitions[index] = Some(place_partition(&mut disk, partition_def, start, lba)?);
+        }
+    }
+
+    let mut is_first = true;
+    for (index, partition_def) in partitions.iter().enumerate() {
+        if partition_def.start.is_some() {
+            continue
+        }
+        let part_alignment = partition_def.effective_alignment(is_first);
+        is_first = false;
+        let part_size = partition_def.size;
+
+        on_event(FlashEvent::AddingPartition {
+            name: partition_def.partition_name.clone(),
+            size: part_size,
+        });
+
+        let part_id = disk.add_partition(
+            partition_def.partition_name.as_str(),
+            part_size,
+            partition_def.effective_type(),
+            partition_def.effective_flags(),
+            // Checked to be a multiple of the logical block size
+            Some(part_alignment / lba_size)
+        ).map_err(|source| FlashError::LayoutTooLarge {
+            partition: partition_def.partition_name.clone(),
+            size: part_size,
+            source,
+        })?;
+
+        let partition = disk.partitions().get(&part_id)
+            .ok_or_else(|| FlashError::PartitionNotFound {
+                name: partition_def.partition_name.clone()
+            })?;
+        log::debug!(
+            "Added partition {} of {} bytes aligned to {} bytes at LBAs {} to {}",
+            partition.name, part_size, part_alignment, partition.first_lba, partition.last_lba
+        );
+        placed_partitions[index] = Some(partition.clone());
+    }
+
+    // Partitions are numbered in the order they were given, no matter where they ended up
+    for (partition_def, partition) in partitions.iter().zip(placed_partitions) {
+        if let Some(partition) = partition {
+            created_partitions.push(CreatedPartition {
+                def: Some(partition_def.clone()),
+                partition,
+                raw: false,
+            });
+        }
+    }
+    let mut next_part_id = existing_partitions.keys().chain(replaced.values())
+        .max().map_or(1, |part_id| part_id + 1);
+    let mut numbered_partitions = existing_partitions.clone();
+    for created in created_partitions.iter().filter(|created| !created.raw) {
+        let part_id = match replaced.get(&created.partition.name) {
+            Some(part_id) => *part_id,
+            None => {
+                next_part_id += 1;
+                next_part_id - 1
+            },
+        };
+        numbered_partitions.insert(part_id, created.partition.clone());
+    }
+    disk.update_partitions(numbered_partitions)
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    // Raw images have no entry anymore, but growing and filling must still leave them alone
+    let raw_lbas: Vec<(u64, u64)> = created_partitions.iter()
+        .filter(|created| created.raw)
+        .map(|created| (created.partition.first_lba, created.partition.last_lba))
+        .collect();
+
+    // The new partitions are laid out as if there was nothing to keep, they must not move
+    // just to make room for the kept ones
+    let mut partitions_with_kept = disk.partitions().clone();
+    for kept_partition in &kept_partitions {
+        let conflict = created_partitions.iter().find(|created|
+            created.partition.first_lba <= kept_partition.last_lba
+                && kept_partition.first_lba <= created.partition.last_lba
+        );
+        if let Some(conflict) = conflict {
+            return Err(FlashError::KeptPartitionConflict {
+                kept: kept_partition.name.clone(),
+                partition: conflict.partition.name.clone(),
+            })
+        }
+
+        on_event(FlashEvent::KeepingPartition {
+            name: kept_partition.name.clone(),
+            first_lba: kept_partition.first_lba,
+            last_lba: kept_partition.last_lba,
+        });
+        let part_id = partitions_with_kept.keys().max().map_or(1, |id| id + 1);
+        partitions_with_kept.insert(part_id, kept_partition.clone());
+    }
+    disk.update_partitions(partitions_with_kept)
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+
+    // The reserved space ends where the backup partition table begins
+    let last_unreserved_lba = match reserve {
+        0 => None,
+        _ => {
+            let last_usable = disk.primary_header()
+                .map(|header| header.last_usable)
+                .unwrap_or_default();
+            let last_unreserved_lba = last_usable.checked_sub(reserve.div_ceil(lba_size))
+                .ok_or(FlashError::ReservedSpaceTooLarge { reserve })?;
+            let intruding = disk.partitions().values()
+                .find(|partition| partition.is_used() && partition.last_lba > last_unreserved_lba);
+            if let Some(partition) = intruding {
+                return Err(FlashError::ReservedSpaceUsed {
+                    partition: partition.name.clone(),
+                    reserve,
+                })
+            }
+            on_event(FlashEvent::ReservingSpace {
+                size: (last_usable - last_unreserved_lba) * lba_size,
+                first_lba: last_unreserved_lba + 1,
+                last_lba: last_usable,
+            });
+            Some(last_unreserved_lba)
+        },
+    };
+
+    // The grown partition takes the space the fill partition would get
+    if let Some(name) = grow_partition {
+        let created = created_partitions.iter_mut()
+            .find(|created| !created.raw && created.partition.name == name)
+            .ok_or_else(|| FlashError::PartitionNotFound { name: name.into() })?;
+        let last_usable = disk.primary_header()
+            .map(|header| header.last_usable)
+            .unwrap_or_default();
+        let last_lba = disk.partitions().values()
+            .filter(|other| other.is_used())
+            .map(|other| (other.first_lba, other.last_lba))
+            .chain(raw_lbas.iter().copied())
+            .filter(|(first_lba, _)| *first_lba > created.partition.last_lba)
+            .map(|(first_lba, _)| first_lba - 1)
+            .min()
+            .unwrap_or(last_usable);
+        let last_lba = last_unreserved_lba.map_or(last_lba, |reserved| last_lba.min(reserved));
+        if last_lba > created.partition.last_lba {
+            on_event(FlashEvent::GrowingPartition {
+                name: name.into(),
+                size: (last_lba + 1 - created.partition.first_lba) * lba_size,
+            });
+            log::debug!(
+                "Growing partition {} from LBA {} to {}",
+                name, created.partition.last_lba, last_lba
+            );
+            created.partition.last_lba = last_lba;
+            let mut grown_partitions = disk.partitions().clone();
+            for partition in grown_partitions.values_mut() {
+                if partition.is_used() && partition.name == name {
+                    partition.last_lba = last_lba;
+                }
+            }
+            disk.update_partitions(grown_partitions)
+                .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+        }
+    }
+
+    // Partitions called like the fill partition, and any userdata partition for one of that
+    // type, already take up what it would get
+    let fills = |name: &str, part_type: &partition_types::Type| {
+        fill_partition.is_some_and(|fill| fill.name == name
+            || fill.partition_type == partition_types::ANDROID_DATA
+                && *part_type == partition_types::ANDROID_DATA)
+    };
+    let has_created_fill_partition = partitions.iter()
+        .any(|def| fills(&def.partition_name, &def.effective_type()))
+        || kept_partitions.iter()
+        .any(|partition| fills(&partition.name, &partition.part_type_guid));
+    // Appending only adds what has been asked for
+    if !has_created_fill_partition && !appending && grow_partition.is_none() {
+        // For the remaining space, we'll create a partition, userdata by default
+        let last_free_sectors = disk.find_free_sectors().last()
+            .filter(|(_, length)| *length > 0)
+            .cloned();
+        // Placed explicitly, the next free space could be a gap left by a partition with a
+        // fixed start
+        let free_lbas = last_free_sectors.and_then(|(start, length)| {
+            let end = start + length;
+            // Raw images in that space, like U-Boot after an idbloader, come before it
+            let start = raw_lbas.iter()
+                .filter(|(first_lba, last_lba)| *first_lba < end && *last_lba >= start)
+                .map(|(_, last_lba)| last_lba + 1)
+                .fold(start, u64::max);
+            let first_lba = checked_align_up(start, PART_ALIGNMENT / lba_size)?;
+            let last_lba = end.checked_sub(1)
+                .map(|last| last_unreserved_lba.map_or(last, |reserved| last.min(reserved)))
+                .filter(|last| *last >= first_lba)?;
+            Some((first_lba, last_lba))
+        });
+        // The fill partition may be capped, the rest stays free, e.g. to grow the last
+        // partition into later or for wear leveling
+        let fill_lbas = free_lbas.zip(fill_partition).and_then(|((first_lba, last_lba), fill)| {
+            let max_last_lba = match fill.max_size {
+                Some(max_size) => first_lba.checked_add((max_size / lba_size).checked_sub(1)?)?,
+                None => last_lba,
+            };
+            Some((first_lba, last_lba.min(max_last_lba), fill))
+        });
+        let free_size = free_lbas.map_or(0, |(first_lba, last_lba)| {
+            let first_free_lba = fill_lbas.map_or(first_lba, |(_, last_lba, _)| last_lba + 1);
+            (last_lba + 1 - first_free_lba) * lba_size
+        });
+        if let Some((first_lba, last_lba, fill)) = fill_lbas {
+            let part_size = (last_lba + 1 - first_lba) * lba_size;
+            on_event(FlashEvent::AddingFillPartition {
+                name: fill.name.clone(),
+                size: part_size,
+            });
+            let partition = Partition {
+                part_type_guid: fill.partition_type.clone(),
+                part_guid: Uuid::new_v4(),
+                first_lba,
+                last_lba,
+                flags: 0,
+                name: fill.name.clone(),
+            };
+            insert_partition(&mut disk, partition.clone())
+                .map_err(|source| FlashError::LayoutTooLarge {
+                    partition: fill.name.clone(),
+                    size: part_size,
+                    source,
+                })?;
+            created_partitions.push(
+                CreatedPartition {
+                    def: None,
+                    partition,
+                    raw: false,
+                }
+            );
+        }
+        if free_size > 0 {
+            on_event(FlashEvent::LeavingFreeSpace { size: free_size });
+        }
+    }
+
+    if let Some(guid_seed) = guid_seed {
+        if !appending {
+            disk.update_guid(Some(seeded_guid(guid_seed, None)))
+                .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+        }
+
+        let mut seeded_partitions = disk.partitions().clone();
+        for partition in seeded_partitions.values_mut() {
+            let is_untouched = kept_partitions.iter().chain(existing_partitions.values())
+                .any(|untouched| untouched.name == partition.name);
+            if is_untouched {
+                continue
+            }
+            partition.part_guid = seeded_guid(guid_seed, Some(&partition.name));
+        }
+        for created_partition in created_partitions.iter_mut() {
+            let name = created_partition.partition.name.clone();
+            created_partition.partition.part_guid = seeded_guid(guid_seed, Some(&name));
+        }
+        disk.update_partitions(seeded_partitions)
+            .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    }
+
+    // Pinned GUIDs win over random and seeded ones
+    let mut pinned_partitions = disk.partitions().clone();
+    for created_partition in created_partitions.iter_mut() {
+        let pinned_guid = created_partition.def.as_ref().and_then(|def| def.part_guid);
+        if let Some(part_guid) = pinned_guid {
+            created_partition.partition.part_guid = part_guid;
+            let partition = pinned_partitions.values_mut()
+                .find(|partition| partition.name == created_partition.partition.name);
+            if let Some(partition) = partition {
+                partition.part_guid = part_guid;
+            }
+        }
+    }
+    disk.update_partitions(pinned_partitions)
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+
+    let mut bootable_partitions = disk.partitions().clone();
+    for name in &plan.bootable {
+        let partition = bootable_partitions.values_mut()
+            .find(|partition| partition.name == *name)
+            .ok_or_else(|| FlashError::PartitionNotFound { name: name.clone() })?;
+        on_event(FlashEvent::MarkingBootable { name: name.clone() });
+        partition.flags |= LEGACY_BOOT_FLAG;
+        let created_partition = created_partitions.iter_mut()
+            .find(|created| created.partition.name == *name);
+        if let Some(created_partition) = created_partition {
+            created_partition.partition.flags |= LEGACY_BOOT_FLAG;
+        }
+    }
+    disk.update_partitions(bootable_partitions)
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+
+    Ok((disk, created_partitions))
+}
+
+/// Writes the partition table laid out by [layout_partition_table]. If there are `hybrid_mbr`
+/// entries, they replace the MBR once the GPT is written, otherwise the MBR is left as it is.
+fn write_partition_table(
+    destination: PathBuf,
+    disk: GptDisk,
+    lba: LogicalBlockSize,
+    hybrid_mbr: &[MbrEntry],
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    on_event(FlashEvent::WritingPartitionTable);
+    let disk_guid = disk.guid().to_string().to_uppercase();
+    let disk_signature = disk_signature(&disk);
+    let partition_table = table::partition_infos(disk.partitions(), lba);
+    disk.write()
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    // Some tools only complain much later about a missing or broken backup
+    table::validate_partition_table(&destination, lba)?;
+    on_event(FlashEvent::PartitionTableWritten {
+        disk_guid,
+        lba_size: u64::from(lba),
+        partitions: partition_table,
+    });
+
+    if !hybrid_mbr.is_empty() {
+        let mut file = open_write_sync(destination.clone(), false)
+            .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+        mbr::write_mbr(&mut file, hybrid_mbr, disk_signature)
+            .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+        on_event(FlashEvent::HybridMbrWritten {
+            lba_size: u64::from(lba),
+            entries: hybrid_mbr.to_vec(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Writes a DOS partition table with `entries` instead of the GPT laid out by
+/// [layout_partition_table]
+fn write_mbr_partition_table(
+    destination: PathBuf,
+    disk: &GptDisk,
+    lba: LogicalBlockSize,
+    entries: &[MbrEntry],
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let lba_size = u64::from(lba);
+    on_event(FlashEvent::WritingPartitionTable);
+    let disk_signature = disk_signature(disk);
+    let mut file = open_write_sync(destination.clone(), false)
+        .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+    mbr::write_mbr(&mut file, entries, disk_signature)
+        .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+
+    // Tools prefer a backup GPT left over from before, the layout keeps its place free
+    let backup_size = GPT_ENTRIES_SIZE + lba_size;
+    let end = file.seek(SeekFrom::End(0))
+        .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+    if let Some(backup_start) = end.checked_sub(backup_size) {
+        file.write_all_at(&vec![0; backup_size as usize], backup_start)
+            .map_err(|source| FlashError::GptWrite { path: destination.clone(), source })?;
+    }
+
+    on_event(FlashEvent::MbrPartitionTableWritten {
+        disk_signature: format!("{:08x}", disk_signature),
+        lba_size,
+        entries: entries.to_vec(),
+    });
+
+    Ok(())
+}
+
+/// Disk signature of an MBR, taken from the disk GUID so it is seeded along with it
+fn disk_signature(disk: &GptDisk) -> u32 {
+    let guid = disk.guid().as_bytes();
+    u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]])
+}
+
+/// Opens the partition table of `destination` to lay it out.
+///
+/// Appending reads the existing partition table to keep its disk GUID, everything else starts
+/// from an empty one. Image files that are not appended to are only stood in for by their
+/// size, they stay untouched until the layout is known to fit and [write_plan] creates them.
+fn open_layout_disk(
+    destination: &Path,
+    size: u64,
+    is_block_device: bool,
+    appending: bool,
+    lba: LogicalBlockSize,
+) -> Result<GptDisk<'static>, FlashError> {
+    let cfg = gpt::GptConfig::new()
+        .initialized(appending)
+        .writable(true)
+        .logical_block_size(lba);
+    let gpt_error = |source| FlashError::GptWrite { path: destination.into(), source };
+    match !is_block_device && !appending {
+        true => cfg.open_from_device(Box::new(UncreatedImage { len: size, position: 0 }))
+            .map_err(gpt_error),
+        false => cfg.open(destination).map_err(gpt_error),
+    }
+}
+
+/// Stands in for an image file that is only created once the layout fits. The partition table
+/// is laid out against its size, reading or writing it fails.
+#[derive(Debug)]
+struct UncreatedImage {
+    len: u64,
+    position: u64,
+}
+
+impl Read for UncreatedImage {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Write for UncreatedImage {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for UncreatedImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+/// Creates an image file of `size` bytes, `keep_contents` only resizes an existing one
+fn create_sparse_file(
+    path: impl AsRef<Path>,
+    size: u64,
+    keep_contents: bool,
+) -> Result<(), FlashError> {
+    let path = path.as_ref();
+    let mut open_options = OpenOptions::new();
+    open_options.read(true).write(true).create(true).truncate(!keep_contents);
+
+    let mut file = open_options.open(path)
+        .map_err(|source| FlashError::DeviceAccess { path: path.into(), source })?;
+
+    if keep_contents {
+        return file.set_len(size)
+            .map_err(|source| FlashError::DeviceAccess { path: path.into(), source })
+    }
+
+    // Write its last byte so the file is actually `size` bytes long
+    file.seek(SeekFrom::Start(size - 1))
+        .map_err(|source| FlashError::DeviceAccess { path: path.into(), source })?;
+    file.write(&[0x00])
+        .map_err(|source| FlashError::DeviceAccess { path: path.into(), source })?;
+
+    Ok(())
+}
+
+/// Unmounts filesystems of the destination, nested mount points first. Failures are reported,
+/// but the destination is written anyway, this is only done with force.
+fn unmount_all(mut mounts: Vec<mounts::Mount>, on_event: &mut dyn FnMut(FlashEvent)) {
+    mounts.sort_by_key(|mount| std::cmp::Reverse(mount.mount_point.components().count()));
+    for mount in mounts {
+        on_event(FlashEvent::Unmounting {
+            source: mount.source.clone(),
+            mount_point: mount.mount_point.clone(),
+        });
+        match run_command(Command::new("umount").arg(&mount.mount_point)) {
+            Ok(()) => on_event(FlashEvent::Unmounted { mount_point: mount.mount_point }),
+            Err(message) => on_event(FlashEvent::UnmountFailed {
+                mount_point: mount.mount_point,
+                message,
+            }),
+        }
+    }
+}
+
+/// Zeroes the first `size` bytes of the destination, at most [FIRST_PART_ALIGNMENT]
+fn erase_beginning(
+    path: PathBuf,
+    start: u64,
+    size: u64,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    const ERASE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+    on_event(FlashEvent::ErasingBeginning { size });
+    let file = open_write_sync(path.clone(), false)
+        .map_err(|source| FlashError::DeviceAccess { path: path.clone(), source })?;
+
+
+    // First we'll erase the first 8 MiB to make sure there are no leftovers of old loaders,
+    // everything before `start` stays
+    let size = size.min(FIRST_PART_ALIGNMENT);
+    if clear_range(&file, true, start, size - start).is_ok() {
+        on_event(FlashEvent::EraseProgress { erased: size });
+    } else {
+        let zeros = vec![0_u8; ERASE_CHUNK_SIZE as usize];
+        for offset in (start..size).step_by(ERASE_CHUNK_SIZE as usize) {
+            let chunk_size = ERASE_CHUNK_SIZE.min(size - offset);
+            file.write_all_at(&zeros[..chunk_size as usize], offset)
+                .map_err(|source| FlashError::DeviceAccess { path: path.clone(), source })?;
+            on_event(FlashEvent::EraseProgress { erased: offset + chunk_size });
+        }
+    }
+
+    on_event(FlashEvent::ErasedBeginning);
+    Ok(())
+}
+
+/// Zeroes all `size` bytes of the destination in chunks of `block_size`, discarding them
+/// where the destination supports it
+fn wipe_destination(
+    path: PathBuf,
+    size: u64,
+    is_block_device: bool,
+    block_size: usize,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    on_event(FlashEvent::WipingDestination { size });
+    let file = open_write(path.clone(), false)
+        .map_err(|source| FlashError::DeviceAccess { path: path.clone(), source })?;
+
+    // Once clearing failed it won't work for the next chunk either
+    let mut can_clear = true;
+    let zeros = vec![0_u8; block_size];
+    for offset in (0..size).step_by(block_size) {
+        let chunk_size = (block_size as u64).min(size - offset);
+        can_clear = can_clear && clear_range(&file, is_block_device, offset, chunk_size).is_ok();
+        if !can_clear {
+            file.write_all_at(&zeros[..chunk_size as usize], offset)
+                .map_err(|source| FlashError::DeviceAccess { path: path.clone(), source })?;
+        }
+        on_event(FlashEvent::WipeProgress { wiped: offset + chunk_size });
+    }
+    file.sync_data()
+        .map_err(|source| FlashError::DeviceAccess { path: path.clone(), source })?;
+
+    on_event(FlashEvent::DestinationWiped);
+    Ok(())
+}
+
+/// Opens the source image of a partition, yielding exactly the bytes that end up on disk
+fn open_source(
+    def: &PartitionDefinition,
+    source_file: PathBuf,
+) -> io::Result<Box<dyn Read + Send>> {
+    if is_stdin(&source_file) {
+        return Ok(Box::new(io::stdin()))
+    }
+    let reader = open_decompressed(source_file, def.compression)?;
+    if def.sparse {
+        Ok(Box::new(SparseReader::new(reader)?))
+    } else {
+        Ok(reader)
+    }
+}
+
+/// Like [open_source], with `patched_flags` set in the header of a vbmeta image as it is read
+fn open_patched_source(
+    def: &PartitionDefinition,
+    source_file: PathBuf,
+    patched_flags: u32,
+) -> io::Result<Box<dyn Read + Send>> {
+    let reader = open_source(def, source_file)?;
+    Ok(match patched_flags {
+        0 => reader,
+        flags => Box::new(avb::FlagPatcher::new(reader, flags)),
+    })
+}
+
+/// Writes the images of `partitions` to the destination of `plan` and clears the blank ones,
+/// with the block size, retries and other write options of `plan`
+fn write_images(
+    plan: &FlashPlan,
+    partitions: Vec<CreatedPartition>,
+    is_block_device: bool,
+    lba: LogicalBlockSize,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<Vec<WrittenImage>, FlashError> {
+    let lba_size = u64::from(lba);
+    let destination = plan.destination.clone();
+    let &FlashPlan {
+        block_size, buffer_count, direct, sparse_copy, clear_tail, write_retries, retry_delay,
+        max_write_rate, vbmeta_flags, ..
+    } = plan;
+    let mut written_images = vec![];
+    // Synchronous writes are very slow on SD cards, each partition is flushed once instead
+    let (file, direct) = match open_write(destination.clone(), direct) {
+        // Not every filesystem supports O_DIRECT, e.g. tmpfs rejects it when opening
+        Err(err) if direct && err.raw_os_error() == Some(libc::EINVAL) => {
+            on_event(FlashEvent::DirectIoUnsupported { path: destination.clone() });
+            (open_write(destination.clone(), false), false)
+        },
+        file => (file, direct),
+    };
+    let file = file
+        .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+    log::debug!(
+        "Writing to {} in blocks of {} bytes with {} buffers, O_DIRECT: {}",
+        destination.to_string_lossy(), block_size, buffer_count, direct
+    );
+    let mut writer = RetryingWriter {
+        path: destination.clone(),
+        direct,
+        file,
+        retry_delay,
+        retries_left: write_retries,
+    };
+
+    const CLEAR_SIZE: usize = 1024;
+    const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
+    // O_DIRECT needs the buffer, the length and the offset of every write to be block aligned
+    let mut buffer = AlignedBuffer::new(block_size, DIRECT_IO_ALIGNMENT);
+    let mut rate_limiter = max_write_rate.filter(|rate| *rate > 0).map(RateLimiter::new);
+
+    for partition in partitions {
+        let name = partition.partition.name.clone();
+        on_event(FlashEvent::WritingPartition {
+            name: name.clone(),
+            size: partition.def.as_ref()
+                .filter(|def| def.source_file.is_some())
+                .map(|def| def.size),
+        });
+        let partition_start = partition.partition.first_lba * lba_size;
+        let partition_len = partition.partition.bytes_len(lba)
+            .map_err(|source| FlashError::GptRead { path: destination.clone(), source })?;
+        let write_error = |offset, source| FlashError::WriteFailed {
+            partition: name.clone(),
+            offset,
+            source,
+        };
+        writer.retries_left = write_retries;
+        log::debug!(
+            "Partition {} spans {} bytes from offset {:#x}", name, partition_len, partition_start
+        );
+
+        // Both def and def.source_file must be Some, otherwise there's no point
+        // in writing anything. This if statement matches both at the same time.
+        if let Some((def, Some(source_file))) = partition.def.and_then(
+            |def| Some((def.clone(), def.source_file))
+        ) {
+            // Once the whole partition reads as zeros, zero blocks of the image can be skipped.
+            // Discarded blocks may still return old data, so the zeros are written then.
+            let zeroed = sparse_copy && matches!(
+                clear_range(&writer.file, is_block_device, partition_start, partition_len),
+                Ok(Cleared::Zeroed)
+            );
+            // Clear the first KiB to make sure there is no file system, after the partition
+            // has been cleared as that may have only discarded it
+            buffer.fill(0);
+            writer.write_at(&name, partition_start, on_event, |file|
+                file.write_all_at(&buffer[..CLEAR_SIZE], partition_start)
+            )?;
+
+            // Plain images can be copied to image files by the kernel, which also keeps holes.
+            // The slack after the last byte is only zero if the partition has been cleared.
+            // The kernel can't be throttled, so limited writes always go through the loop below.
+            let patched_flags = match avb::is_vbmeta_partition(&name) {
+                true => vbmeta_flags,
+                false => 0,
+            };
+            let is_plain_image = !def.sparse && def.compression == Compression::None
+                && patched_flags == 0 && !is_stdin(&source_file);
+            let copied_in_kernel = if zeroed && !is_block_device && is_plain_image
+                && rate_limiter.is_none() {
+                copy_in_kernel(
+                    &source_file, &writer.file, partition_start, partition_len,
+                    &mut |bytes_written| on_event(FlashEvent::WriteProgress {
+                        name: name.clone(),
+                        written: bytes_written,
+                        total: def.size,
+                    }),
+                ).ok()
+            } else {
+                None
+            };
+
+            if let Some(bytes_copied) = copied_in_kernel {
+                log::debug!("Copied {} bytes of {} in the kernel", bytes_copied, name);
+            }
+            let mut bytes_copied = copied_in_kernel.unwrap_or(0);
+            let mut hasher = Sha256::new();
+            if copied_in_kernel.is_some() {
+                // The kernel copied the source as it is, its data never passed through here
+                let mut input_file = open_source(&def, source_file.clone())
+                    .map_err(|err| FlashError::source_access(source_file.clone(), err))?;
+                copy(&mut input_file, &mut hasher)
+                    .map_err(|err| FlashError::source_access(source_file.clone(), err))?;
+            } else {
+                let input_file = open_patched_source(&def, source_file.clone(), patched_flags)
+                    .map_err(|err| FlashError::source_access(source_file.clone(), err))?;
+                let mut last_reported = 0;
+                bytes_copied = copy_pipelined(
+                    input_file,
+                    &|source| FlashError::SourceReadFailed {
+                        partition: name.clone(),
+                        path: source_file.clone(),
+                        source,
+                    },
+                    buffer_count, block_size,
+                    &mut |buffer, read, copied| {
+                        // Always write whole blocks, as O_DIRECT requires and the tail is cleared
+                        // in blocks too. The padding of the last block is zero like the rest of
+                        // the partition.
+                        let write_len = align_up_pow2(read, lba_size as usize);
+                        if let Some(size) = def.declared_size
+                            .filter(|size| copied + read as u64 > *size) {
+                            return Err(FlashError::StdinTooLong { partition: name.clone(), size })
+                        }
+                        if copied + write_len as u64 > partition_len {
+                            return Err(FlashError::ImageTooLarge {
+                                partition: name.clone(),
+                                path: source_file.clone(),
+                                size: None,
+                                available: partition_len,
+                            })
+                        }
+                        buffer[read..write_len].fill(0);
+                        hasher.update(&buffer[..read]);
+
+                        let offset = partition_start + copied;
+                        log::debug!(
+                            "Writing {} bytes of {} at offset {:#x}", write_len, name, offset
+                        );
+                        if let Some(rate_limiter) = &mut rate_limiter {
+                            rate_limiter.acquire(write_len as u64);
+                        }
+                        writer.write_at(&name, offset, on_event, |file| if zeroed {
+                            write_skipping_zeros(file, &buffer[..write_len], offset)
+                        } else {
+                            file.write_all_at(&buffer[..write_len], offset)
+                        })?;
+
+                        // Report the decompressed bytes, these are what ends up in the partition
+                        let written = copied + read as u64;
+                        if written - last_reported >= PROGRESS_INTERVAL {
+                            last_reported = written;
+                            on_event(FlashEvent::WriteProgress {
+                                name: name.clone(),
+                                written,
+                                total: def.size,
+                            });
+                        }
+                        Ok(())
+                    }
+                )?;
+            }
+            // The last chunk is usually smaller than the reporting interval
+            on_event(FlashEvent::WriteProgress {
+                name: name.clone(),
+                written: bytes_copied,
+                total: def.size,
+            });
+
+            let clear_start = align_up_pow2(bytes_copied, lba_size);
+            // Otherwise whatever the partition held before is left after the image
+            let clears_tail = clear_tail && clear_start < partition_len;
+            if clears_tail && !zeroed {
+                on_event(FlashEvent::ClearingPartition {
+                    name: name.clone(),
+                    remaining: partition_len - clear_start,
+                });
+            }
+
+            // Clearing on the device is a lot faster than writing zeros and doesn't wear out the flash
+            let discarded = zeroed || clears_tail && clear_range(
+                &writer.file, is_block_device,
+                partition_start + clear_start, partition_len - clear_start
+            ).is_ok();
+
+            if clears_tail && !discarded {
+                buffer.fill(0);
+                let mut last_reported = 0;
+                for offset in (clear_start..partition_len).step_by(buffer.len()) {
+                    // Partitions end on a block boundary, so this stays aligned
+                    let len = (partition_len - offset).min(buffer.len() as u64) as usize;
+                    log::debug!(
+                        "Writing {} zeros to {} at offset {:#x}",
+                        len, name, partition_start + offset
+                    );
+                    if let Some(rate_limiter) = &mut rate_limiter {
+                        rate_limiter.acquire(len as u64);
+                    }
+                    writer.write_at(&name, partition_start + offset, on_event, |file|
+                        file.write_all_at(&buffer[..len], partition_start + offset)
+                    )?;
+                    let cleared = offset + len as u64 - clear_start;
+                    if cleared - last_reported >= PROGRESS_INTERVAL {
+                        last_reported = cleared;
+                        on_event(FlashEvent::ClearProgress {
+                            name: name.clone(),
+                            cleared,
+                            total: partition_len - clear_start,
+                        });
+                    }
+                }
+            }
+
+            // A failed flush can't be retried, the kernel may have dropped the data already
+            on_event(FlashEvent::SyncingPartition { name: name.clone() });
+            writer.file.sync_data()
+                .map_err(|source| write_error(partition_start, source))?;
+
+            let checksum = Checksum::Sha256(hasher.finalize().to_vec());
+            on_event(FlashEvent::PartitionWritten {
+                name: name.clone(),
+                written: bytes_copied,
+                offset: partition_start,
+                source_file: source_file.clone(),
+                sha256: checksum.clone(),
+            });
+            // Stdin has been consumed
+            let source = (!is_stdin(&source_file)).then(|| ImageSource {
+                def: def.clone(),
+                path: source_file.clone(),
+                patched_flags,
+            });
+            written_images.push(WrittenImage {
+                name,
+                offset: partition_start,
+                bytes_written: bytes_copied,
+                checksum,
+                source,
+            });
+        } else {
+            // Blank partitions like userdata can be huge, so they are only cleared completely
+            // if the destination can do that without writing zeros
+            let _ = clear_range(&writer.file, is_block_device, partition_start, partition_len);
+            // The first KiB is always written, so there is no file system left either way
+            buffer.fill(0);
+            writer.write_at(&name, partition_start, on_event, |file|
+                file.write_all_at(&buffer[..CLEAR_SIZE], partition_start)
+            )?;
+            writer.file.sync_data()
+                .map_err(|source| write_error(partition_start, source))?;
+            on_event(FlashEvent::PartitionCleared { name });
+        }
+    }
+
+    on_event(FlashEvent::WriteFinished);
+
+    Ok(written_images)
+}
+
+/// The destination opened for writing, which is reopened to retry writes that failed
+/// for a reason that might go away, like a USB card reader resetting
+struct RetryingWriter {
+    path: PathBuf,
+    direct: bool,
+    file: File,
+    retry_delay: Duration,
+    /// Retries left for the partition being written
+    retries_left: u32,
+}
+
+impl RetryingWriter {
+    /// Runs `write`, which writes to `offset` of partition `name`, until it succeeds or there
+    /// are no retries left. Every write goes to an explicit offset, so repeating it after
+    /// reopening writes exactly the same bytes to the same place again.
+    ///
+    /// The device gets more time to recover with every retry of the same write.
+    fn write_at(
+        &mut self,
+        name: &str,
+        offset: u64,
+        on_event: &mut dyn FnMut(FlashEvent),
+        write: impl Fn(&File) -> io::Result<()>,
+    ) -> Result<(), FlashError> {
+        let mut result = write(&self.file);
+        let mut delay = self.retry_delay;
+        while let Err(err) = result {
+            if self.retries_left == 0 || !is_transient_error(&err) {
+                return Err(FlashError::WriteFailed { partition: name.into(), offset, source: err })
+            }
+            self.retries_left -= 1;
+            on_event(FlashEvent::RetryingWrite {
+                name: name.into(),
+                offset,
+                retries_left: self.retries_left,
+                message: err.to_string(),
+            });
+
+            sleep(delay);
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            result = open_write(self.path.clone(), self.direct).and_then(|file| {
+                self.file = file;
+                write(&self.file)
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether an error might go away when trying again, like the device being reset or
+/// briefly disappearing while it is enumerated again
+fn is_transient_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EIO | libc::ENODEV | libc::ENXIO | libc::ENOENT | libc::ETIMEDOUT)
+    )
+}
+
+/// Reads a limit of the request queue of the block device behind `file` from sysfs,
+/// e.g. discard_max_bytes. Partitions share the queue of their disk.
+fn queue_limit(file: &File, limit: &str) -> Option<u64> {
+    let rdev = file.metadata().ok()?.rdev();
+    let mut sysfs_dir = Path::new("/sys/dev/block")
+        .join(format!("{}:{}", libc::major(rdev), libc::minor(rdev)))
+        .canonicalize().ok()?;
+    if sysfs_dir.join("partition").exists() {
+        sysfs_dir = sysfs_dir.parent()?.into();
+    }
+
+    read_to_string(sysfs_dir.join("queue").join(limit)).ok()?.trim().parse().ok()
+}
+
+/// How `clear_range` got rid of the contents of a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cleared {
+    /// The range reads back as zeros, after a BLKZEROOUT or a punched hole
+    Zeroed,
+    /// The range has been discarded, the device may still return the old data or anything else
+    Discarded,
+}
+
+/// Clears `len` bytes at `offset` without writing them.
+///
+/// Block devices get a BLKZEROOUT if they can zero blocks without transferring zeros,
+/// otherwise a BLKDISCARD if they support discarding. Image files get a hole punched into them.
+/// Fails if none of that works, the caller has to write zeros then. Only `Cleared::Zeroed`
+/// guarantees that the range reads as zeros.
+pub(crate) fn clear_range(
+    file: &File,
+    is_block_device: bool,
+    offset: u64,
+    len: u64,
+) -> io::Result<Cleared> {
+    log::debug!("Clearing {} bytes at offset {:#x}", len, offset);
+    let (result, cleared) = if is_block_device {
+        let (request, cleared) = if queue_limit(file, "write_zeroes_max_bytes").unwrap_or(0) > 0 {
+            (BLKZEROOUT, Cleared::Zeroed)
+        } else if queue_limit(file, "discard_max_bytes").unwrap_or(0) > 0 {
+            (BLKDISCARD, Cleared::Discarded)
+        } else {
+            return Err(io::ErrorKind::Unsupported.into())
+        };
+        let range: [u64; 2] = [offset, len];
+        (unsafe { libc::ioctl(file.as_raw_fd(), request as _, &range) }, cleared)
+    } else {
+        let result = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        (result, Cleared::Zeroed)
+    };
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        log::debug!("Clearing {} bytes at offset {:#x} failed: {}", len, offset, err);
+        return Err(err)
+    }
+    Ok(cleared)
+}
+
+/// Copies the file at `source` to `offset` of `destination` using copy_file_range,
+/// reporting the bytes copied so far. Only the data of the source is copied, its holes
+/// are skipped, so `destination` has to read as zeros there already.
+///
+/// Fails if the kernel can't copy between the two files, e.g. across filesystems on older
+/// kernels, or if the file doesn't fit into `max_len`. The caller has to copy it itself then.
+#[cfg(target_os = "linux")]
+fn copy_in_kernel(
+    source: &Path,
+    destination: &File,
+    offset: u64,
+    max_len: u64,
+    on_progress: &mut dyn FnMut(u64),
+) -> io::Result<u64> {
+    const COPY_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+    let source = File::open(source)?;
+    let source_len = source.metadata()?.len() as libc::loff_t;
+    if source_len as u64 > max_len {
+        return Err(io::ErrorKind::InvalidInput.into())
+    }
+
+    let mut source_offset: libc::loff_t = 0;
+    while source_offset < source_len {
+        // Filesystems without support for finding holes report everything as data
+        let data_start = unsafe {
+            libc::lseek(source.as_raw_fd(), source_offset, libc::SEEK_DATA)
+        };
+        if data_start < 0 {
+            match io::Error::last_os_error() {
+                // There is only a hole left
+                err if err.raw_os_error() == Some(libc::ENXIO) => break,
+                err => return Err(err),
+            }
+        }
+        let data_end = unsafe { libc::lseek(source.as_raw_fd(), data_start, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        source_offset = data_start;
+        let mut destination_offset = offset as libc::loff_t + data_start;
+        while source_offset < data_end {
+            let chunk_len = ((data_end - source_offset) as usize).min(COPY_CHUNK_SIZE);
+            let copied = unsafe {
+                libc::copy_file_range(
+                    source.as_raw_fd(), &mut source_offset,
+                    destination.as_raw_fd(), &mut destination_offset,
+                    chunk_len, 0,
+                )
+            };
+            match copied {
+                // The source has been truncated in the meantime
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                copied if copied < 0 => return Err(io::Error::last_os_error()),
+                _ => on_progress(source_offset as u64),
+            }
+        }
+    }
+
+    Ok(source_len as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_in_kernel(
+    _source: &Path,
+    _destination: &File,
+    _offset: u64,
+    _max_len: u64,
+    _on_progress: &mut dyn FnMut(u64),
+) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Reads `reader` on a separate thread into `buffer_count` buffers of `block_size` bytes and
+/// hands every filled one to `write` together with the bytes read into it and their position
+/// in the stream, so reading and writing overlap. Returns the number of bytes read.
+///
+/// The buffers are aligned for O_DIRECT. `write` may change them, e.g. to pad the last block.
+fn copy_pipelined(
+    mut reader: Box<dyn Read + Send>,
+    read_error: &dyn Fn(io::Error) -> FlashError,
+    buffer_count: usize,
+    block_size: usize,
+    write: &mut dyn FnMut(&mut AlignedBuffer, usize, u64) -> Result<(), FlashError>,
+) -> Result<u64, FlashError> {
+    let (free_sender, free_receiver) = mpsc::channel::<AlignedBuffer>();
+    let (filled_sender, filled_receiver) = mpsc::sync_channel(buffer_count.max(1));
+    for _ in 0..buffer_count.max(1) {
+        let _ = free_sender.send(AlignedBuffer::new(block_size, DIRECT_IO_ALIGNMENT));
+    }
+
+    // Both channels are moved in, so the reader stops as soon as the writer returns
+    thread::scope(move |scope| {
+        scope.spawn(move || {
+            while let Ok(mut buffer) = free_receiver.recv() {
+                let result = read_fully(&mut reader, &mut buffer);
+                let done = !matches!(result, Ok(read) if read > 0);
+                if filled_sender.send(result.map(|read| (buffer, read))).is_err() || done {
+                    break
+                }
+            }
+        });
+
+        let mut bytes_copied = 0_u64;
+        for filled in filled_receiver {
+            let (mut buffer, read): (AlignedBuffer, usize) = filled.map_err(read_error)?;
+            if read == 0 {
+                break
+            }
+            write(&mut buffer, read, bytes_copied)?;
+            bytes_copied += read as u64;
+            let _ = free_sender.send(buffer);
+        }
+
+        Ok(bytes_copied)
+    })
+}
+
+/// Writes `data` at `offset`, leaving out chunks that are all zeros.
+///
+/// The chunks are block aligned, so this works with O_DIRECT as long as `data` does.
+fn write_skipping_zeros(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    const ZERO_CHUNK_SIZE: usize = 64 * 1024;
+
+    // Consecutive chunks with data are written at once
+    let mut data_start = None;
+    for (index, chunk) in data.chunks(ZERO_CHUNK_SIZE).enumerate() {
+        let chunk_start = index * ZERO_CHUNK_SIZE;
+        match (chunk.iter().all(|&byte| byte == 0), data_start) {
+            (false, None) => data_start = Some(chunk_start),
+            (true, Some(start)) => {
+                file.write_all_at(&data[start..chunk_start], offset + start as u64)?;
+                data_start = None;
+            },
+            _ => {},
+        }
+    }
+    if let Some(start) = data_start {
+        file.write_all_at(&data[start..], offset + start as u64)?;
+    }
+
+    Ok(())
+}
+
+/// Reads from `reader` until `buf` is full or the end of the stream is reached
+fn read_fully(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Drops cached pages of `file` so the following reads hit the medium
+pub(crate) fn drop_caches(file: &File) -> io::Result<()> {
+    file.sync_all()?;
+    if cfg!(unix) {
+        let result = unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED)
+        };
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result))
+        }
+    }
+    Ok(())
+}
+
+/// Hashes everything `reader` yields, returning the digest and the number of bytes read
+fn sha256_of(reader: &mut impl Read) -> io::Result<(Checksum, u64)> {
+    let mut hasher = Sha256::new();
+    let length = copy(reader, &mut hasher)?;
+    Ok((Checksum::Sha256(hasher.finalize().to_vec()), length))
+}
+
+fn verify_images(
+    destination: PathBuf,
+    images: Vec<WrittenImage>,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    // Without O_SYNC, reading is a lot faster
+    let file = File::open(destination.clone())
+        .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+    drop_caches(&file)
+        .map_err(|source| FlashError::DeviceAccess { path: destination.clone(), source })?;
+
+    const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+    let mut failed_partitions = vec![];
+
+    for image in images {
+        on_event(FlashEvent::VerifyingPartition {
+            name: image.name.clone(),
+            size: image.bytes_written,
+        });
+
+        // The source has been hashed while it was written, no need to read it again
+        let expected = image.checksum.clone();
+
+        let read_error = |source| FlashError::ReadFailed {
+            partition: image.name.clone(),
+            offset: image.offset,
+            source,
+        };
+        (&file).seek(SeekFrom::Start(image.offset)).map_err(read_error)?;
+
+        // Only compare what has actually been written, the rest of the partition is zeroed
+        let mut written_range = ProgressReader::new(
+            (&file).take(image.bytes_written), PROGRESS_INTERVAL, |bytes_verified| {
+                on_event(FlashEvent::VerifyProgress {
+                    name: image.name.clone(),
+                    verified: bytes_verified,
+                    total: image.bytes_written,
+                });
+            }
+        );
+        let (actual, verified) = sha256_of(&mut written_range).map_err(read_error)?;
+        if verified != image.bytes_written {
+            return Err(read_error(io::Error::from(io::ErrorKind::UnexpectedEof)))
+        }
+
+        if actual == expected {
+            on_event(FlashEvent::PartitionVerified { name: image.name, verified });
+        } else {
+            // The checksums only tell that something differs, the source tells where
+            let difference = match &image.source {
+                Some(source) => first_difference(&file, &image, source)?,
+                None => None,
+            };
+            on_event(FlashEvent::PartitionVerifyFailed {
+                name: image.name.clone(),
+                mismatch: difference.unwrap_or(Mismatch::Checksum { expected, actual }),
+            });
+            failed_partitions.push(image.name);
+        }
+    }
+
+    on_event(FlashEvent::VerifyFinished);
+
+    if !failed_partitions.is_empty() {
+        return Err(FlashError::VerifyFailed { partitions: failed_partitions })
+    }
+
+    Ok(())
+}
+
+/// Reads the source of `image` and the data written from it again, to find the first byte that
+/// differs. There is none if the source has changed in the same way since.
+fn first_difference(
+    file: &File,
+    image: &WrittenImage,
+    source: &ImageSource,
+) -> Result<Option<Mismatch>, FlashError> {
+    const CHUNK_SIZE: usize = 1024 * 1024;
+    let source_error = |err| FlashError::source_access(source.path.clone(), err);
+    let mut input = open_patched_source(&source.def, source.path.clone(), source.patched_flags)
+        .map_err(source_error)?;
+
+    let mut expected = vec![0_u8; CHUNK_SIZE];
+    let mut actual = vec![0_u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < image.bytes_written {
+        let len = (image.bytes_written - offset).min(CHUNK_SIZE as u64) as usize;
+        input.read_exact(&mut expected[..len]).map_err(source_error)?;
+        file.read_exact_at(&mut actual[..len], image.offset + offset)
+            .map_err(|source| FlashError::ReadFailed {
+                partition: image.name.clone(),
+                offset: image.offset + offset,
+                source,
+            })?;
+        if let Some(position) = first_differing_byte(&expected[..len], &actual[..len]) {
+            return Ok(Some(Mismatch::Byte {
+                offset: offset + position as u64,
+                expected: expected[position],
+                actual: actual[position],
+            }))
+        }
+        offset += len as u64;
+    }
+
+    Ok(None)
+}
+
+/// Index of the first byte that differs between `expected` and `actual`
+pub(crate) fn first_differing_byte(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    expected.iter().zip(actual).position(|(expected, actual)| expected != actual)
+}
+
+/// Asks the kernel to reread the partition table of `destination`, so the partition devices
+/// match it
+fn probe_partitions(destination: &Path, on_event: &mut dyn FnMut(FlashEvent)) {
+    on_event(FlashEvent::ProbingPartitions);
+    for message in reread_partitions(destination) {
+        on_event(FlashEvent::ProbeFailed { message });
+    }
+}
+
+/// Rereads the partition table of a block device and waits for udev to create the links
+/// in /dev/disk for its partitions. Returns what went wrong, as none of it has to be fatal.
+///
+/// BLKRRPART fails while a partition of the device is in use, partprobe tells the kernel
+/// about every partition on its own then.
+fn reread_partitions(destination: &Path) -> Vec<String> {
+    if !is_block_device(destination).unwrap_or(false) {
+        return vec![]
+    }
+
+    let mut failures = vec![];
+    let reread = File::open(destination).and_then(|file| {
+        match unsafe { libc::ioctl(file.as_raw_fd(), BLKRRPART as _) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    });
+    // Without partprobe, BLKRRPART has to do
+    let has_partprobe = find_program("partprobe");
+    if let Err(err) = &reread {
+        failures.push(format!("BLKRRPART failed: {}", err));
+        if !has_partprobe {
+            failures.push("partprobe is not installed to reread the partitions otherwise".into());
+        }
+    }
+    if has_partprobe {
+        match Command::new("partprobe").arg(destination).output() {
+            Ok(output) if !output.status.success() => failures.push(format!(
+                "partprobe failed:\n{}\n{}",
+                String::from_utf8_lossy(output.stdout.as_slice()),
+                String::from_utf8_lossy(output.stderr.as_slice())
+            )),
+            Ok(_) => {},
+            Err(err) => failures.push(format!("Failed to run partprobe: {}", err)),
+        }
+    }
+    // Not every system has udev, waiting for the links to show up has to do then
+    let _ = Command::new("udevadm").arg("settle").output();
+
+    failures
+}
+
+/// Whether `program` is an executable file in one of the directories in PATH
+pub(crate) fn find_program(program: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|path| std::env::split_paths(&path).any(|dir| {
+        dir.join(program).metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+    }))
+}
+
+/// Checks that mkfs is installed for all filesystems to create that need it, before anything
+/// is written
+fn check_mkfs_tools(partitions_to_format: &[FormatPartitionDefinition]) -> Result<(), FlashError> {
+    let mut missing: Vec<String> = partitions_to_format.iter()
+        .filter(|partition_to_format| !fat::is_builtin_format(partition_to_format))
+        .map(|partition_to_format| format!("mkfs.{}", partition_to_format.format_as))
+        .filter(|program| !find_program(program))
+        .collect();
+    missing.sort();
+    missing.dedup();
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(FlashError::MissingTools { tools: missing }),
+    }
+}
+
+fn format_partitions(
+    destination: PathBuf,
+    partitions_to_format: Vec<FormatPartitionDefinition>,
+    table_type: PartitionTableType,
+    mbr_entries: &[MbrEntry],
+    lba: LogicalBlockSize,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError>  {
+    if partitions_to_format.is_empty() {
+        return Ok(())
+    }
+    // FAT is created right in the destination, everything else needs a device for the partition
+    let needs_device = |partition_to_format: &FormatPartitionDefinition| {
+        !fat::is_builtin_format(partition_to_format) || partition_to_format.populate_from.is_some()
+    };
+    let needs_devices = partitions_to_format.iter().any(needs_device);
+    if needs_devices && !cfg!(target_os = "linux") {
+        return Err(FlashError::Unsupported(format!(
+            "Creating filesystems other than FAT is unsupported on {}", std::env::consts::OS
+        )));
+    }
+
+    if needs_devices {
+        probe_partitions(&destination, on_event);
+    }
+
+    // Only a GPT has partition names and GUIDs. A DOS partition table that has just been written
+    // is looked up by the number of each partition, which udev combines with the disk signature.
+    let located_partitions = match table_type {
+        PartitionTableType::Gpt => {
+            let cfg = gpt::GptConfig::new()
+                .initialized(true)
+                .writable(false)
+                .logical_block_size(lba);
+
+            let disk = cfg.open(destination.clone())
+                .map_err(|source| FlashError::GptRead { path: destination.clone(), source })?;
+
+            partitions_to_format.into_iter()
+                .map(|partition_to_format| {
+                    let (_, gpt_part) = disk.partitions().iter().find(
+                        |(_, part)| part.name == partition_to_format.partition_name
+                    ).ok_or_else(|| FlashError::PartitionNotFound {
+                        name: partition_to_format.partition_name.clone()
+                    })?;
+                    Ok((
+                        partition_to_format,
+                        gpt_part.part_guid.to_string(),
+                        gpt_part.first_lba,
+                        gpt_part.last_lba,
+                    ))
+                })
+                .collect::<Result<Vec<_>, FlashError>>()?
+        },
+        PartitionTableType::Mbr => {
+            let disk_signature = File::open(&destination)
+                .and_then(|mut file| gpt::mbr::read_disk_signature(&mut file))
+                .map_err(|source| FlashError::GptRead { path: destination.clone(), source })?;
+            let disk_signature = u32::from_le_bytes(disk_signature);
+
+            partitions_to_format.into_iter()
+                .map(|partition_to_format| {
+                    let name = &partition_to_format.partition_name;
+                    let entry = mbr_entries.iter()
+                        .find(|entry| entry.name.as_ref() == Some(name))
+                        .ok_or_else(|| FlashError::PartitionNotFound { name: name.clone() })?;
+                    let part_uuid = format!("{:08x}-{:02x}", disk_signature, entry.index);
+                    Ok((partition_to_format, part_uuid, entry.first_lba, entry.last_lba))
+                })
+                .collect::<Result<Vec<_>, FlashError>>()?
+        },
+    };
+
+    // The links for the partitions can take a while to appear on some kernels
+    let devices: Vec<PathBuf> = located_partitions.iter()
+        .filter(|(partition_to_format, ..)| needs_device(partition_to_format))
+        .map(|(_, part_uuid, ..)| PathBuf::from(format!("/dev/disk/by-partuuid/{}", part_uuid)))
+        .collect();
+    if !devices.is_empty() {
+        wait_for_devices(&destination, &devices, 20, Duration::from_millis(500), on_event)?;
+    }
+
+    for (partition_to_format, part_uuid, first_lba, last_lba) in located_partitions {
+        let name = partition_to_format.partition_name.clone();
+        on_event(FlashEvent::Formatting {
+            name: name.clone(),
+            filesystem: partition_to_format.format_as.clone(),
+            part_uuid: part_uuid.clone(),
+        });
+        let device = format!("/dev/disk/by-partuuid/{}", part_uuid);
+        if fat::is_builtin_format(&partition_to_format) {
+            let label = default_label_args(&name, &partition_to_format.format_as, &[], on_event)
+                .and_then(|mut label_args| label_args.pop())
+                .unwrap_or_default();
+            let lba_size = u64::from(lba);
+            log::debug!(
+                "Creating a FAT filesystem labeled {:?} on LBAs {} to {} of {}",
+                label, first_lba, last_lba, destination.to_string_lossy()
+            );
+            fat::format_fat(
+                &destination,
+                first_lba * lba_size,
+                (last_lba + 1 - first_lba) * lba_size,
+                lba_size,
+                &label,
+            ).map_err(|e| FlashError::MkfsFailed {
+                partition: name.clone(),
+                filesystem: partition_to_format.format_as.clone(),
+                message: e.to_string(),
+            })?;
+        } else {
+            run_mkfs_for(&name, &device, &part_uuid, &partition_to_format, on_event)?;
+        }
+        on_event(FlashEvent::PartitionFormatted {
+            name: name.clone(),
+            filesystem: partition_to_format.format_as.clone(),
+        });
+
+        if let Some(source) = &partition_to_format.populate_from {
+            on_event(FlashEvent::Populating { name: name.clone(), source: source.clone() });
+            populate_partition(Path::new(&device), source).map_err(
+                |message| FlashError::PopulateFailed { partition: name.clone(), message }
+            )?;
+            on_event(FlashEvent::PartitionPopulated { name });
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the filesystem of `partition_to_format` on `device` using mkfs
+fn run_mkfs_for(
+    name: &str,
+    device: &str,
+    part_uuid: &str,
+    partition_to_format: &FormatPartitionDefinition,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let mut mkfs_args = partition_to_format.mkfs_args.clone();
+    if let Some(label_args) = default_label_args(
+        name, &partition_to_format.format_as, &mkfs_args, on_event
+    ) {
+        mkfs_args.splice(0..0, label_args);
+    }
+    let output = run_mkfs(device.into(), partition_to_format.format_as.clone(), &mkfs_args)
+        .map_err(|e| FlashError::MkfsFailed {
+            partition: name.into(),
+            filesystem: partition_to_format.format_as.clone(),
+            message: e.to_string(),
+        })?;
+    if !output.status.success() {
+        on_event(FlashEvent::MkfsOutput {
+            filesystem: partition_to_format.format_as.clone(),
+            status: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(output.stdout.as_slice()).into(),
+            stderr: String::from_utf8_lossy(output.stderr.as_slice()).into(),
+        });
+        return Err(FlashError::MkfsFailed {
+            partition: name.into(),
+            filesystem: partition_to_format.format_as.clone(),
+            message: format!(
+                "exited with status code {} (PARTUUID={})",
+                output.status.code().unwrap_or(-1),
+                part_uuid
+            ),
+        })
+    }
+
+    Ok(())
+}
+
+/// Flushes everything written to the destination down to the device. Block devices also get
+/// their buffer cache flushed, so nothing is left that the device hasn't acknowledged.
+///
+/// Unlike flushing a partition, this can be retried: all partitions have been flushed
+/// successfully before, so there is no data the kernel could have dropped, only the write
+/// cache of the device is flushed again.
+pub(crate) fn flush_destination(
+    destination: &Path,
+    retries: u32,
+    retry_delay: Duration,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let is_block_device = is_block_device(destination).unwrap_or(false);
+    let flush = || -> io::Result<()> {
+        let file = open_write(destination.into(), false)?;
+        file.sync_all()?;
+        if is_block_device && unsafe { libc::ioctl(file.as_raw_fd(), BLKFLSBUF as _) } != 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    };
+
+    on_event(FlashEvent::FlushingDestination { path: destination.into() });
+    let mut retries_left = retries;
+    let mut delay = retry_delay;
+    let mut result = flush();
+    while let Err(err) = result {
+        if retries_left == 0 || !is_transient_error(&err) {
+            return Err(FlashError::DeviceAccess { path: destination.into(), source: err })
+        }
+        retries_left -= 1;
+        on_event(FlashEvent::RetryingFlush {
+            path: destination.into(),
+            retries_left,
+            message: err.to_string(),
+        });
+
+        sleep(delay);
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+        result = flush();
+    }
+
+    Ok(())
+}
+
+/// Powers off the destination, so it can be removed safely. udisks takes care of unmounting
+/// and powering off the whole USB device, without it the device gets a SCSI START STOP UNIT
+/// through eject.
+pub(crate) fn eject_destination(
+    destination: &Path,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    on_event(FlashEvent::Ejecting { path: destination.into() });
+
+    let eject_error = |message| FlashError::EjectFailed { path: destination.into(), message };
+    let output = match Command::new("udisksctl")
+        .arg("power-off").arg("--block-device").arg(destination)
+        .output() {
+        Ok(output) if output.status.success() => output,
+        _ => Command::new("eject").arg(destination).output()
+            .map_err(|err| eject_error(format!("Failed to run eject: {}", err)))?,
+    };
+    if !output.status.success() {
+        return Err(eject_error(String::from_utf8_lossy(&output.stderr).trim().into()))
+    }
+
+    on_event(FlashEvent::Ejected { path: destination.into() });
+    Ok(())
+}
+
+/// Waits until all `devices` exist, rereading the partition table of `destination` between
+/// attempts, and fails with the ones that never showed up
+fn wait_for_devices(
+    destination: &Path,
+    devices: &[PathBuf],
+    retries: u32,
+    retry_interval: Duration,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    let mut tried = 0;
+    loop {
+        let missing: Vec<PathBuf> = devices.iter()
+            .filter(|device| !is_device_ready(device))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            return Ok(())
+        }
+        if retries == tried {
+            return Err(FlashError::DeviceTimeout { paths: missing, retries: tried })
+        }
+        if tried == 0 {
+            for device in &missing {
+                on_event(FlashEvent::WaitingForDevice { path: device.clone() });
+            }
+        }
+        tried += 1;
+        sleep(retry_interval);
+        // Failures have been reported by the first attempt already
+        reread_partitions(destination);
+    }
+}
+
+/// Whether `device` can be opened. It's usually a link udev created, the device node it points
+/// to may show up after the link, or udev may create the node itself instead of a link.
+fn is_device_ready(device: &Path) -> bool {
+    let Ok(node) = device.canonicalize() else {
+        return false
+    };
+    (node.is_file() || is_block_device(&node).unwrap_or(false)) && File::open(&node).is_ok()
+}
+
+/// Returns the mkfs arguments that label the filesystem like its partition, so it shows up
+/// in /dev/disk/by-label. Nothing is returned if the filesystem has no labels or `mkfs_args`
+/// already set one.
+fn default_label_args(
+    partition_name: &str,
+    filesystem: &str,
+    mkfs_args: &[String],
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Option<Vec<String>> {
+    // Options that set the label and the maximum label length, if it is shorter than
+    // a partition name can be
+    let (label_options, max_len): (&[&str], _) = match filesystem {
+        "ext2" | "ext3" | "ext4" => (&["-L"], None),
+        "f2fs" => (&["-l"], None),
+        "vfat" | "fat" | "msdos" => (&["-n"], Some(11)),
+        // exfatprogs uses -L, the older exfat-utils used -n
+        "exfat" => (&["-L", "-n"], Some(11)),
+        _ => return None,
+    };
+    let has_label = mkfs_args.iter()
+        .any(|arg| label_options.iter().any(|option| arg.starts_with(option)));
+    if has_label {
+        return None
+    }
+
+    let mut label = partition_name.to_string();
+    if let Some(max_len) = max_len.filter(|max_len| label.len() > *max_len) {
+        let mut len = max_len;
+        while !label.is_char_boundary(len) {
+            len -= 1;
+        }
+        label.truncate(len);
+        on_event(FlashEvent::LabelTruncated {
+            name: partition_name.into(),
+            filesystem: filesystem.into(),
+            label: label.clone(),
+        });
+    }
+
+    Some(vec![label_options[0].into(), label])
+}
+
+/// Runs a command that only reports failure through its exit status and stderr
+fn run_command(command: &mut Command) -> Result<(), String> {
+    log::debug!("Running {:?}", command);
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command.output()
+        .map_err(|err| format!("Failed to run {}: {}", program, err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} exited with status code {}: {}",
+            program, output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+
+    Ok(())
+}
+
+/// A filesystem mounted on a temporary directory, which is unmounted and removed again
+/// when it goes out of scope, also if populating it fails
+struct TempMount {
+    mount_point: PathBuf,
+    mounted: bool,
+}
+
+impl TempMount {
+    fn mount(device: &Path) -> Result<Self, String> {
+        let name = device.file_name().unwrap_or_default().to_string_lossy();
+        let mount_point = std::env::temp_dir()
+            .join(format!("rockflasher-{}-{}", std::process::id(), name));
+        std::fs::create_dir(&mount_point).map_err(|err| format!(
+            "Could not create mount point {}: {}", mount_point.to_string_lossy(), err
+        ))?;
+
+        let mut temp_mount = TempMount { mount_point, mounted: false };
+        run_command(Command::new("mount").arg(device).arg(&temp_mount.mount_point))?;
+        temp_mount.mounted = true;
+        Ok(temp_mount)
+    }
+
+    /// Unmounting writes everything that has been copied back to the device
+    fn unmount(mut self) -> Result<(), String> {
+        self.mounted = false;
+        run_command(Command::new("umount").arg(&self.mount_point))
+    }
+}
+
+impl Drop for TempMount {
+    fn drop(&mut self) {
+        if self.mounted {
+            let _ = Command::new("umount").arg(&self.mount_point).output();
+        }
+        let _ = std::fs::remove_dir(&self.mount_point);
+    }
+}
+
+/// Copies the contents of `source` onto the filesystem on `device`, keeping ownership,
+/// permissions and links
+fn populate_partition(device: &Path, source: &Path) -> Result<(), String> {
+    if !source.is_dir() {
+        return Err(format!("{} is not a directory", source.to_string_lossy()))
+    }
+
+    let temp_mount = TempMount::mount(device)?;
+    run_command(Command::new("cp").arg("-a").arg("--")
+        .arg(source.join(".")).arg(&temp_mount.mount_point))?;
+    temp_mount.unmount()
+}
+
+fn run_mkfs(device: String, fs: String, args: &[String]) -> io::Result<Output> {
+    let mut command = Command::new(format!("mkfs.{}", fs));
+    command.args(args).arg(device);
+    log::debug!("Running {:?}", command);
+    command.output()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempfile::TempFile;
+
+    #[test]
+    fn backup_gpt_is_written_to_the_last_lba_of_an_image_file() {
+        // Not a multiple of the partition alignment, so the end of the image is unused
+        const SIZE: u64 = 64 * 1024 * 1024 + 7 * 512;
+
+        let image = TempFile::new("test-backup-gpt").unwrap();
+        let mut plan = FlashPlan::new(image.path().into());
+        plan.size = SIZE;
+        plan.partitions = vec![PartitionDefinition::empty("cache".into(), 4 * 1024 * 1024)];
+        flash(plan, |_| {}).unwrap();
+
+        let file = File::open(image.path()).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), SIZE);
+        let mut signature = [0_u8; 8];
+        file.read_exact_at(&mut signature, SIZE - 512).unwrap();
+        assert_eq!(&signature, b"EFI PART");
+        table::validate_partition_table(image.path(), LogicalBlockSize::Lb512).unwrap();
+    }
+
+    #[test]
+    fn sources_larger_than_their_partition_are_rejected() {
+        const MIB: u64 = 1024 * 1024;
+
+        let source = TempFile::new("test-oversized-source").unwrap();
+        std::fs::write(source.path(), vec![0x5a_u8; 3 * MIB as usize]).unwrap();
+        let result =
+            PartitionDefinition::from_source("boot".into(), source.path().into(), Some(MIB));
+        assert!(matches!(result, Err(FlashError::ImageTooLarge { .. })));
+
+        // A source that has grown since its size was read is only noticed while writing it
+        let mut def = PartitionDefinition::from_source("boot".into(), source.path().into(), None)
+            .unwrap();
+        def.size = MIB;
+        let image = TempFile::new("test-oversized-image").unwrap();
+        let mut plan = FlashPlan::new(image.path().into());
+        plan.size = 64 * MIB;
+        plan.partitions = vec![def];
+        match flash(plan, |_| {}) {
+            Err(FlashError::ImageTooLarge { partition, available, .. }) => {
+                assert_eq!(partition, "boot");
+                assert_eq!(available, MIB);
+            },
+            result => panic!("Expected ImageTooLarge, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn verification_reports_the_first_differing_byte() {
+        let source = TempFile::new("test-verify-source").unwrap();
+        let mut data = vec![0x5a_u8; 3 * 1024 * 1024];
+        std::fs::write(source.path(), &data).unwrap();
+
+        // The image is written 4 KiB into the destination, which is corrupted in its
+        // second chunk
+        data[1024 * 1024 + 17] = 0xa5;
+        let destination = TempFile::new("test-verify-destination").unwrap();
+        let mut contents = vec![0_u8; 4096];
+        contents.extend_from_slice(&data);
+        std::fs::write(destination.path(), contents).unwrap();
+
+        let def = PartitionDefinition::from_source("boot".into(), source.path().into(), None)
+            .unwrap();
+        let image = WrittenImage {
+            name: "boot".into(),
+            offset: 4096,
+            bytes_written: data.len() as u64,
+            checksum: Checksum::Sha256(vec![0; 32]),
+            source: None,
+        };
+        let source = ImageSource { def, path: source.path().into(), patched_flags: 0 };
+        let file = File::open(destination.path()).unwrap();
+        match first_difference(&file, &image, &source).unwrap() {
+            Some(Mismatch::Byte { offset, expected, actual }) => {
+                assert_eq!(offset, 1024 * 1024 + 17);
+                assert_eq!((expected, actual), (0x5a, 0xa5));
+            },
+            mismatch => panic!("Expected a differing byte, got {:?}", mismatch),
+        }
+        assert_eq!(first_differing_byte(b"abc", b"abc"), None);
+        assert_eq!(first_differing_byte(b"abc", b"abd"), Some(2));
+    }
+
+    #[test]
+    fn userdata_is_laid_out_after_uboot() {
+        const MIB: u64 = 1024 * 1024;
+
+        let uboot = TempFile::new("test-raw-uboot").unwrap();
+        std::fs::write(uboot.path(), vec![0x5a_u8; 4096]).unwrap();
+        let image = TempFile::new("test-raw-uboot-image").unwrap();
+        let mut plan = FlashPlan::new(image.path().into());
+        plan.size = 64 * MIB;
+        plan.raw_images = vec![RawImage {
+            name: "uboot".into(),
+            source_file: uboot.path().into(),
+            offset: DEFAULT_UBOOT_OFFSET,
+        }];
+        let summary = flash(plan, |_| {}).unwrap();
+
+        let userdata = summary.partitions.iter()
+            .find(|partition| partition.name == "userdata")
+            .unwrap();
+        assert!(userdata.first_lba * 512 >= DEFAULT_UBOOT_OFFSET + 4096);
+        let mut written = vec![0_u8; 4096];
+        let file = File::open(image.path()).unwrap();
+        file.read_exact_at(&mut written, DEFAULT_UBOOT_OFFSET).unwrap();
+        assert_eq!(written, vec![0x5a_u8; 4096]);
+    }
+
+    #[test]
+    fn devices_behind_symlinks_are_ready_once_their_target_exists() {
+        let target = TempFile::new("test-device-target").unwrap();
+        let link = TempFile::new("test-device-link").unwrap();
+        // Only their names are needed, the target appears later
+        std::fs::remove_file(target.path()).unwrap();
+        std::fs::remove_file(link.path()).unwrap();
+        std::os::unix::fs::symlink(target.path(), link.path()).unwrap();
+        assert!(!is_device_ready(link.path()));
+
+        std::fs::write(target.path(), [0_u8; 512]).unwrap();
+        assert!(is_device_ready(link.path()));
+        let links = [link.path().to_path_buf()];
+        wait_for_devices(target.path(), &links, 0, Duration::ZERO, &mut |_| {}).unwrap();
+    }
+}
@@ -1,709 +1,2356 @@
 use std::collections::BTreeMap;
-use std::fs::{File, metadata, OpenOptions};
+use std::env::temp_dir;
+use std::fs::write;
 use std::io;
-use std::io::{copy, Seek, SeekFrom, Write};
-use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
-use std::thread::sleep;
-use std::time::Duration;
-use block_utils::{BlockResult, get_device_info, is_block_device};
-use clap::Parser;
-use gpt::disk::LogicalBlockSize;
-use gpt::partition::Partition;
-use gpt::partition_types;
-use parse_size::parse_size;
-use sizes::BinarySize;
-use spinner::SpinnerBuilder;
-use crate::alignment::align_up;
-
-pub mod alignment;
-
-const LBA: LogicalBlockSize = LogicalBlockSize::Lb512;
-
-const LBA_SIZE: u64 = match LBA {
-    LogicalBlockSize::Lb512 => 512,
-    LogicalBlockSize::Lb4096 => 4096
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use block_utils::get_device_info;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::{generate, Shell};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{Level, LevelFilter};
+use serde::Serialize;
+use rockflasher::{
+    FillPartition, FlashError, FlashEvent, FlashPlan, FormatPartitionDefinition, Mismatch,
+    PartitionDefinition, PartitionTableType, RawImage, Slot, WipeMode
 };
+use rockflasher::avb::{FLAG_HASHTREE_DISABLED, FLAG_VERIFICATION_DISABLED, is_vbmeta_partition};
+use rockflasher::checksum::Checksum;
+use rockflasher::idbloader::find_soc;
+use rockflasher::layout::{Layout, read_layout};
+use rockflasher::manifest::Manifest;
+use rockflasher::mbr::{HybridMbrPartition, MbrEntry, parse_hybrid_mbr_partition, read_mbr};
+use rockflasher::partition::{
+    AB_PARTITIONS, format_partition_flags, parse_partition_flags, parse_partition_guid,
+    parse_partition_type, partition_name_to_type, partition_type_name, read_type_map,
+    split_mkfs_args
+};
+use rockflasher::offset::flash_at_offset;
+use rockflasher::rockusb::{ROCKUSB_DESTINATION, flash_rockusb};
+use rockflasher::sizes::{BinarySize, DecimalSize, ParsedSize};
+use rockflasher::spi::{DEFAULT_SPI_SIZE, DEFAULT_SPI_UBOOT_OFFSET, SpiPlan, spi_idbloader};
+use rockflasher::table::{
+    DiskInfo, FreeRegion, PartitionInfo, read_disk_info, read_partition_table
+};
+use rockflasher::tempfile::TempFile;
+
+/// Flashes partitions, images and Rockchip loaders to eMMC modules, SD cards, SPI flash and
+/// image files
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand, the arguments are the ones of flash
+    #[command(flatten)]
+    flash: FlashArgs,
+}
+
+/// Arguments of the commands that print messages, they follow the subcommand
+#[derive(clap::Args, Debug, Clone, Copy, Default)]
+struct Verbosity {
+    /// Print more details, -v adds debug messages like the LBAs of every partition, each
+    /// write and the mkfs commands, -vv also those of the libraries
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write partitions, images and loaders to a disk or image file (the default)
+    Flash(Box<FlashArgs>),
+    /// Create filesystems in partitions of the existing partition table
+    Format(FormatArgs),
+    /// Print the partition table of the destination with partition types, attributes, the
+    /// disk GUID and the free space
+    Info(InfoArgs),
+    /// Print the partition table of the destination
+    List(ListArgs),
+    /// Remove partitions from the existing partition table, leaving all other partitions
+    /// untouched
+    Delete(DeleteArgs),
+    /// Move the end of a partition of the existing partition table
+    Resize(ResizeArgs),
+    /// Check the destination against a manifest written with --manifest
+    Verify(VerifyArgs),
+    /// Print the completion script for a shell, e.g. rockflasher completions bash >
+    /// /etc/bash_completion.d/rockflasher
+    Completions(CompletionsArgs),
+    /// Print candidates for the completion scripts
+    #[command(hide = true)]
+    Complete(CompleteArgs),
+}
 
-const PART_ALIGNMENT: u64 = 1 * 1024 * 1024;
-const FIRST_PART_ALIGNMENT: u64 = 8 * 1024 * 1024;
+#[derive(clap::Args, Debug)]
+struct CompletionsArgs {
+    /// Shell to print the completion script for, block devices and the partitions of the
+    /// layout file are completed in bash and zsh
+    #[arg(value_enum)]
+    shell: Shell,
+}
 
-// https://opensource.rock-chips.com/wiki_Boot_option#The_Pre-bootloader.28IDBLoader.29
-const IDBLOADER_ALIGNMENT_LBA: u64 = 0x40;
-const IDBLOADER_ALIGNMENT: u64 = 0x40 * LBA_SIZE;
+#[derive(clap::Args, Debug)]
+struct CompleteArgs {
+    #[arg(value_enum)]
+    candidates: Candidates,
 
-const IDBLOADER_PARTNAME: &'static str = "idbloader";
+    /// Layout file to take the partition names from
+    #[arg(long)]
+    layout: Option<PathBuf>,
+}
 
-/// Simple program to greet a person
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
+/// What the completion scripts ask for
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Candidates {
+    /// Block devices that can be a destination
+    Devices,
+    /// Names of the partitions in the layout file, for --format-partition and --populate
+    Partitions,
+}
+
+#[derive(clap::Args, Debug)]
+struct FormatArgs {
+    /// Partition to format (name:fs[:mkfs options], can be repeated)
+    #[arg(value_name = "NAME:FS[:OPTIONS]", required = true)]
+    partition: Vec<String>,
+
+    /// Disk or image file with the partitions
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    destination: PathBuf,
+
+    /// Copy the contents of a directory onto a formatted partition (name:dir, can be repeated)
+    #[arg(long, value_name = "NAME:DIR")]
+    populate: Vec<String>,
+
+    /// Don't ask for confirmation before overwriting a block device
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Logical block size of the partition table (512 or 4096), detected for block devices
+    #[arg(long, visible_alias = "sector-size")]
+    lba_size: Option<u64>,
+
+    /// Print events as JSON lines on stdout instead of progress for humans
+    #[arg(long)]
+    json: bool,
+
+    /// Units for the sizes that are printed, binary (MiB, GiB) or decimal (MB, GB)
+    #[arg(long, value_enum, default_value_t = SizeUnits::Iec)]
+    size_units: SizeUnits,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    /// Disk or image file to read the partition table of
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    destination: PathBuf,
+
+    /// Logical block size of the partition table (512 or 4096), detected for block devices
+    #[arg(long, visible_alias = "sector-size")]
+    lba_size: Option<u64>,
+
+    /// Print the partition table as JSON on stdout
+    #[arg(long)]
+    json: bool,
+
+    /// Units for the sizes that are printed, binary (MiB, GiB) or decimal (MB, GB)
+    #[arg(long, value_enum, default_value_t = SizeUnits::Iec)]
+    size_units: SizeUnits,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    /// Disk or image file to read the partition table of
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    destination: PathBuf,
+
+    /// Type of partition table to read
+    #[arg(long, value_enum, default_value_t = TableType::Gpt)]
+    table: TableType,
+
+    /// Logical block size of the partition table (512 or 4096), detected for block devices
+    #[arg(long, visible_alias = "sector-size")]
+    lba_size: Option<u64>,
+
+    /// Print the partition table as JSON on stdout
+    #[arg(long)]
+    json: bool,
+
+    /// Units for the sizes that are printed, binary (MiB, GiB) or decimal (MB, GB)
+    #[arg(long, value_enum, default_value_t = SizeUnits::Iec)]
+    size_units: SizeUnits,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(clap::Args, Debug)]
+struct DeleteArgs {
+    /// Partition to remove (can be repeated)
+    #[arg(value_name = "NAME", required = true)]
+    partition: Vec<String>,
+
+    /// Disk or image file with the partitions
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    destination: PathBuf,
+
+    /// Also clear the data of the deleted partitions
+    #[arg(long)]
+    wipe_contents: bool,
+
+    /// Don't ask for confirmation before overwriting a block device
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Logical block size of the partition table (512 or 4096), detected for block devices
+    #[arg(long, visible_alias = "sector-size")]
+    lba_size: Option<u64>,
+
+    /// Print events as JSON lines on stdout instead of progress for humans
+    #[arg(long)]
+    json: bool,
+
+    /// Units for the sizes that are printed, binary (MiB, GiB) or decimal (MB, GB)
+    #[arg(long, value_enum, default_value_t = SizeUnits::Iec)]
+    size_units: SizeUnits,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(clap::Args, Debug)]
+struct ResizeArgs {
+    /// Partition to resize (name[:size]). Without a size it grows into the free space after
+    /// it, shrinking needs --force.
+    #[arg(value_name = "NAME[:SIZE]")]
+    partition: String,
+
+    /// Disk or image file with the partitions
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    destination: PathBuf,
+
+    /// Create a filesystem in the resized partition afterwards (name:fs[:mkfs options])
+    #[arg(short, long)]
+    format_partition: Vec<String>,
+
+    /// Shrink the partition even though that can cut off its filesystem
+    #[arg(long)]
+    force: bool,
+
+    /// Don't ask for confirmation before overwriting a block device
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Logical block size of the partition table (512 or 4096), detected for block devices
+    #[arg(long, visible_alias = "sector-size")]
+    lba_size: Option<u64>,
+
+    /// Print events as JSON lines on stdout instead of progress for humans
+    #[arg(long)]
+    json: bool,
+
+    /// Units for the sizes that are printed, binary (MiB, GiB) or decimal (MB, GB)
+    #[arg(long, value_enum, default_value_t = SizeUnits::Iec)]
+    size_units: SizeUnits,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Manifest written with --manifest
+    #[arg(value_name = "MANIFEST", value_hint = ValueHint::FilePath)]
+    manifest: PathBuf,
+
+    /// Disk or image file to check
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    destination: PathBuf,
+
+    /// Print events as JSON lines on stdout instead of progress for humans
+    #[arg(long)]
+    json: bool,
+
+    /// Units for the sizes that are printed, binary (MiB, GiB) or decimal (MB, GB)
+    #[arg(long, value_enum, default_value_t = SizeUnits::Iec)]
+    size_units: SizeUnits,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(clap::Args, Debug)]
+struct FlashArgs {
     /// Add a partition to the disk
+    /// (name:file[:size][:type=type][:sha256=digest][:uuid=guid][:flags=flags][:start=offset]
+    /// [:align=size]). A file of - reads the image from stdin and needs the size.
     #[arg(short, long)]
     partition: Vec<String>,
 
     /// Add empty partition to the disk
+    /// (name:size[:type=type][:uuid=guid][:flags=flags][:start=offset][:align=size])
     #[arg(short, long)]
     blank_partition: Vec<String>,
 
-    /// Disk or image file to write to
-    #[arg(short, long)]
-    destination: PathBuf,
+    /// Disk or image file to write to, required unless the layout file names one. rockusb:
+    /// writes the flash of a board in loader or maskrom mode on USB using rkdeveloptool.
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    destination: Option<PathBuf>,
+
+    /// Download this loader (e.g. rk3399_loader_v1.30.130.bin) to a board in maskrom mode
+    /// before writing its flash over rockusb
+    #[arg(long, value_name = "LOADER")]
+    rockusb_loader: Option<PathBuf>,
 
-    /// Format partition (use in combination with --blank-partition)
+    /// Format partition (name:fs[:mkfs options], use in combination with --blank-partition)
     #[arg(short, long)]
     format_partition: Vec<String>,
 
+    /// Copy the contents of a directory onto a formatted partition (name:dir, can be repeated)
+    #[arg(long, value_name = "NAME:DIR")]
+    populate: Vec<String>,
+
     /// Image file size (only if destination is not a device)
-    #[arg(short, long, default_value="0")]
-    size: String,
+    #[arg(short, long)]
+    size: Option<String>,
+
+    /// Lay out the destination as if it started this many bytes in, leaving everything before
+    /// untouched, e.g. for a disk image inside a larger container. --size is the size of this
+    /// region then.
+    #[arg(
+        long, value_name = "SIZE",
+        conflicts_with_all = [
+            "keep", "append", "spi", "list", "info", "verify_manifest", "delete", "resize"
+        ]
+    )]
+    offset: Option<String>,
 
     /// Path to IDBloader
     #[arg(short, long)]
-    idbloader: Option<PathBuf>
+    idbloader: Option<PathBuf>,
+
+    /// Write the IDBloader to sector 0x40 without a partition table entry for it
+    #[arg(long)]
+    idbloader_raw: bool,
+
+    /// Write the IDBloader even if it doesn't start with a Rockchip boot header
+    #[arg(long)]
+    skip_idbloader_check: bool,
+
+    /// Build the IDBloader from this TPL (e.g. tpl/u-boot-tpl.bin) and the SPL given by --spl
+    #[arg(long, requires = "spl")]
+    tpl: Option<PathBuf>,
+
+    /// Build the IDBloader from this SPL (e.g. spl/u-boot-spl.bin) instead of using --idbloader
+    #[arg(long, requires = "soc", conflicts_with = "idbloader")]
+    spl: Option<PathBuf>,
+
+    /// Write SPI NOR flash (an image file or MTD device like /dev/mtd0) instead: the IDBloader
+    /// at offset 0 and --uboot at 0x60000, without a partition table
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "partition", "blank_partition", "format_partition", "trust", "keep", "append",
+            "list", "info", "verify_manifest", "delete", "resize"
+        ]
+    )]
+    spi: bool,
+
+    /// SoC to build the IDBloader for, e.g. rk3399 or rk3588
+    #[arg(long, requires = "spl")]
+    soc: Option<String>,
+
+    /// Write U-Boot (e.g. uboot.img) to sector 0x4000 without a partition table entry
+    #[arg(long)]
+    uboot: Option<PathBuf>,
+
+    /// Offset to write U-Boot to instead of 0x800000 (0x60000 with --spi)
+    #[arg(long, value_name = "OFFSET", requires = "uboot")]
+    uboot_offset: Option<String>,
+
+    /// Write the trust image (e.g. trust.img) to sector 0x6000 without a partition table entry
+    #[arg(long)]
+    trust: Option<PathBuf>,
+
+    /// Offset to write the trust image to instead of 0xc00000
+    #[arg(long, value_name = "OFFSET", requires = "trust")]
+    trust_offset: Option<String>,
+
+    /// Don't ask for confirmation before overwriting a block device
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Power off the destination when done, so it can be removed right away
+    #[arg(long)]
+    eject: bool,
+
+    /// Flash even if the destination is mounted or holds the running system
+    #[arg(long, visible_alias = "i-know-what-im-doing")]
+    force: bool,
+
+    /// Leave the space after the last partition free instead of creating a userdata partition
+    #[arg(long, visible_aliases = ["no-fill", "no-auto-userdata"])]
+    no_userdata: bool,
+
+    /// Grow this partition into the space after it instead of creating a userdata partition
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["no_userdata", "fill_partition"])]
+    auto_grow: Option<String>,
+
+    /// Leave this much space at the end of the disk unpartitioned, e.g. for over-provisioning
+    /// or vendor data
+    #[arg(long, value_name = "SIZE", visible_alias = "end-reserve")]
+    reserve: Option<String>,
+
+    /// Partition to create in the space after the last partition instead of userdata
+    /// (name[:type[:max size]]), space beyond the max size is left free
+    #[arg(long, value_name = "NAME[:TYPE[:MAXSIZE]]", conflicts_with = "no_userdata")]
+    fill_partition: Option<String>,
+
+    /// Read back all written images and compare them to their source
+    #[arg(long)]
+    verify: bool,
+
+    /// Read the disk layout from a TOML file (other arguments override or append to it)
+    #[arg(short, long, visible_alias = "config")]
+    layout: Option<PathBuf>,
+
+    /// Read partition types by partition name from a TOML file, e.g. rootfs = "linux",
+    /// for partitions without an explicit type
+    #[arg(long, value_name = "FILE")]
+    type_map: Option<PathBuf>,
+
+    /// Create _a and _b slots of the A/B partitions of Android (boot, vendor_boot, dtbo,
+    /// vbmeta, super, …) with the same image in both, like slots=ab does for one partition
+    #[arg(long)]
+    ab: bool,
+
+    /// Mark this slot of A/B partitions as active and the other one as fallback using the
+    /// priority and successful flags, unless flags= is given
+    #[arg(long, value_enum)]
+    active_slot: Option<ActiveSlot>,
+
+    /// Disable dm-verity in the vbmeta image while writing it, the file stays as it is
+    #[arg(long)]
+    disable_verity: bool,
+
+    /// Disable verification in the vbmeta image while writing it, the file stays as it is
+    #[arg(long)]
+    disable_verification: bool,
+
+    /// Size of the chunks images are written in
+    #[arg(long)]
+    block_size: Option<String>,
+
+    /// Number of chunks of --block-size that are read ahead while writing
+    #[arg(long, default_value_t = rockflasher::DEFAULT_BUFFER_COUNT)]
+    buffers: usize,
+
+    /// Logical block size of the partition table (512 or 4096), detected for block devices
+    #[arg(long, visible_alias = "sector-size")]
+    lba_size: Option<u64>,
+
+    /// Bypass the page cache when writing images (O_DIRECT)
+    #[arg(long)]
+    direct: bool,
+
+    /// How often failed writes are retried per partition (I/O errors, device resets)
+    #[arg(
+        long, visible_alias = "write-retries",
+        default_value_t = rockflasher::DEFAULT_WRITE_RETRIES
+    )]
+    retries: u32,
+
+    /// Time to wait before retrying a failed write, doubled for every further retry
+    #[arg(long)]
+    retry_delay: Option<String>,
+
+    /// Limit the average write rate per second, e.g. 20MiB (0 means unlimited)
+    #[arg(long)]
+    max_write_rate: Option<String>,
+
+    /// Expected checksum of a partition's source file (name=sha256:digest)
+    #[arg(long)]
+    checksum: Vec<String>,
+
+    /// Derive the disk and partition GUIDs from this string for reproducible images
+    #[arg(long)]
+    guid_seed: Option<String>,
+
+    /// Write all blocks of the images, even those that are all zeros
+    #[arg(long)]
+    no_sparse_copy: bool,
+
+    /// Don't clear what follows the image in each partition, e.g. for partitions that are
+    /// formatted afterwards or for images that fill their partition
+    #[arg(long)]
+    no_clear_tail: bool,
+
+    /// Leave this partition of the existing partition table untouched (can be repeated)
+    #[arg(long)]
+    keep: Vec<String>,
+
+    /// Add the partitions to the existing partition table in its free space, leaving all
+    /// other partitions and the beginning of the disk untouched
+    #[arg(long, conflicts_with_all = ["keep", "hybrid_mbr", "bootable_pmbr"])]
+    append: bool,
+
+    /// Replace this partition of the existing partition table by the new one with the same
+    /// name when appending (can be repeated)
+    #[arg(long, value_name = "NAME", requires = "append")]
+    replace: Vec<String>,
+
+    /// Mark this partition as legacy BIOS bootable, e.g. idbloader (can be repeated)
+    #[arg(long, value_name = "NAME")]
+    set_bootable: Vec<String>,
+
+    /// Also mark the protective MBR partition as active, for firmware that looks for one
+    #[arg(long)]
+    bootable_pmbr: bool,
+
+    /// Don't write a protective MBR, the first block is left as it is for boot ROMs that
+    /// choke on one. Tools expecting a protective MBR may not recognize the GPT.
+    #[arg(long, conflicts_with_all = ["bootable_pmbr", "hybrid_mbr", "append"])]
+    no_pmbr: bool,
+
+    /// Mirror this partition in a hybrid MBR for loaders that only read MBR entries,
+    /// optionally with a hex MBR type like boot:0c (up to 3 times)
+    #[arg(
+        long, value_name = "NAME[:TYPE]", value_parser = parse_hybrid_mbr_partition,
+        conflicts_with = "bootable_pmbr"
+    )]
+    hybrid_mbr: Vec<HybridMbrPartition>,
+
+    /// Type of partition table to write, a DOS partition table holds up to 4 partitions
+    #[arg(
+        long, value_enum, default_value_t = TableType::Gpt,
+        conflicts_with_all = ["keep", "append", "hybrid_mbr", "bootable_pmbr", "no_pmbr"]
+    )]
+    table: TableType,
+
+    /// How much of the destination to clear before partitioning: quick clears the first
+    /// 8 MiB of block devices, full zeros the whole destination
+    #[arg(long, value_enum, default_value_t = Wipe::Quick, conflicts_with_all = ["keep", "append"])]
+    wipe: Wipe,
+
+    /// Write a manifest of the partitions and checksums to this file after flashing
+    /// (TOML if it ends in .toml, JSON otherwise)
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Deprecated, use rockflasher verify
+    #[arg(long, value_name = "MANIFEST", hide = true)]
+    verify_manifest: Option<PathBuf>,
+
+    /// Deprecated, use rockflasher list
+    #[arg(long, hide = true)]
+    list: bool,
+
+    /// Deprecated, use rockflasher info
+    #[arg(long, hide = true, conflicts_with_all = ["list", "verify_manifest"])]
+    info: bool,
+
+    /// Deprecated, use rockflasher delete
+    #[arg(
+        long, value_name = "NAME", hide = true,
+        conflicts_with_all = ["list", "info", "verify_manifest"]
+    )]
+    delete: Vec<String>,
+
+    /// Deprecated, use rockflasher delete --wipe-contents
+    #[arg(long, hide = true, requires = "delete")]
+    wipe_contents: bool,
+
+    /// Deprecated, use rockflasher resize
+    #[arg(
+        long, value_name = "NAME[:SIZE]", hide = true,
+        conflicts_with_all = ["list", "info", "verify_manifest", "delete"]
+    )]
+    resize: Option<String>,
+
+    /// Print events as JSON lines on stdout instead of progress for humans
+    #[arg(long)]
+    json: bool,
+
+    /// Units for the sizes that are printed, binary (MiB, GiB) or decimal (MB, GB)
+    #[arg(long, value_enum, default_value_t = SizeUnits::Iec)]
+    size_units: SizeUnits,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+/// Partition table types that can be written
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TableType {
+    /// GUID partition table
+    Gpt,
+    /// DOS partition table, for bootroms that can't read a GPT
+    Mbr,
+}
+
+/// How much of the destination is cleared before partitioning
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Wipe {
+    /// Clear the first 8 MiB, where old loaders and partition tables live
+    Quick,
+    /// Zero the whole destination
+    Full,
+}
+
+impl From<Wipe> for WipeMode {
+    fn from(wipe: Wipe) -> Self {
+        match wipe {
+            Wipe::Quick => WipeMode::Quick,
+            Wipe::Full => WipeMode::Full,
+        }
+    }
+}
+
+impl From<TableType> for PartitionTableType {
+    fn from(table_type: TableType) -> Self {
+        match table_type {
+            TableType::Gpt => PartitionTableType::Gpt,
+            TableType::Mbr => PartitionTableType::Mbr,
+        }
+    }
+}
+
+/// Slot of A/B partitions
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ActiveSlot {
+    A,
+    B,
+}
+
+impl From<ActiveSlot> for Slot {
+    fn from(slot: ActiveSlot) -> Self {
+        match slot {
+            ActiveSlot::A => Slot::A,
+            ActiveSlot::B => Slot::B,
+        }
+    }
+}
+
+/// How sizes are shown to the user
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SizeUnits {
+    /// Binary units like MiB, which is what most tools show
+    Iec,
+    /// Decimal units like MB, which is what storage vendors advertise
+    Si,
+}
+
+impl SizeUnits {
+    fn format(self, bytes: u64) -> String {
+        match self {
+            SizeUnits::Iec => BinarySize::from(bytes).rounded().to_string(),
+            SizeUnits::Si => DecimalSize::from(bytes).rounded().to_string(),
+        }
+    }
+
+    fn progress_template(self) -> &'static str {
+        match self {
+            SizeUnits::Iec => PROGRESS_TEMPLATE,
+            SizeUnits::Si => DECIMAL_PROGRESS_TEMPLATE,
+        }
+    }
+}
+
+/// Parses a size argument, noting when an ambiguous unit like "M" has been read as binary
+fn parse_size_arg(size_string: &str) -> Result<u64, String> {
+    let size: ParsedSize = size_string.parse()?;
+    if size.assumed_binary {
+        log::warn!(
+            "Note: interpreting size {} as binary units ({} bytes)", size_string, size.bytes
+        );
+    }
+    Ok(size.bytes)
+}
+
+/// Parses an offset, a size like 24MiB or a hex offset like 0x1800000
+fn parse_offset(offset_string: &str) -> Result<u64, String> {
+    match offset_string.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).map_err(|err| err.to_string()),
+        None => parse_size_arg(offset_string),
+    }
+}
+
+/// Parses the start of a partition
+fn parse_start_arg(start_string: &str) -> Result<u64, String> {
+    parse_offset(start_string)
+        .map_err(|e| format!("Invalid partition start ({}): {}", start_string, e))
+}
+
+fn parse_align_arg(align_string: &str) -> Result<u64, String> {
+    parse_size_arg(align_string)
+        .map_err(|e| format!("Invalid partition alignment ({}): {}", align_string, e))
+}
+
+/// Parses the slots of a partition, only A/B partitions are supported
+fn parse_slots_arg(slots_string: &str) -> Result<bool, String> {
+    match slots_string {
+        "ab" => Ok(true),
+        _ => Err(format!("Invalid partition slots {}, only ab is supported", slots_string)),
+    }
+}
+
+/// Parses a duration argument in seconds or milliseconds, e.g. 2s, 1.5 or 500ms
+fn parse_duration_arg(duration_string: &str) -> Result<Duration, String> {
+    let (number, millis) = match duration_string.strip_suffix("ms") {
+        Some(number) => (number, true),
+        None => (duration_string.strip_suffix("s").unwrap_or(duration_string), false),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| "not a number".to_string())?;
+    let seconds = if millis { number / 1000.0 } else { number };
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
 }
 
-fn check_args(opt: &Args) -> Result<(), String> {
-    match opt.destination.try_exists() {
+/// Checks that the destination can be written to as a file or block device
+fn check_destination(destination: &Path) -> Result<(), String> {
+    match destination.try_exists() {
         Err(err) => Err(format!(
             "Could not access file {}: {}",
-            opt.destination.to_str().unwrap_or("<invalid path>"), err
+            destination.to_str().unwrap_or("<invalid path>"), err
         )),
         _ => Ok(())
     }?;
 
-    if opt.destination.is_dir() {
+    if destination.is_dir() {
         return Err(format!(
             "Destination {} is a directory",
-            opt.destination.to_str().unwrap_or("<invalid path>")
+            destination.to_str().unwrap_or("<invalid path>")
         ))
     }
 
     Ok(())
 }
 
-#[derive(Clone, Debug)]
-struct PartitionDefinition {
-    partition_name: String,
-    source_file: Option<PathBuf>,
-    size: u64,
+fn check_args(opt: &FlashArgs, destination: &Path) -> Result<(), String> {
+    check_destination(destination)?;
+
+    let is_block_device = destination.metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_block_device());
+    // Boards written over rockusb are reset instead
+    let is_rockusb = destination == Path::new(ROCKUSB_DESTINATION);
+    if opt.eject && !is_block_device && !is_rockusb {
+        return Err(format!(
+            "Can't eject {}, it is not a block device",
+            destination.to_str().unwrap_or("<invalid path>")
+        ))
+    }
+
+    Ok(())
 }
 
-#[derive(Clone, Debug)]
-struct FormatPartitionDefinition {
-    partition_name: String,
-    format_as: String,
+fn parse_partition(part_arg: &String) -> Result<PartitionDefinition, CliError> {
+    let mut split = part_arg.split(":");
+    let (partition_name, source_filename) = match (split.next(), split.next()) {
+        (Some(partition_name), Some(source_filename)) => Ok((partition_name, source_filename)),
+        _ => Err(format!("Invalid partition argument: {}", part_arg)),
+    }?;
+
+    // Remaining fields are the size, the partition type or key=value options
+    let mut explicit_size = None;
+    let mut partition_type = None;
+    let mut checksum = None;
+    let mut part_guid = None;
+    let mut flags = None;
+    let mut start = None;
+    let mut alignment = None;
+    let mut ab_slots = false;
+    for field in split {
+        match field.split_once("=") {
+            Some(("sha256", digest)) => checksum = Some(Checksum::parse("sha256", digest)?),
+            Some(("uuid", guid_string)) => part_guid = Some(parse_partition_guid(guid_string)?),
+            Some(("flags", flags_string)) => flags = Some(parse_partition_flags(flags_string)?),
+            Some(("start", start_string)) => start = Some(parse_start_arg(start_string)?),
+            Some(("align", align_string)) => alignment = Some(parse_align_arg(align_string)?),
+            Some(("slots", slots_string)) => ab_slots = parse_slots_arg(slots_string)?,
+            Some(("type", type_string)) =>
+                partition_type = Some(parse_partition_type(type_string)?),
+            Some((key, _)) => return Err(format!(
+                "Unknown option {} in partition argument: {}", key, part_arg
+            ).into()),
+            None => match (parse_size_arg(field), parse_partition_type(field)) {
+                (Ok(size), _) => explicit_size = Some(size),
+                (Err(_), Ok(type_from_arg)) => partition_type = Some(type_from_arg),
+                (Err(size_err), Err(type_err)) => return Err(format!(
+                    "Invalid size or partition type ({}): {}, {}", field, size_err, type_err
+                ).into()),
+            },
+        }
+    }
+
+    let mut partition = PartitionDefinition::from_source(
+        partition_name.into(), source_filename.into(), explicit_size
+    )?;
+    partition.partition_type = partition_type;
+    partition.checksum = checksum;
+    partition.part_guid = part_guid;
+    partition.flags = flags;
+    partition.start = start;
+    partition.alignment = alignment;
+    partition.ab_slots = ab_slots;
+
+    Ok(partition)
 }
 
-#[derive(Clone, Debug)]
-struct CreatedPartition {
-    def: Option<PartitionDefinition>,
-    partition: Partition,
+/// Parses a checksum argument of the form name=algorithm:digest
+fn parse_checksum(checksum_arg: &String) -> Result<(String, Checksum), String> {
+    let (partition_name, checksum) = checksum_arg.split_once("=")
+        .ok_or_else(|| format!("Invalid checksum argument: {}", checksum_arg))?;
+    let (algorithm, digest) = checksum.split_once(":")
+        .ok_or_else(|| format!("Invalid checksum argument (missing algorithm): {}", checksum_arg))?;
+
+    Ok((partition_name.into(), Checksum::parse(algorithm, digest)?))
 }
 
-fn parse_partition(part_arg: &String) -> Result<PartitionDefinition, String> {
-    let split = match part_arg.split_once(":") {
-        None => Err(format!("Invalid partition argument: {}", part_arg)),
-        Some(split) => Ok(split)
-    }?;
-    let source_filename = split.1;
-    let source_file: PathBuf = source_filename.into();
-    match source_file.try_exists() {
-        Err(err) => Err(
-            format!("Source file {} is inaccessible: {}", source_filename, err)
-        ),
-        Ok(false) => Err(format!("Source file {} does not exist", source_filename)),
-        _ => Ok(())
-    }?;
-    let part_size =
-        metadata(source_file.clone())
-            .map_err(|err| format!(
-                "Failed to get metadata for source file {}: {}",
-                source_file.to_str().unwrap(), err
+fn apply_checksums(
+    opt: &FlashArgs,
+    mut partitions: Vec<PartitionDefinition>
+) -> Result<Vec<PartitionDefinition>, String> {
+    for checksum_arg in &opt.checksum {
+        let (partition_name, checksum) = parse_checksum(checksum_arg)?;
+        let partition = partitions.iter_mut()
+            .find(|part| part.partition_name == partition_name)
+            .ok_or_else(|| format!("Checksum given for unknown partition {}", partition_name))?;
+        if partition.source_file.is_none() {
+            return Err(format!(
+                "Checksum given for partition {} which has no source file", partition_name
             ))
-            .and_then(|source_metadata|
-                Ok(align_up(source_metadata.len(), FIRST_PART_ALIGNMENT))
-            )?;
-
-    Ok(PartitionDefinition {
-        partition_name: split.0.into(),
-        source_file: Some(source_file),
-        size: part_size,
-    })
+        }
+        partition.checksum = Some(checksum);
+    }
+    Ok(partitions)
+}
+
+/// Gives the A/B partitions of Android two slots with --ab
+fn apply_ab(
+    opt: &FlashArgs,
+    mut partitions: Vec<PartitionDefinition>,
+) -> Vec<PartitionDefinition> {
+    if opt.ab {
+        for partition in &mut partitions {
+            if AB_PARTITIONS.contains(&partition.partition_name.to_lowercase().as_str()) {
+                partition.ab_slots = true;
+            }
+        }
+    }
+    partitions
 }
 
 fn parse_empty_partition(part_arg: &String) -> Result<PartitionDefinition, String> {
-    let split = match part_arg.split_once(":") {
-        None => Err(format!("Invalid empty partition argument: {}", part_arg)),
-        Some(split) => Ok(split)
+    let mut split = part_arg.split(":");
+    let (partition_name, size_string) = match (split.next(), split.next()) {
+        (Some(partition_name), Some(size_string)) => Ok((partition_name, size_string)),
+        _ => Err(format!("Invalid empty partition argument: {}", part_arg)),
     }?;
-    let size_string = split.1;
-    let size = parse_size(size_string)
+    let size = parse_size_arg(size_string)
         .map_err(|e| format!("Invalid size for empty partition ({}): {}", size_string, e))?;
 
-    Ok(PartitionDefinition {
-        partition_name: split.0.into(),
-        source_file: None,
-        size,
-    })
+    // Remaining fields are the partition type or key=value options
+    let mut partition = PartitionDefinition::empty(partition_name.into(), size);
+    for field in split {
+        match field.split_once("=") {
+            Some(("type", type_string)) =>
+                partition.partition_type = Some(parse_partition_type(type_string)?),
+            Some(("uuid", guid_string)) =>
+                partition.part_guid = Some(parse_partition_guid(guid_string)?),
+            Some(("flags", flags_string)) =>
+                partition.flags = Some(parse_partition_flags(flags_string)?),
+            Some(("start", start_string)) => partition.start = Some(parse_start_arg(start_string)?),
+            Some(("align", align_string)) =>
+                partition.alignment = Some(parse_align_arg(align_string)?),
+            Some(("slots", slots_string)) => partition.ab_slots = parse_slots_arg(slots_string)?,
+            Some((key, _)) => return Err(format!(
+                "Unknown option {} in empty partition argument: {}", key, part_arg
+            )),
+            None => partition.partition_type = Some(parse_partition_type(field)?),
+        }
+    }
+
+    Ok(partition)
+}
+
+fn parse_fill_partition(fill_arg: &str) -> Result<FillPartition, String> {
+    let mut split = fill_arg.split(':');
+    let name = split.next().filter(|name| !name.is_empty())
+        .ok_or_else(|| format!("Invalid fill partition argument: {}", fill_arg))?;
+    // An empty type derives it from the name like for other partitions, e.g. data::32GiB
+    let partition_type = match split.next().filter(|type_string| !type_string.is_empty()) {
+        Some(type_string) => parse_partition_type(type_string)?,
+        None => partition_name_to_type(name.into()),
+    };
+    let max_size = split.next()
+        .map(|size| parse_size_arg(size)
+            .map_err(|e| format!("Invalid max size for fill partition ({}): {}", size, e)))
+        .transpose()?;
+    if split.next().is_some() {
+        return Err(format!("Invalid fill partition argument: {}", fill_arg))
+    }
+
+    Ok(FillPartition { name: name.into(), partition_type, max_size })
 }
 
 fn parse_format_partition(part_arg: &String) -> Result<FormatPartitionDefinition, String> {
-    let split = match part_arg.split_once(":") {
-        None => Err(format!("Invalid partition argument (missing fs): {}", part_arg)),
-        Some(split) => Ok(split)
+    // The options may contain colons themselves, e.g. -E root_owner=1000:1000
+    let mut split = part_arg.splitn(3, ":");
+    let (partition_name, format_as) = match (split.next(), split.next()) {
+        (Some(partition_name), Some(format_as)) => Ok((partition_name, format_as)),
+        _ => Err(format!("Invalid partition argument (missing fs): {}", part_arg)),
     }?;
-    let partition_name = split.0.into();
-    let format_as = split.1.into();
+    let mkfs_args = match split.next() {
+        Some(options) => split_mkfs_args(options)
+            .map_err(|err| format!("Invalid format partition argument ({}): {}", part_arg, err))?,
+        None => vec![],
+    };
+
+    Ok(FormatPartitionDefinition {
+        partition_name: partition_name.into(),
+        format_as: format_as.into(),
+        mkfs_args,
+        populate_from: None,
+    })
+}
+
+/// Collects U-Boot and the trust image, which are written to fixed offsets
+fn raw_images(opt: &FlashArgs, layout: &Layout) -> Result<Vec<RawImage>, String> {
+    let images = [
+        ("uboot", &opt.uboot, &layout.uboot, &opt.uboot_offset, rockflasher::DEFAULT_UBOOT_OFFSET),
+        ("trust", &opt.trust, &layout.trust, &opt.trust_offset, rockflasher::DEFAULT_TRUST_OFFSET),
+    ];
+    let mut raw_images = vec![];
+    for (name, source_file, layout_source_file, offset, default_offset) in images {
+        if let Some(source_file) = source_file.as_ref().or(layout_source_file.as_ref()) {
+            raw_images.push(RawImage {
+                name: name.into(),
+                source_file: source_file.clone(),
+                offset: offset.as_deref().map(parse_start_arg).transpose()?
+                    .unwrap_or(default_offset),
+            });
+        }
+    }
 
-    Ok(FormatPartitionDefinition { partition_name, format_as })
+    Ok(raw_images)
 }
 
-fn parse_partitions(opt: &Args) -> Result<Vec<PartitionDefinition>, String> {
+fn parse_partitions(opt: &FlashArgs) -> Result<Vec<PartitionDefinition>, CliError> {
     opt.partition.iter()
         .map(|part_arg| parse_partition(part_arg))
         .chain(
             opt.blank_partition.iter()
-                .map(|part_arg| parse_empty_partition(part_arg))
+                .map(|part_arg| parse_empty_partition(part_arg).map_err(CliError::from))
         )
         .collect()
 }
 
-fn parse_format_partitions(opt: &Args) -> Result<Vec<FormatPartitionDefinition>, String> {
-    opt.format_partition.iter()
+/// Sets the directories of --populate arguments of the form name:dir
+fn apply_populate(
+    populate_args: &[String],
+    mut partitions_to_format: Vec<FormatPartitionDefinition>,
+) -> Result<Vec<FormatPartitionDefinition>, String> {
+    for populate_arg in populate_args {
+        let (partition_name, dir) = populate_arg.split_once(':')
+            .filter(|(_, dir)| !dir.is_empty())
+            .ok_or_else(|| format!(
+                "Invalid populate argument (expected name:dir): {}", populate_arg
+            ))?;
+        let partition = partitions_to_format.iter_mut()
+            .find(|part| part.partition_name == partition_name)
+            .ok_or_else(|| format!(
+                "Partition {} to populate isn't formatted, pass --format-partition", partition_name
+            ))?;
+        if !Path::new(dir).is_dir() {
+            return Err(format!("Directory {} to populate {} does not exist", dir, partition_name))
+        }
+        partition.populate_from = Some(dir.into());
+    }
+
+    Ok(partitions_to_format)
+}
+
+fn parse_format_partitions(
+    format_args: &[String]
+) -> Result<Vec<FormatPartitionDefinition>, String> {
+    format_args.iter()
         .map(|part_arg| parse_format_partition(part_arg))
         .collect()
 }
 
-fn reorder_partitions(partitions: Vec<PartitionDefinition>) -> Vec<PartitionDefinition> {
-    let bootloader_partitions = partitions.clone().into_iter()
-        .filter(|part|
-            partition_name_to_type(
-                part.partition_name.clone()
-            ) == partition_types::ANDROID_BOOTLOADER
-        );
-
-    let all_other_partitions = partitions.into_iter()
-        .filter(|part|
-            partition_name_to_type(
-                part.partition_name.clone()
-            ) != partition_types::ANDROID_BOOTLOADER
-        );
+fn layout_partitions(
+    layout: &Layout
+) -> Result<(Vec<PartitionDefinition>, Vec<FormatPartitionDefinition>), CliError> {
+    let mut partitions = vec![];
+    let mut partitions_to_format = vec![];
+
+    for layout_partition in &layout.partitions {
+        let mut partition = match (&layout_partition.source, layout_partition.size) {
+            (Some(source), size) =>
+                PartitionDefinition::from_source(
+                    layout_partition.name.clone(), source.clone(), size
+                )?,
+            (None, Some(size)) =>
+                PartitionDefinition::empty(layout_partition.name.clone(), size),
+            (None, None) => unreachable!("validated when reading the layout"),
+        };
+        partition.partition_type = layout_partition.partition_type.as_ref()
+            .map(|type_string| parse_partition_type(type_string)
+                .map_err(|err| format!(
+                    "Invalid value for key 'type' in [[partition]] ({}): {}",
+                    layout_partition.name, err
+                ))
+            )
+            .transpose()?;
+        partition.checksum = layout_partition.sha256.as_ref()
+            .map(|digest| Checksum::parse("sha256", digest)
+                .map_err(|err| format!(
+                    "Invalid value for key 'sha256' in [[partition]] ({}): {}",
+                    layout_partition.name, err
+                ))
+            )
+            .transpose()?;
+        partition.part_guid = layout_partition.uuid.as_ref()
+            .map(|guid_string| parse_partition_guid(guid_string)
+                .map_err(|err| format!(
+                    "Invalid value for key 'uuid' in [[partition]] ({}): {}",
+                    layout_partition.name, err
+                ))
+            )
+            .transpose()?;
+        partition.start = layout_partition.start;
+        partition.alignment = layout_partition.align;
+        partition.ab_slots = layout_partition.slots.as_deref()
+            .map(|slots_string| parse_slots_arg(slots_string)
+                .map_err(|err| format!(
+                    "Invalid value for key 'slots' in [[partition]] ({}): {}",
+                    layout_partition.name, err
+                ))
+            )
+            .transpose()?
+            .unwrap_or(false);
+        partition.flags = layout_partition.flags.as_ref()
+            .map(|flags_string| parse_partition_flags(flags_string)
+                .map_err(|err| format!(
+                    "Invalid value for key 'flags' in [[partition]] ({}): {}",
+                    layout_partition.name, err
+                ))
+            )
+            .transpose()?;
+        partitions.push(partition);
+
+        if let Some(format_as) = &layout_partition.format {
+            let mkfs_args = match &layout_partition.mkfs_options {
+                Some(options) => split_mkfs_args(options)
+                    .map_err(|err| format!(
+                        "Invalid value for key 'mkfs_options' in [[partition]] ({}): {}",
+                        layout_partition.name, err
+                    ))?,
+                None => vec![],
+            };
+            partitions_to_format.push(FormatPartitionDefinition {
+                partition_name: layout_partition.name.clone(),
+                format_as: format_as.clone(),
+                mkfs_args,
+                populate_from: layout_partition.populate.clone(),
+            });
+        }
+    }
 
-    bootloader_partitions.chain(all_other_partitions).collect()
+    Ok((partitions, partitions_to_format))
 }
 
-fn main() -> Result<(), String> {
-    let opt = Args::parse();
+/// Builds the IDBloader from --tpl and --spl into a temporary file, so that it can be flashed
+/// like one passed using --idbloader. SPI flash gets it spread out like `mkimage -T rkspi` does.
+fn build_idbloader(opt: &FlashArgs, spl: &Path) -> Result<TempFile, CliError> {
+    let soc = find_soc(opt.soc.as_deref().unwrap_or_default())?;
+    let image = rockflasher::idbloader::build_idbloader(soc, opt.tpl.as_deref(), spl)?;
+    let image = match opt.spi {
+        true => spi_idbloader(&image),
+        false => image,
+    };
+
+    let file = TempFile::new("idbloader").map_err(|source| CliError::output(
+        format!(
+            "Failed to create a temporary file in {}",
+            temp_dir().to_str().unwrap_or("<invalid path>")
+        ),
+        source
+    ))?;
+    write(file.path(), image).map_err(|source| CliError::output(
+        format!(
+            "Failed to write IDBloader {}", file.path().to_str().unwrap_or("<invalid path>")
+        ),
+        source
+    ))?;
+    Ok(file)
+}
 
-    let size = parse_size(opt.size.clone())
-        .map_err(|e| format!("Invalid size ({}): {}", opt.size, e))?;
+/// Replaces the entries in `base` by the ones in `overrides` with the same name
+/// and appends all other entries of `overrides`
+fn merge_by_name<T>(mut base: Vec<T>, overrides: Vec<T>, name: impl Fn(&T) -> &str) -> Vec<T> {
+    for entry in overrides {
+        match base.iter().position(|existing| name(existing) == name(&entry)) {
+            Some(index) => base[index] = entry,
+            None => base.push(entry),
+        }
+    }
+    base
+}
 
-    check_args(&opt)?;
+/// Why rockflasher failed, decides about the exit code
+#[derive(Debug, thiserror::Error)]
+enum CliError {
+    /// The arguments or files they point to, like the layout, are invalid
+    #[error("{0}")]
+    Args(String),
+    /// Flashing, formatting or reading the destination failed
+    #[error(transparent)]
+    Flash(#[from] FlashError),
+    /// Output like the partition table or the manifest could not be written
+    #[error("{message}: {source}")]
+    Output { message: String, source: io::Error },
+}
 
-    let partitions = parse_partitions(&opt)?;
-    let partitions = reorder_partitions(partitions);
-    let partitions_to_format = parse_format_partitions(&opt)?;
+impl CliError {
+    fn output(message: impl Into<String>, source: io::Error) -> Self {
+        CliError::Output { message: message.into(), source }
+    }
 
-    flash(opt.destination.clone(), size, partitions, opt.idbloader)?;
-    format_partitions(opt.destination.clone(), partitions_to_format)?;
+    /// Exit codes, 2 is what clap uses for invalid arguments as well
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Args(_) => 2,
+            CliError::Output { .. } => 1,
+            CliError::Flash(err) => match err {
+                // The layout doesn't work out with the destination or the other arguments
+                FlashError::LayoutDoesNotFit { .. } |
+                FlashError::MissingImageSize { .. } |
+                FlashError::SizeOverflow { .. } |
+                FlashError::PartitionNotFound { .. } |
+                FlashError::KeptPartitionMissing { .. } |
+                FlashError::KeptPartitionConflict { .. } |
+                FlashError::KeptPartitionBeyondEnd { .. } |
+                FlashError::ReservedSpaceTooLarge { .. } |
+                FlashError::ReservedSpaceUsed { .. } |
+                FlashError::PartitionExists { .. } |
+                FlashError::ReplacedPartitionInvalid { .. } |
+                FlashError::ResizeInvalid { .. } |
+                FlashError::PartitionStartInvalid { .. } |
+                FlashError::PartitionAlignmentInvalid { .. } |
+                FlashError::PartitionOverlap { .. } |
+                FlashError::MbrEntryInvalid { .. } |
+                FlashError::DuplicatePartitionGuid { .. } |
+                FlashError::InvalidBlockSize { .. } |
+                FlashError::OffsetInvalid { .. } |
+                FlashError::StdinSourceInvalid { .. } |
+                FlashError::UnsupportedLbaSize { .. } |
+                FlashError::Unsupported(_) => 2,
+                FlashError::DeviceAccess { .. } |
+                FlashError::DeviceInfo { .. } |
+                FlashError::DestinationMounted { .. } |
+                FlashError::SystemDisk { .. } |
+                FlashError::PartitionMounted { .. } |
+                FlashError::DeviceTimeout { .. } |
+                FlashError::EjectFailed { .. } |
+                FlashError::RockusbFailed { .. } => 3,
+                FlashError::NotConfirmed { .. } |
+                FlashError::Prompt(_) |
+                FlashError::Aborted => 4,
+                FlashError::SourceMissing { .. } |
+                FlashError::SourceAccess { .. } |
+                FlashError::SourceSize { .. } |
+                FlashError::ChecksumMismatch { .. } |
+                FlashError::ImageTooLarge { .. } |
+                FlashError::SourceReadFailed { .. } |
+                FlashError::StdinTooLong { .. } |
+                FlashError::BootStageInvalid { .. } |
+                FlashError::IdbloaderInvalid { .. } |
+                FlashError::VbmetaInvalid { .. } => 5,
+                FlashError::LayoutTooLarge { .. } |
+                FlashError::GptRead { .. } |
+                FlashError::GptWrite { .. } |
+                FlashError::InvalidPartitionTable { .. } => 6,
+                FlashError::WriteFailed { .. } |
+                FlashError::ReadFailed { .. } |
+                FlashError::VerifyFailed { .. } => 7,
+                FlashError::MkfsFailed { .. } |
+                FlashError::PopulateFailed { .. } => 8,
+                FlashError::MissingTools { .. } => 1,
+            },
+        }
+    }
+}
 
-    Ok(())
+impl From<String> for CliError {
+    fn from(message: String) -> Self {
+        CliError::Args(message)
+    }
 }
 
-fn flash(
-    destination: PathBuf,
-    size: u64,
-    partitions: Vec<PartitionDefinition>,
-    idbloader: Option<PathBuf>,
-) -> Result<(), String> {
-    if partitions.is_empty() && idbloader.is_none() {
-        eprintln!("No partitions specified, nothing to flash, skipping.");
-        return Ok(())
+impl From<&str> for CliError {
+    fn from(message: &str) -> Self {
+        CliError::Args(message.into())
     }
+}
 
-    let (size, is_block_device) = match is_block_device(destination.clone()) {
-        Ok(true) => match get_device_size(destination.clone()) {
-            Ok(size) => Ok((size, true)),
-            Err(_) => Err(format!(
-                "Failed to determine device size: {}",
-                destination.to_str().unwrap_or("<invalid path>")
-            ))
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let verbosity = match &cli.command {
+        Some(Command::Flash(opt)) => opt.verbosity,
+        Some(Command::Format(opt)) => opt.verbosity,
+        Some(Command::Info(opt)) => opt.verbosity,
+        Some(Command::List(opt)) => opt.verbosity,
+        Some(Command::Delete(opt)) => opt.verbosity,
+        Some(Command::Resize(opt)) => opt.verbosity,
+        Some(Command::Verify(opt)) => opt.verbosity,
+        Some(Command::Completions(_) | Command::Complete(_)) => Verbosity::default(),
+        None => cli.flash.verbosity,
+    };
+    init_logging(verbosity);
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(err.exit_code())
         },
-        _ => Ok((size, false)),
-    }?;
-
-    eprintln!(
-        "Destination: {} ({})", destination.to_str().unwrap(),
-        BinarySize::from(size).rounded()
-    );
-
-    if !is_block_device {
-        create_sparse_file(destination.clone(), size)?;
-    } else {
-        erase_beginning(destination.clone())?;
     }
+}
 
-    let created_partitions =
-        create_partition_table(destination.clone(), partitions, idbloader)?;
+/// Messages rockflasher prints by default are info messages, so they are shown like they
+/// always have been. Debug messages name their module, e.g. rockflasher::partition.
+fn init_logging(verbosity: Verbosity) {
+    let (level, library_level) = match (verbosity.quiet, verbosity.verbose) {
+        (true, _) => (LevelFilter::Error, LevelFilter::Error),
+        (false, 0) => (LevelFilter::Info, LevelFilter::Warn),
+        (false, 1) => (LevelFilter::Debug, LevelFilter::Warn),
+        (false, _) => (LevelFilter::Trace, LevelFilter::Debug),
+    };
+    env_logger::Builder::new()
+        .filter_level(library_level)
+        .filter_module("rockflasher", level)
+        .format(|buf, record| match record.level() {
+            Level::Error | Level::Warn | Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(buf, "[{} {}] {}", level, record.target(), record.args()),
+        })
+        // RUST_LOG takes precedence over the flags
+        .parse_default_env()
+        .init();
+}
 
-    write_images(destination, created_partitions)?;
+fn run(cli: Cli) -> Result<(), CliError> {
+    match cli.command {
+        Some(Command::Flash(opt)) => run_flash(*opt),
+        Some(Command::Format(opt)) => run_format(opt),
+        Some(Command::Info(opt)) => run_info(opt),
+        Some(Command::List(opt)) => run_list(opt),
+        Some(Command::Delete(opt)) => run_delete(opt),
+        Some(Command::Resize(opt)) => run_resize(opt),
+        Some(Command::Verify(opt)) => run_verify(opt),
+        Some(Command::Completions(opt)) => {
+            print_completions(opt.shell);
+            Ok(())
+        },
+        Some(Command::Complete(opt)) =>
+            Ok(print_candidates(opt.candidates, opt.layout.as_deref())?),
+        // Invocations from before there were subcommands
+        None => run_flash(cli.flash),
+    }
+}
 
-    eprintln!("Flash complete.");
+/// Takes the destination from the arguments or the layout file
+fn resolve_destination(destination: Option<&PathBuf>, layout: &Layout) -> Result<PathBuf, String> {
+    destination.or(layout.destination.as_ref())
+        .cloned()
+        .ok_or_else(|| {
+            "No destination given, pass --destination or set it in the layout file".into()
+        })
+}
 
+/// Asks before overwriting a block device, image files are written without asking
+fn confirm_overwrite(
+    destination: &Path,
+    yes: bool,
+    size_units: SizeUnits,
+) -> Result<(), FlashError> {
+    if yes {
+        return Ok(())
+    }
+    if let Some(device_size) = rockflasher::device_size(destination)? {
+        confirm_destination(destination.into(), device_size, size_units)?;
+    }
     Ok(())
 }
 
-fn open_write_sync(path: PathBuf) -> io::Result<File> {
-    OpenOptions::new()
-        .read(true).write(true)
-        .custom_flags(
-            if cfg!(unix) {
-                libc::O_SYNC
-            } else {
-                0
-            }
-        )
-        .open(path)
+/// Runs `run` with its events going to the JSON or the human reporter
+fn report_events(
+    json: bool,
+    size_units: SizeUnits,
+    run: impl FnOnce(&mut dyn FnMut(FlashEvent)) -> Result<(), FlashError>,
+) -> Result<(), FlashError> {
+    if json {
+        let mut reporter = JsonReporter::default();
+        let result = run(&mut |event| reporter.report(event));
+        reporter.summary(&result);
+        result
+    } else if !log::log_enabled!(Level::Info) {
+        // Quiet, only the error is printed
+        run(&mut |_| {})
+    } else {
+        let mut reporter = Reporter::new(size_units);
+        let result = run(&mut |event| reporter.report(event));
+        reporter.finish();
+        result
+    }
 }
 
-fn create_protective_mbr(path: PathBuf) -> Result<(), String> {
-    let mut file = open_write_sync(path.clone())
-        .map_err(|err| format!("Could not open file: {}", err))?;
+fn run_info(opt: InfoArgs) -> Result<(), CliError> {
+    check_destination(&opt.destination)?;
+    let lba = rockflasher::logical_block_size(&opt.destination, opt.lba_size)?;
+    let disk_info = read_disk_info(&opt.destination, lba)?;
+    if opt.json {
+        return Ok(print_json(&disk_info))
+    }
+    print_disk_info(&mut io::stdout(), &disk_info, opt.size_units)
+        .map_err(|source| CliError::output("Failed to print partition table", source))
+}
 
-    let device_size = get_device_size(path.clone()).unwrap();
+fn run_list(opt: ListArgs) -> Result<(), CliError> {
+    check_destination(&opt.destination)?;
+    let destination = &opt.destination;
+    let lba = rockflasher::logical_block_size(destination, opt.lba_size)?;
+    if let TableType::Mbr = opt.table {
+        let mbr_entries = read_mbr(destination, lba)?;
+        if opt.json {
+            return Ok(print_json(&mbr_entries))
+        }
+        return print_mbr_table(&mut io::stdout(), &mbr_entries, lba.into(), opt.size_units)
+            .map_err(|source| CliError::output("Failed to print MBR", source))
+    }
+    let partitions = read_partition_table(destination, lba)?;
+    if opt.json {
+        return Ok(print_json(&partitions))
+    }
+    print_partition_table(&mut io::stdout(), &partitions, opt.size_units)
+        .map_err(|source| CliError::output("Failed to print partition table", source))?;
+    // A plain protective MBR isn't worth mentioning, neither is a missing one
+    let mut mbr_entries = read_mbr(destination, lba).unwrap_or_default();
+    for entry in &mut mbr_entries {
+        entry.name = partitions.iter()
+            .find(|partition| partition.first_lba == entry.first_lba)
+            .map(|partition| partition.name.clone());
+    }
+    if mbr_entries.iter().any(|entry| entry.os_type != 0xEE) {
+        println!();
+        print_mbr_table(&mut io::stdout(), &mbr_entries, lba.into(), opt.size_units)
+            .map_err(|source| CliError::output("Failed to print MBR", source))?;
+    }
+    Ok(())
+}
 
-    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
-        u32::try_from((device_size / LBA_SIZE) - 1).unwrap_or(0xFF_FF_FF_FF));
-    mbr.overwrite_lba0(&mut file)
-        .map_err(|err| format!("Failed to write MBR to {}: {}", path.to_str().unwrap(), err))?;
+fn run_delete(opt: DeleteArgs) -> Result<(), CliError> {
+    check_destination(&opt.destination)?;
+    confirm_overwrite(&opt.destination, opt.yes, opt.size_units)?;
+    report_events(opt.json, opt.size_units, |on_event| {
+        rockflasher::delete_partitions(
+            opt.destination.clone(), &opt.partition, opt.wipe_contents, opt.lba_size, on_event
+        )
+    })?;
+    Ok(())
+}
 
+fn run_resize(opt: ResizeArgs) -> Result<(), CliError> {
+    check_destination(&opt.destination)?;
+    let (name, size) = match opt.partition.split_once(':') {
+        Some((name, size)) => (name, Some(
+            parse_size_arg(size).map_err(|e| format!("Invalid size ({}): {}", size, e))?
+        )),
+        None => (opt.partition.as_str(), None),
+    };
+    let partitions_to_format = parse_format_partitions(&opt.format_partition)?;
+    confirm_overwrite(&opt.destination, opt.yes, opt.size_units)?;
+    report_events(opt.json, opt.size_units, |on_event| {
+        rockflasher::resize_partition(
+            opt.destination.clone(), name, size, opt.force, partitions_to_format, opt.lba_size,
+            on_event
+        )
+    })?;
     Ok(())
 }
 
-fn create_partition_table(
-    destination: PathBuf,
-    partitions: Vec<PartitionDefinition>,
-    idbloader: Option<PathBuf>,
-) -> Result<Vec<CreatedPartition>, String> {
-    let mut created_partitions = vec![];
+fn run_verify(opt: VerifyArgs) -> Result<(), CliError> {
+    check_destination(&opt.destination)?;
+    let manifest = Manifest::read(&opt.manifest)?;
+    report_events(opt.json, opt.size_units, |on_event| {
+        rockflasher::verify_manifest(opt.destination.clone(), &manifest, on_event)
+    })?;
+    if !opt.json {
+        log::info!(
+            "{} matches the manifest.", opt.destination.to_str().unwrap_or("<invalid path>")
+        );
+    }
+    Ok(())
+}
 
-    eprintln!("Creating protective MBR…");
-    create_protective_mbr(destination.clone())?;
+const BIN_NAME: &str = "rockflasher";
+
+/// Completes --destination with block devices and --format-partition and --populate with the
+/// partitions of the layout file, then hands over to the generated _rockflasher
+const BASH_DYNAMIC_COMPLETION: &str = r#"
+_rockflasher_dynamic() {
+    local cur prev layout i
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+    case "${prev}" in
+        -d|--destination)
+            COMPREPLY=($(compgen -W "$(rockflasher complete devices 2>/dev/null)" -- "${cur}"))
+            COMPREPLY+=($(compgen -f -- "${cur}"))
+            return 0
+            ;;
+        -f|--format-partition|--populate)
+            for ((i = 1; i < COMP_CWORD - 1; i++)); do
+                case "${COMP_WORDS[i]}" in
+                    -l|--layout|--config) layout="${COMP_WORDS[i+1]}" ;;
+                esac
+            done
+            if [[ -n "${layout}" ]]; then
+                COMPREPLY=($(compgen -W \
+                    "$(rockflasher complete partitions --layout "${layout}" 2>/dev/null)" \
+                    -- "${cur}"))
+                compopt -o nospace
+                return 0
+            fi
+            ;;
+    esac
+    _rockflasher "$@"
+}
 
-    let cfg = gpt::GptConfig::new()
-        .initialized(false)
-        .writable(true)
-        .logical_block_size(LBA);
+complete -F _rockflasher_dynamic -o bashdefault -o default rockflasher
+"#;
 
+/// The same for zsh, these are defined before the generated _rockflasher runs
+const ZSH_DYNAMIC_COMPLETION: &str = r#"
+_rockflasher_destinations() {
+    local -a devices
+    devices=(${(f)"$(rockflasher complete devices 2>/dev/null)"})
+    _alternative 'devices:block device:compadd -a devices' 'files:file:_files'
+}
 
-    eprintln!("Opening {}…", destination.to_str().unwrap());
-    let mut disk = cfg.open(destination.clone())
-        .map_err(|err| format!(
-            "Failed to open file {} for creating a partition table: {}",
-            destination.to_str().unwrap(), err
-        ))?;
+_rockflasher_partitions() {
+    local layout i
+    for ((i = 2; i < CURRENT; i++)); do
+        case "${words[i]}" in
+            -l|--layout|--config) layout="${words[i+1]}" ;;
+        esac
+    done
+    [[ -n "$layout" ]] || return 1
+    local -a names
+    names=(${(f)"$(rockflasher complete partitions --layout "$layout" 2>/dev/null)"})
+    compadd -S '' -a names
+}
+"#;
+
+/// Adds block devices to the files fish completes for --destination
+const FISH_DYNAMIC_COMPLETION: &str = "complete -c rockflasher -s d -l destination -r \
+    -a '(rockflasher complete devices 2>/dev/null)'\n";
+
+fn print_completions(shell: Shell) {
+    let mut script = vec![];
+    generate(shell, &mut Cli::command(), BIN_NAME, &mut script);
+    let script = String::from_utf8_lossy(&script);
+    let script = match shell {
+        Shell::Bash => format!("{}{}", script, BASH_DYNAMIC_COMPLETION),
+        // The generated script is the body of the autoloaded function, so the helpers have to
+        // be defined before it calls _rockflasher
+        Shell::Zsh => {
+            let script = script
+                .replace(":DESTINATION:_files", ":DESTINATION:_rockflasher_destinations")
+                .replace(":FORMAT_PARTITION:_default", ":FORMAT_PARTITION:_rockflasher_partitions")
+                .replace(":NAME:DIR:_default", ":NAME\\:DIR:_rockflasher_partitions")
+                .replace(":NAME\\:DIR:_default", ":NAME\\:DIR:_rockflasher_partitions");
+            match script.split_once('\n') {
+                Some((compdef, rest)) =>
+                    format!("{}\n{}\n{}", compdef, ZSH_DYNAMIC_COMPLETION, rest),
+                None => script,
+            }
+        },
+        Shell::Fish => format!("{}{}", script, FISH_DYNAMIC_COMPLETION),
+        _ => script.into_owned(),
+    };
+    print!("{}", script);
+}
 
-    // Make sure there are no partitions
-    disk.update_partitions(BTreeMap::<u32, Partition>::new())
-        .map_err(|err| format!("Failed to clear partition table: {}", err))?;
+/// Prints one completion candidate per line, errors are left out as nobody would see them
+fn print_candidates(candidates: Candidates, layout: Option<&Path>) -> Result<(), String> {
+    match candidates {
+        Candidates::Devices => {
+            let Ok(entries) = std::fs::read_dir("/sys/block") else { return Ok(()) };
+            let mut devices: Vec<String> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                // RAM disks and unused loop devices have no size
+                .filter(|name| {
+                    std::fs::read_to_string(format!("/sys/block/{}/size", name))
+                        .is_ok_and(|size| size.trim() != "0")
+                })
+                .filter(|name| !name.starts_with("ram") && !name.starts_with("zram"))
+                .collect();
+            devices.sort();
+            for device in devices {
+                println!("/dev/{}", device);
+            }
+        },
+        Candidates::Partitions => {
+            let Some(layout) = layout.and_then(|layout| read_layout(layout).ok()) else {
+                return Ok(())
+            };
+            for partition in layout.partitions {
+                println!("{}:", partition.name);
+            }
+        },
+    }
+    Ok(())
+}
 
-    if let Some(idbloader) = idbloader {
-        let loader_size = metadata(idbloader.clone())
-            .map_err(|err| format!(
-                "Failed to get metadata for file {}: {}",
-                idbloader.to_str().unwrap(), err
-            ))
-            .and_then(|source_metadata|
-                Ok(align_up(source_metadata.len(), IDBLOADER_ALIGNMENT))
-            )?;
-        eprintln!(
-            "Adding partition for pre-bootloader, size {}",
-            BinarySize::from(loader_size).rounded()
-        );
-        let part_id = disk.add_partition(
-            IDBLOADER_PARTNAME,
-            loader_size,
-            partition_types::ANDROID_BOOTLOADER,
-            0,
-            Some(IDBLOADER_ALIGNMENT_LBA)
-        ).map_err(|err| format!(
-            "Could not add pre-bootloader partition, size {}: {}",
-            BinarySize::from(loader_size).rounded(), err
-        ))?;
+fn run_format(opt: FormatArgs) -> Result<(), CliError> {
+    check_destination(&opt.destination)?;
+    let partitions_to_format = parse_format_partitions(&opt.partition)?;
+    let partitions_to_format = apply_populate(&opt.populate, partitions_to_format)?;
+    confirm_overwrite(&opt.destination, opt.yes, opt.size_units)?;
+    report_events(opt.json, opt.size_units, |on_event| {
+        rockflasher::format_existing_partitions(
+            opt.destination.clone(), partitions_to_format, opt.lba_size, on_event
+        )
+    })?;
+    Ok(())
+}
 
-        let partition = disk.partitions().get(&part_id)
-            .ok_or(format!("Can't find created partition with ID {}", part_id))?;
+fn run_flash(opt: FlashArgs) -> Result<(), CliError> {
+    let layout = match &opt.layout {
+        Some(layout) => read_layout(layout)?,
+        None => Layout::default(),
+    };
+    let destination = resolve_destination(opt.destination.as_ref(), &layout)?;
+    let rockusb = destination == Path::new(ROCKUSB_DESTINATION);
+    if rockusb && (opt.list || opt.info || opt.verify_manifest.is_some() || !opt.delete.is_empty()
+        || opt.resize.is_some() || opt.spi) {
+        return Err("Only flashing is supported over rockusb".into())
+    }
+    if opt.rockusb_loader.is_some() && !rockusb {
+        return Err(format!("--rockusb-loader needs --destination {}", ROCKUSB_DESTINATION).into())
+    }
+    if rockusb && opt.offset.is_some() {
+        return Err("--offset can't be used over rockusb".into())
+    }
 
-        created_partitions.push(
-            CreatedPartition {
-                def: Some(PartitionDefinition {
-                    partition_name: IDBLOADER_PARTNAME.into(),
-                    source_file: Some(idbloader.clone()),
-                    size: loader_size,
-                }),
-                partition: partition.clone(),
-            }
-        );
+    // The modes from before the subcommands, they are hidden but still work
+    if opt.info {
+        log::warn!("--info is deprecated, use rockflasher info");
+        return run_info(InfoArgs {
+            destination, lba_size: opt.lba_size, json: opt.json, size_units: opt.size_units,
+            verbosity: opt.verbosity,
+        })
     }
 
-    for (index, partition_def) in partitions.iter().enumerate() {
-        let part_alignment = if index == 0 { FIRST_PART_ALIGNMENT } else { PART_ALIGNMENT };
-        let part_size = partition_def.size;
+    if opt.list {
+        log::warn!("--list is deprecated, use rockflasher list");
+        return run_list(ListArgs {
+            destination, table: opt.table, lba_size: opt.lba_size, json: opt.json,
+            size_units: opt.size_units, verbosity: opt.verbosity,
+        })
+    }
 
-        eprintln!(
-            "Adding partition {}, size {}",
-            partition_def.partition_name, BinarySize::from(part_size).rounded()
-        );
+    if let Some(manifest) = opt.verify_manifest {
+        log::warn!("--verify-manifest is deprecated, use rockflasher verify");
+        return run_verify(VerifyArgs {
+            manifest, destination, json: opt.json, size_units: opt.size_units,
+            verbosity: opt.verbosity,
+        })
+    }
 
-        let part_id = disk.add_partition(
-            partition_def.partition_name.as_str(),
-            part_size,
-            partition_name_to_type(partition_def.partition_name.clone()),
-            partition_name_to_flags(partition_def.partition_name.clone()),
-            // Align on 1 MiB boundary
-            Some(part_alignment / LBA_SIZE)
-        ).map_err(|err| format!(
-            "Could not add partition name {}, size {}: {}",
-            partition_def.partition_name, BinarySize::from(part_size).rounded(), err
-        ))?;
+    if !opt.delete.is_empty() {
+        log::warn!("--delete is deprecated, use rockflasher delete");
+        return run_delete(DeleteArgs {
+            partition: opt.delete, destination, wipe_contents: opt.wipe_contents, yes: opt.yes,
+            lba_size: opt.lba_size, json: opt.json, size_units: opt.size_units,
+            verbosity: opt.verbosity,
+        })
+    }
 
-        let partition = disk.partitions().get(&part_id)
-            .ok_or(format!("Can't find created partition with ID {}", part_id))?;
-        created_partitions.push(
-            CreatedPartition {
-                def: Some(partition_def.clone()),
-                partition: partition.clone(),
-            }
-        );
+    if let Some(partition) = opt.resize {
+        log::warn!("--resize is deprecated, use rockflasher resize");
+        return run_resize(ResizeArgs {
+            partition, destination, format_partition: opt.format_partition, force: opt.force,
+            yes: opt.yes, lba_size: opt.lba_size, json: opt.json, size_units: opt.size_units,
+            verbosity: opt.verbosity,
+        })
     }
 
-    let has_created_userdata = partitions.iter()
-        .any(|def|
-            partition_name_to_type(def.partition_name.clone()) == partition_types::ANDROID_DATA
-        );
-    if !has_created_userdata {
-        // For the remaining space, we'll create an userdata partition
-        if let Some(last_free_sectors) = disk.find_free_sectors().last() {
-            let last_free_sectors = last_free_sectors.clone();
-            let part_size = last_free_sectors.1 * LBA_SIZE;
-            eprintln!(
-                "Creating userdata partition, size {}", BinarySize::from(part_size).rounded()
-            );
-            let part_id = disk.add_partition(
-                "userdata",
-                part_size,
-                partition_types::ANDROID_DATA,
-                0,
-                Some(PART_ALIGNMENT / LBA_SIZE)
-            ).map_err(|err| format!(
-                "Could not add userdata partition size {}: {}",
-                BinarySize::from(part_size).rounded(), err
-            ))?;
-            let partition = disk.partitions().get(&part_id)
-                .ok_or(format!("Can't find created partition with ID {}", part_id))?;
-            created_partitions.push(
-                CreatedPartition {
-                    def: None,
-                    partition: partition.clone(),
-                }
-            );
+    if opt.spi {
+        if !layout.partitions.is_empty() {
+            return Err("SPI flash has no partition table, the layout can't have partitions".into())
+        }
+        let built_idbloader = opt.spl.as_ref()
+            .map(|spl| build_idbloader(&opt, spl))
+            .transpose()?;
+        let idbloader = match &built_idbloader {
+            Some(built_idbloader) => Some(built_idbloader.path().into()),
+            None => opt.idbloader.clone().or(layout.idbloader.clone()),
+        }.ok_or("SPI flash needs an IDBloader, pass --idbloader or --spl")?;
+        let size = match &opt.size {
+            Some(size) => parse_size_arg(size)
+                .map_err(|e| format!("Invalid size ({}): {}", size, e))?,
+            None => layout.size.unwrap_or(DEFAULT_SPI_SIZE),
+        };
+        let mut raw_images = vec![];
+        if let Some(uboot) = opt.uboot.clone().or(layout.uboot.clone()) {
+            raw_images.push(RawImage {
+                name: "uboot".into(),
+                source_file: uboot,
+                offset: opt.uboot_offset.as_deref().map(parse_start_arg).transpose()?
+                    .unwrap_or(DEFAULT_SPI_UBOOT_OFFSET),
+            });
+        }
+        let plan = SpiPlan {
+            destination: destination.clone(),
+            size,
+            idbloader,
+            check_idbloader: !opt.skip_idbloader_check,
+            raw_images,
+        };
+
+        if !opt.yes {
+            if let Some(device_size) = rockflasher::spi::mtd_device_size(&destination)? {
+                confirm_destination(destination.clone(), device_size, opt.size_units)?;
+            }
         }
+        return Ok(report_events(opt.json, opt.size_units, |on_event| {
+            rockflasher::spi::flash_spi(plan, on_event)
+        })?)
     }
 
-    eprintln!("Writing partition table…");
-    disk.write().map_err(|err| format!("Failed to write partition table: {}", err))?;
+    let size = match &opt.size {
+        Some(size) => parse_size_arg(size)
+            .map_err(|e| format!("Invalid size ({}): {}", size, e))?,
+        None => layout.size.unwrap_or(0),
+    };
+    let built_idbloader = opt.spl.as_ref()
+        .map(|spl| build_idbloader(&opt, spl))
+        .transpose()?;
+    let idbloader = match &built_idbloader {
+        Some(built_idbloader) => Some(built_idbloader.path().into()),
+        None => opt.idbloader.clone().or(layout.idbloader.clone()),
+    };
+    if opt.idbloader_raw && idbloader.is_none() {
+        return Err("--idbloader-raw needs an IDBloader, pass --idbloader".into())
+    }
+    let raw_images = raw_images(&opt, &layout)?;
+    let block_size = match &opt.block_size {
+        Some(block_size) => parse_size_arg(block_size)
+            .map_err(|e| format!("Invalid block size ({}): {}", block_size, e))
+            .and_then(|block_size| usize::try_from(block_size)
+                .map_err(|_| format!("Block size {} is too large", block_size))
+            )?,
+        None => rockflasher::DEFAULT_BLOCK_SIZE,
+    };
+    let retry_delay = match &opt.retry_delay {
+        Some(retry_delay) => parse_duration_arg(retry_delay)
+            .map_err(|e| format!("Invalid retry delay ({}): {}", retry_delay, e))?,
+        None => rockflasher::DEFAULT_RETRY_DELAY,
+    };
+    let offset = opt.offset.as_ref()
+        .map(|offset| parse_offset(offset)
+            .map_err(|e| format!("Invalid offset ({}): {}", offset, e))
+        )
+        .transpose()?;
+    let max_write_rate = opt.max_write_rate.as_ref()
+        .map(|rate| parse_size_arg(rate)
+            .map_err(|e| format!("Invalid write rate ({}): {}", rate, e))
+        )
+        .transpose()?
+        .filter(|rate| *rate > 0);
 
-    Ok(created_partitions)
-}
+    check_args(&opt, &destination)?;
 
-fn get_device_size(device_path: impl AsRef<Path>) -> BlockResult<u64> {
-    match get_device_info(device_path) {
-        Ok(device) => Ok(device.capacity),
-        Err(e) => Err(e),
+    let (layout_partitions, layout_partitions_to_format) = layout_partitions(&layout)?;
+    let partitions = merge_by_name(
+        layout_partitions, parse_partitions(&opt)?, |part| part.partition_name.as_str()
+    );
+    let partitions = apply_checksums(&opt, partitions)?;
+    let partitions = apply_ab(&opt, partitions);
+    let partitions_to_format = merge_by_name(
+        layout_partitions_to_format, parse_format_partitions(&opt.format_partition)?,
+        |part| part.partition_name.as_str()
+    );
+    let partitions_to_format = apply_populate(&opt.populate, partitions_to_format)?;
+
+    let type_map = match &opt.type_map {
+        Some(type_map) => read_type_map(type_map)?,
+        None => BTreeMap::new(),
+    };
+    let reserve = match &opt.reserve {
+        Some(reserve) => parse_size_arg(reserve)
+            .map_err(|e| format!("Invalid size to reserve ({}): {}", reserve, e))?,
+        None => 0,
+    };
+    let fill_partition = match &opt.fill_partition {
+        _ if opt.no_userdata || opt.auto_grow.is_some() => None,
+        Some(fill_arg) => Some(parse_fill_partition(fill_arg)?),
+        None => Some(FillPartition::default()),
+    };
+
+    let vbmeta_flags = match (opt.disable_verity, opt.disable_verification) {
+        (true, true) => FLAG_HASHTREE_DISABLED | FLAG_VERIFICATION_DISABLED,
+        (true, false) => FLAG_HASHTREE_DISABLED,
+        (false, true) => FLAG_VERIFICATION_DISABLED,
+        (false, false) => 0,
+    };
+    let has_vbmeta = partitions.iter()
+        .any(|part| part.source_file.is_some() && is_vbmeta_partition(&part.partition_name));
+    if vbmeta_flags != 0 && !has_vbmeta {
+        return Err("--disable-verity and --disable-verification need a vbmeta partition".into())
     }
-}
-
-fn create_sparse_file(path: impl AsRef<Path>, size: u64) -> Result<(), String> {
-    let mut open_options = OpenOptions::new();
-    open_options.read(true).write(true).create(true).truncate(true);
 
-    let mut file = open_options.open(path)
-        .map_err(|err| format!("Could not create and open file: {}", err))?;
+    let plan = FlashPlan {
+        destination: destination.clone(),
+        size,
+        partitions,
+        partitions_to_format,
+        idbloader,
+        idbloader_raw: opt.idbloader_raw,
+        check_idbloader: !opt.skip_idbloader_check,
+        raw_images,
+        verify: opt.verify,
+        block_size,
+        buffer_count: opt.buffers,
+        lba_size: opt.lba_size,
+        direct: opt.direct,
+        write_retries: opt.retries,
+        retry_delay,
+        max_write_rate,
+        force: opt.force,
+        eject: opt.eject,
+        guid_seed: opt.guid_seed.clone(),
+        sparse_copy: !opt.no_sparse_copy,
+        keep: opt.keep.clone(),
+        bootable: opt.set_bootable.clone(),
+        bootable_pmbr: opt.bootable_pmbr,
+        protective_mbr: !opt.no_pmbr,
+        hybrid_mbr: opt.hybrid_mbr.clone(),
+        table_type: opt.table.into(),
+        append: opt.append,
+        replace: opt.replace.clone(),
+        type_map,
+        wipe: opt.wipe.into(),
+        clear_tail: !opt.no_clear_tail,
+        reserve,
+        fill_partition,
+        grow_partition: opt.auto_grow.clone(),
+        active_slot: opt.active_slot.map(Slot::from),
+        vbmeta_flags,
+    };
+
+    if rockusb && !opt.yes {
+        return Err("There is no device to show for confirmation over rockusb, pass --yes".into())
+    }
+    if !plan.is_empty() {
+        confirm_overwrite(&plan.destination, opt.yes, opt.size_units)?;
+    }
 
-    // Make sure the file is actually 16GB in size
-    file.seek(SeekFrom::Start(size - 1))
-        .map_err(|err| format!("Could not seek into sparse file: {}", err))?;
-    file.write(&[0x00])
-        .map_err(|err| format!("Could not finalize sparse file: {}", err))?;
+    let mut manifest = Manifest::default();
+    report_events(opt.json, opt.size_units, |report| {
+        let on_event = |event: FlashEvent| {
+            manifest.record(&event);
+            report(event)
+        };
+        let summary = match (rockusb, offset) {
+            (true, _) => flash_rockusb(plan, opt.rockusb_loader.as_deref(), on_event),
+            (false, Some(offset)) => flash_at_offset(plan, offset, on_event),
+            (false, None) => rockflasher::flash(plan, on_event),
+        };
+        // The reporter has printed the partition table already
+        summary.map(drop)
+    })?;
+
+    if let Some(manifest_path) = &opt.manifest {
+        manifest.write(manifest_path).map_err(|source| CliError::output(
+            format!(
+                "Failed to write manifest {}", manifest_path.to_str().unwrap_or("<invalid path>")
+            ),
+            source
+        ))?;
+    }
 
     Ok(())
 }
 
-fn erase_beginning(path: PathBuf) -> Result<(), String> {
-    let sp = SpinnerBuilder::new("Erasing beginning of disk".into()).start();
-    let file = open_write_sync(path)
-        .map_err(|err| format!("Could not open file: {}", err))?;
-
-
-    // First we'll erase the first 8 MiB to make sure there are no leftovers of old loaders
-    file.write_at(vec![0_u8; FIRST_PART_ALIGNMENT as usize].as_slice(), 0)
-        .map_err(|err| format!("Failed to erase beginning of disk: {}", err))?;
+/// Prints a partition table like gdisk does, images without an entry have the index 0
+fn print_partition_table(
+    out: &mut dyn Write,
+    partitions: &[PartitionInfo],
+    size_units: SizeUnits,
+) -> io::Result<()> {
+    let name_width = partitions.iter()
+        .map(|partition| partition.name.chars().count())
+        .max().unwrap_or(0).max("Name".len());
+
+    writeln!(
+        out, "{:>3}  {:<name_width$}  {:<36}  {:<36}  {:>12}  {:>12}  {:>10}",
+        "#", "Name", "Type GUID", "Unique GUID", "First LBA", "Last LBA", "Size"
+    )?;
+    for partition in partitions {
+        writeln!(
+            out, "{:>3}  {:<name_width$}  {:<36}  {:<36}  {:>12}  {:>12}  {:>10}",
+            match partition.index {
+                0 => "-".to_string(),
+                index => index.to_string(),
+            },
+            partition.name, partition.type_guid, partition.unique_guid,
+            partition.first_lba, partition.last_lba, size_units.format(partition.size)
+        )?;
+    }
 
-    sp.message("Erased beginning of disk".into());
-    sp.close();
     Ok(())
 }
 
-fn partition_name_to_type(name: String) -> partition_types::Type {
-    match name.as_str() {
-        "system" | "vendor" | "super" | "product" | "odm" => partition_types::ANDROID_SYSTEM,
-        "cache" => partition_types::ANDROID_CACHE,
-        "userdata" => partition_types::ANDROID_DATA,
-        "boot" | "vendor_boot" | "system_dlkm" | "vendor_dlkm" | "odm_dlkm" |
-        "dtb" | "dtbo" | "vbmeta" | "security" | "init_boot" => partition_types::ANDROID_BOOT,
-        "recovery" => partition_types::ANDROID_RECOVERY,
-        "misc" => partition_types::ANDROID_MISC,
-        "metadata" => partition_types::ANDROID_META,
-        "factory" | "backup" => partition_types::ANDROID_FACTORY,
-        "uboot" | "bootloader" | "loader" | "trust" | "idbloader" =>
-            partition_types::ANDROID_BOOTLOADER,
-        "stage2" | "bootloader2" | "loader2" => partition_types::ANDROID_BOOTLOADER2,
-        "fastboot" => partition_types::ANDROID_FASTBOOT,
-        "oem" => partition_types::ANDROID_OEM,
-        "persist" => partition_types::ANDROID_PERSISTENT,
-        _ => partition_types::BASIC
+/// Prints a partition table with everything there is to know about the partitions, like
+/// gdisk -l does
+fn print_disk_info(
+    out: &mut dyn Write,
+    disk_info: &DiskInfo,
+    size_units: SizeUnits,
+) -> io::Result<()> {
+    writeln!(out, "Disk GUID:          {}", disk_info.disk_guid)?;
+    writeln!(out, "Logical block size: {} bytes", disk_info.lba_size)?;
+    writeln!(
+        out, "Usable LBAs:        {} to {}",
+        disk_info.first_usable_lba, disk_info.last_usable_lba
+    )?;
+    writeln!(out)?;
+
+    let partition_types: Vec<&str> = disk_info.partitions.iter()
+        .map(|partition| partition_type_name(&partition.type_guid)
+            .unwrap_or(&partition.type_guid))
+        .collect();
+    let name_width = disk_info.partitions.iter()
+        .map(|partition| partition.name.chars().count())
+        .max().unwrap_or(0).max("Name".len());
+    let type_width = partition_types.iter()
+        .map(|partition_type| partition_type.len())
+        .max().unwrap_or(0).max("Type".len());
+    writeln!(
+        out, "{:>3}  {:<name_width$}  {:<type_width$}  {:<36}  {:>12}  {:>10}  {}",
+        "#", "Name", "Type", "Unique GUID", "First LBA", "Size", "Attributes"
+    )?;
+    for (partition, partition_type) in disk_info.partitions.iter().zip(partition_types) {
+        writeln!(
+            out, "{:>3}  {:<name_width$}  {:<type_width$}  {:<36}  {:>12}  {:>10}  {}",
+            partition.index, partition.name, partition_type, partition.unique_guid,
+            partition.first_lba, size_units.format(partition.size),
+            format_partition_flags(partition.flags)
+        )?;
     }
-}
 
-fn partition_name_to_flags(name: String) -> u64 {
-    match name.as_str() {
-        // it looks like we don't need to set any flags, but maybe we should set 0 and 1 accordingly
-        _ => 0
+    writeln!(out)?;
+    if disk_info.free_regions.is_empty() {
+        return writeln!(out, "No free space")
+    }
+    writeln!(out, "Free space:")?;
+    writeln!(out, "{:>12}  {:>12}  {:>10}", "First LBA", "Last LBA", "Size")?;
+    for region in &disk_info.free_regions {
+        writeln!(
+            out, "{:>12}  {:>12}  {:>10}",
+            region.first_lba, region.last_lba, size_units.format(region.size)
+        )?;
     }
-}
 
-fn write_images(
-    destination: PathBuf,
-    partitions: Vec<CreatedPartition>
-) -> Result<(), String> {
-    eprintln!("Opening {} to write images…", destination.to_str().unwrap());
-    let mut file = OpenOptions::new().read(true).write(true)
-        .custom_flags(
-            if cfg!(unix) {
-                libc::O_SYNC
-            } else {
-                0
-            }
-        )
-        .open(destination.clone())
-        .map_err(|err| format!(
-            "Could not open destination file {} for writing images: {}",
-            destination.to_str().unwrap(), err
-        ))?;
+    Ok(())
+}
 
-    const CLEAR_BYTES: [u8; 1024] = [0; 1024];
-    const BIG_CLEAR_BYTES: [u8; 1024*32] = [0; 1024*32];
+/// Prints the entries of an MBR like fdisk does
+fn print_mbr_table(
+    out: &mut dyn Write,
+    entries: &[MbrEntry],
+    lba_size: u64,
+    size_units: SizeUnits,
+) -> io::Result<()> {
+    let name_width = entries.iter()
+        .filter_map(|entry| entry.name.as_ref().map(|name| name.chars().count()))
+        .max().unwrap_or(0).max("Name".len());
+
+    writeln!(
+        out, "{:>3}  {:<name_width$}  {:>4}  {:>4}  {:>12}  {:>12}  {:>10}",
+        "MBR", "Name", "Boot", "Type", "First LBA", "Last LBA", "Size"
+    )?;
+    for entry in entries {
+        writeln!(
+            out, "{:>3}  {:<name_width$}  {:>4}  {:>4}  {:>12}  {:>12}  {:>10}",
+            entry.index,
+            entry.name.as_deref().unwrap_or(if entry.os_type == 0xEE { "(GPT)" } else { "" }),
+            if entry.bootable { "*" } else { "" },
+            format!("{:02x}", entry.os_type), entry.first_lba, entry.last_lba,
+            size_units.format((entry.last_lba + 1 - entry.first_lba) * lba_size)
+        )?;
+    }
 
-    for partition in partitions {
-        let sp = SpinnerBuilder::new(
-            format!("Preparing partition {}", partition.partition.name)
-        ).start();
-        let partition_start = partition.partition.first_lba * LBA_SIZE;
-
-        // First, clear the first KiB to make sure there is no file system
-        file.write_at(&CLEAR_BYTES, partition_start)
-            .map_err(|err| format!(
-                "Failed to clear filesystem signatures on partition {} at offset {}: {}",
-                partition.partition.name, partition_start, err
-            ))?;
+    Ok(())
+}
 
-        // Both def and def.source_file must be Some, otherwise there's no point
-        // in writing anything. This if statement matches both at the same time.
-        if let Some((def, Some(source_file))) = partition.def.and_then(
-            |def| Some((def.clone(), def.source_file))
-        ) {
-            file.seek(SeekFrom::Start(partition_start))
-                .map_err(|err| format!(
-                    "Could not seek to start of partition {}: {}",
-                    partition.partition.name, err
-                ))?;
+const PROGRESS_TEMPLATE: &'static str =
+    "{msg} [{wide_bar}] {percent:>3}% {binary_bytes}/{binary_total_bytes} \
+    {binary_bytes_per_sec} ETA {eta}";
+const DECIMAL_PROGRESS_TEMPLATE: &'static str =
+    "{msg} [{wide_bar}] {percent:>3}% {decimal_bytes}/{decimal_total_bytes} \
+    {decimal_bytes_per_sec} ETA {eta}";
+const SPINNER_TEMPLATE: &'static str = "{spinner} {msg}";
+const SPINNER_TICK: Duration = Duration::from_millis(100);
+/// How often progress is printed when stderr is not a terminal
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Renders the events of the flashing pipeline on the terminal
+struct Reporter {
+    progress_bar: Option<ProgressBar>,
+    /// Name, offset and size of images without a partition table entry, listed along with it
+    raw_images: Vec<(String, u64, u64)>,
+    /// Space left unpartitioned at the end, listed after the partitions
+    reserved: Option<FreeRegion>,
+    /// Without a terminal, progress is printed as plain lines instead of progress bars
+    is_terminal: bool,
+    /// Message, total size and start of the task in progress, for the plain status lines
+    task: Option<(String, u64, Instant)>,
+    last_status: Instant,
+    size_units: SizeUnits,
+}
 
-            sp.update(format!(
-                "Writing partition {} ({})",
-                partition.partition.name, BinarySize::from(def.size).rounded()
-            ));
+impl Reporter {
+    fn new(size_units: SizeUnits) -> Self {
+        Reporter {
+            progress_bar: None,
+            raw_images: vec![],
+            reserved: None,
+            // Debug messages would tear progress bars apart
+            is_terminal: io::stderr().is_terminal() && !log::log_enabled!(Level::Debug),
+            task: None,
+            last_status: Instant::now(),
+            size_units,
+        }
+    }
 
-            let mut input_file = OpenOptions::new().read(true).open(source_file.clone())
-                .map_err(|err| format!(
-                    "Could not open source file {} to write to {}: {}",
-                    source_file.to_str().unwrap(), partition.partition.name, err
-                ))?;
+    /// Shows a spinner for a task without known size
+    fn start(&mut self, message: String) {
+        self.finish();
+        if !self.is_terminal {
+            return eprintln!("{}…", message)
+        }
+        let spinner = ProgressBar::new_spinner()
+            .with_style(ProgressStyle::with_template(SPINNER_TEMPLATE).unwrap())
+            .with_message(message);
+        spinner.enable_steady_tick(SPINNER_TICK);
+        self.progress_bar = Some(spinner);
+    }
 
-            let bytes_copied = copy(&mut input_file, &mut file)
-                .map_err(|err| format!(
-                    "Failed to write image {} to {} on {}: {}",
-                    source_file.to_str().unwrap(), partition.partition.name,
-                    destination.to_str().unwrap(), err
-                ))?;
+    /// Shows a progress bar for a task of `total` bytes
+    fn start_progress(&mut self, message: String, total: u64) {
+        self.finish();
+        if !self.is_terminal {
+            eprintln!("{} ({})…", message, self.size_units.format(total));
+            self.task = Some((message, total, Instant::now()));
+            self.last_status = Instant::now();
+            return
+        }
+        let progress_bar = ProgressBar::new(total)
+            .with_style(ProgressStyle::with_template(self.size_units.progress_template()).unwrap())
+            .with_message(message);
+        self.progress_bar = Some(progress_bar);
+    }
 
-            let remaining_bytes = partition.partition.bytes_len(LBA)
-                .map_err(|err| format!(
-                    "Unable to calculate remaining bytes for {}: {}",
-                    partition.partition.name, err
-                ))? - bytes_copied;
-
-            if remaining_bytes > 0 {
-                sp.update(format!(
-                    "Clearing rest of partition {} ({})…",
-                    partition.partition.name, BinarySize::from(remaining_bytes).rounded()
-                ));
-
-                let clear_bytes_size = BIG_CLEAR_BYTES.len();
-                let mut clear_bytes: Vec<u8> = BIG_CLEAR_BYTES.into();
-                for offset in (0..remaining_bytes).step_by(clear_bytes_size) {
-                    // This will only actually truncate when the last step is reached
-                    clear_bytes.truncate((remaining_bytes - offset) as usize);
-                    file.write(clear_bytes.as_slice()).map_err(|err| format!(
-                        "Failed to write clear bytes to {} on {}: {}",
-                        partition.partition.name,
-                        destination.to_str().unwrap(), err
-                    ))?;
-                }
+    fn progress(&mut self, position: u64) {
+        if let Some(progress_bar) = &self.progress_bar {
+            return progress_bar.set_position(position)
+        }
+        if let Some((message, total, started)) = &self.task {
+            if self.last_status.elapsed() >= STATUS_INTERVAL {
+                let rate = position as f64 / started.elapsed().as_secs_f64().max(0.001);
+                eprintln!(
+                    "{}: {} of {} ({}%, {}/s)",
+                    message, self.size_units.format(position),
+                    self.size_units.format(*total), position * 100 / (*total).max(1),
+                    self.size_units.format(rate as u64)
+                );
+                self.last_status = Instant::now();
             }
-
-            sp.message(format!(
-                "Successfully wrote {} ({} at {:#x})",
-                partition.partition.name, BinarySize::from(def.size).rounded(),
-                partition_start,
-            ));
-        } else {
-            sp.message(format!("Cleared {}, nothing else to do.", partition.partition.name));
         }
-        sp.close();
     }
 
-    eprintln!("Finished writing all partitions");
-
-    Ok(())
-}
+    /// Prints `message` without ending the current task
+    fn warn(&self, message: String) {
+        match &self.progress_bar {
+            Some(progress_bar) => progress_bar.suspend(|| eprintln!("{}", message)),
+            None => eprintln!("{}", message),
+        }
+    }
 
-fn format_partitions(
-    destination: PathBuf,
-    partitions_to_format: Vec<FormatPartitionDefinition>
-) -> Result<(), String>  {
-    if partitions_to_format.is_empty() {
-        return Ok(())
+    /// Ends the current task with `message`
+    fn message(&mut self, message: String) {
+        if let Some(progress_bar) = self.progress_bar.take() {
+            progress_bar.finish_and_clear();
+        }
+        self.task = None;
+        eprintln!("{}", message);
     }
-    if !cfg!(target_os = "linux") {
-        return Err(format!("Creating filesystems is unsupported on {}", cfg!(target_os)));
+
+    fn finish(&mut self) {
+        if let Some(progress_bar) = self.progress_bar.take() {
+            progress_bar.abandon();
+        }
+        self.task = None;
     }
 
-    eprintln!("Probing partitions");
-    let output = Command::new("partprobe")
-        .output()
-        .or_else(|e| {
-            eprintln!("Failed to run partprobe: {}", e);
-            Err(e)
-        })
-        .ok();
-    if let Some(output) = output {
-        if !output.status.success() {
-            eprintln!(
-                "WARNING: partprobe failed:\n{}\n{}",
-                String::from_utf8_lossy(output.stdout.as_slice()),
-                String::from_utf8_lossy(output.stderr.as_slice())
-            )
+    fn report(&mut self, event: FlashEvent) {
+        match event {
+            FlashEvent::NothingToFlash =>
+                eprintln!("No partitions specified, nothing to flash, skipping."),
+            FlashEvent::Destination { path, size, .. } => eprintln!(
+                "Destination: {} ({})", path.to_str().unwrap(), self.size_units.format(size)
+            ),
+            FlashEvent::VerifyingChecksum { name } =>
+                self.start(format!("Verifying checksum of {}", name)),
+            FlashEvent::ChecksumVerified { name } =>
+                self.message(format!("Checksum of {} matches", name)),
+            FlashEvent::Unmounting { source, mount_point } => eprintln!(
+                "Unmounting {} from {}…", source, mount_point.to_string_lossy()
+            ),
+            FlashEvent::Unmounted { mount_point } =>
+                eprintln!("Unmounted {}", mount_point.to_string_lossy()),
+            FlashEvent::UnmountFailed { mount_point, message } => eprintln!(
+                "WARNING: Failed to unmount {}: {}", mount_point.to_string_lossy(), message
+            ),
+            FlashEvent::ErasingBeginning { size } =>
+                self.start_progress("Erasing beginning of disk".into(), size),
+            FlashEvent::EraseProgress { erased } => self.progress(erased),
+            FlashEvent::ErasedBeginning => self.message("Erased beginning of disk".into()),
+            FlashEvent::WipingDestination { size } =>
+                self.start_progress("Wiping destination".into(), size),
+            FlashEvent::WipeProgress { wiped } => self.progress(wiped),
+            FlashEvent::DestinationWiped => self.message("Wiped destination".into()),
+            FlashEvent::CreatingProtectiveMbr => eprintln!("Creating protective MBR…"),
+            FlashEvent::SkippingProtectiveMbr => eprintln!(
+                "WARNING: Not writing a protective MBR, tools that expect one may not \
+                recognize the partition table"
+            ),
+            FlashEvent::AddingPartition { name, size } => eprintln!(
+                "Adding partition {}, size {}", name, self.size_units.format(size)
+            ),
+            FlashEvent::AddingRawImage { name, offset, size } => {
+                eprintln!(
+                    "Adding {} at {:#x} without a partition, size {}",
+                    name, offset, self.size_units.format(size)
+                );
+                self.raw_images.push((name, offset, size));
+            },
+            FlashEvent::KeepingPartition { name, first_lba, last_lba } => eprintln!(
+                "Keeping partition {} (LBA {}–{})", name, first_lba, last_lba
+            ),
+            FlashEvent::ReservingSpace { size, first_lba, last_lba } => {
+                eprintln!(
+                    "Reserving {} at the end of the disk (LBA {}–{})",
+                    self.size_units.format(size), first_lba, last_lba
+                );
+                self.reserved = Some(FreeRegion { first_lba, last_lba, size });
+            },
+            FlashEvent::AddingFillPartition { name, size } => eprintln!(
+                "Creating {} partition in the remaining space, size {}",
+                name, self.size_units.format(size)
+            ),
+            FlashEvent::GrowingPartition { name, size } => eprintln!(
+                "Growing partition {} into the remaining space, size {}",
+                name, self.size_units.format(size)
+            ),
+            FlashEvent::LeavingFreeSpace { size } => eprintln!(
+                "Leaving {} free at the end", self.size_units.format(size)
+            ),
+            FlashEvent::DeletingPartition { name, first_lba, last_lba } => eprintln!(
+                "Deleting partition {} (LBA {}–{})", name, first_lba, last_lba
+            ),
+            FlashEvent::ResizingPartition { name, size, new_size } => eprintln!(
+                "Resizing partition {} from {} to {}",
+                name, self.size_units.format(size), self.size_units.format(new_size)
+            ),
+            FlashEvent::MarkingBootable { name } =>
+                eprintln!("Marking partition {} as bootable", name),
+            FlashEvent::WritingPartitionTable => eprintln!("Writing partition table…"),
+            FlashEvent::PartitionTableWritten { lba_size, partitions, .. } => {
+                self.finish();
+                let raw_images = self.raw_images.iter().map(|(name, offset, size)| PartitionInfo {
+                    index: 0,
+                    name: name.clone(),
+                    type_guid: "(raw image)".into(),
+                    unique_guid: String::new(),
+                    first_lba: offset / lba_size,
+                    last_lba: (offset + size) / lba_size - 1,
+                    size: *size,
+                    flags: 0,
+                });
+                let reserved = self.reserved.iter().map(|region| PartitionInfo {
+                    index: 0,
+                    name: "(reserved)".into(),
+                    type_guid: String::new(),
+                    unique_guid: String::new(),
+                    first_lba: region.first_lba,
+                    last_lba: region.last_lba,
+                    size: region.size,
+                    flags: 0,
+                });
+                let partitions: Vec<PartitionInfo> = raw_images.chain(partitions)
+                    .chain(reserved)
+                    .collect();
+                // Nothing to do about it if stderr is gone
+                let _ = print_partition_table(&mut io::stderr(), &partitions, self.size_units);
+            },
+            FlashEvent::MbrPartitionTableWritten { disk_signature, lba_size, entries } => {
+                self.finish();
+                eprintln!("Disk signature: {}", disk_signature);
+                // Nothing to do about it if stderr is gone
+                let _ = print_mbr_table(&mut io::stderr(), &entries, lba_size, self.size_units);
+            },
+            FlashEvent::HybridMbrWritten { lba_size, entries } => {
+                eprintln!("Replaced protective MBR by a hybrid MBR:");
+                // Nothing to do about it if stderr is gone
+                let _ = print_mbr_table(&mut io::stderr(), &entries, lba_size, self.size_units);
+            },
+            FlashEvent::DirectIoUnsupported { path } => eprintln!(
+                "WARNING: {} doesn't support O_DIRECT, writing through the page cache",
+                path.to_string_lossy()
+            ),
+            FlashEvent::WritingPartition { name, size: Some(size) } => self.start_progress(
+                format!("Writing partition {}", name), size
+            ),
+            FlashEvent::WritingPartition { name, size: None } =>
+                self.start(format!("Preparing partition {}", name)),
+            FlashEvent::WriteProgress { written, .. } => self.progress(written),
+            FlashEvent::RetryingWrite { name, offset, retries_left, message } => self.warn(format!(
+                "WARNING: Writing partition {} at offset {:#x} failed ({}), retrying \
+                ({} retries left)", name, offset, message, retries_left
+            )),
+            FlashEvent::ClearingPartition { name, remaining } =>
+                self.start_progress(format!("Clearing rest of partition {}", name), remaining),
+            FlashEvent::ClearProgress { cleared, .. } => self.progress(cleared),
+            FlashEvent::SyncingPartition { name } =>
+                self.start(format!("Flushing partition {} to disk", name)),
+            FlashEvent::PartitionWritten { name, written, offset, .. } => self.message(format!(
+                "Successfully wrote {} ({} at {:#x})",
+                name, self.size_units.format(written), offset
+            )),
+            FlashEvent::PartitionCleared { name } =>
+                self.message(format!("Cleared {}, nothing else to do.", name)),
+            FlashEvent::WipingPartition { name, size } =>
+                self.start_progress(format!("Wiping deleted partition {}", name), size),
+            FlashEvent::PartitionWiped { name } =>
+                self.message(format!("Wiped the data of deleted partition {}", name)),
+            FlashEvent::WriteFinished => eprintln!("Finished writing all partitions"),
+            FlashEvent::VerifyingPartition { name, size } =>
+                self.start_progress(format!("Verifying partition {}", name), size),
+            FlashEvent::VerifyProgress { verified, .. } => self.progress(verified),
+            FlashEvent::PartitionVerified { name, verified } => self.message(format!(
+                "Verified {} ({}): OK", name, self.size_units.format(verified)
+            )),
+            FlashEvent::PartitionVerifyFailed { name, mismatch } => self.message(match mismatch {
+                Mismatch::Byte { offset, expected, actual } => format!(
+                    "Verified {}: FAILED at offset {:#x}, expected {:#04x}, read back {:#04x}",
+                    name, offset, expected, actual
+                ),
+                Mismatch::Checksum { expected, actual } => format!(
+                    "Verified {}: FAILED, expected {} {}, read back {}",
+                    name, expected.algorithm(), expected, actual
+                ),
+            }),
+            FlashEvent::PartitionMissing { name } =>
+                eprintln!("Verified {}: FAILED, the partition doesn't exist", name),
+            FlashEvent::VerifyFinished => eprintln!("Finished verifying all partitions"),
+            FlashEvent::ErasingFlash { size } =>
+                eprintln!("Erasing {} of flash…", self.size_units.format(size)),
+            FlashEvent::WritingSpiImage { size } =>
+                eprintln!("Writing {} SPI flash image…", self.size_units.format(size)),
+            FlashEvent::SpreadingIdbloader =>
+                eprintln!("IDBloader is laid out for SD cards, spreading it out for SPI flash"),
+            FlashEvent::DownloadingLoader { path } =>
+                eprintln!("Downloading loader {}…", path.to_string_lossy()),
+            FlashEvent::WritingRockusb { size } =>
+                self.start_progress("Writing over rockusb".into(), size),
+            FlashEvent::RockusbProgress { written } => self.progress(written),
+            FlashEvent::RockusbWritten => self.message("Written over rockusb".into()),
+            FlashEvent::ResettingDevice => eprintln!("Resetting the board…"),
+            FlashEvent::WritingAtOffset { offset, size } =>
+                self.start_progress(format!("Writing the region at {:#x}", offset), size),
+            FlashEvent::OffsetProgress { written } => self.progress(written),
+            FlashEvent::OffsetWritten => self.message("Written the region".into()),
+            FlashEvent::FlushingDestination { path } =>
+                eprintln!("Flushing caches of {}…", path.to_string_lossy()),
+            FlashEvent::RetryingFlush { path, retries_left, message } => eprintln!(
+                "WARNING: Flushing {} failed ({}), retrying ({} retries left)",
+                path.to_string_lossy(), message, retries_left
+            ),
+            FlashEvent::Ejecting { path } => eprintln!("Ejecting {}…", path.to_string_lossy()),
+            FlashEvent::Ejected { path } =>
+                eprintln!("{} can be removed now", path.to_string_lossy()),
+            FlashEvent::FlashComplete => eprintln!("Flash complete."),
+            FlashEvent::ProbingPartitions => eprintln!("Probing partitions"),
+            FlashEvent::ProbeFailed { message } =>
+                eprintln!("WARNING: {}", message),
+            FlashEvent::WaitingForDevice { path } =>
+                eprintln!("Waiting for device {}…", path.to_string_lossy()),
+            FlashEvent::Formatting { name, filesystem, part_uuid } => eprintln!(
+                "Formatting {} as {} (PARTUUID={})", name, filesystem, part_uuid
+            ),
+            FlashEvent::LabelTruncated { name, filesystem, label } => eprintln!(
+                "WARNING: Partition name {} is too long for a {} label, using {}",
+                name, filesystem, label
+            ),
+            FlashEvent::PartitionFormatted { name, filesystem } =>
+                eprintln!("Formatted {} as {}", name, filesystem),
+            FlashEvent::Populating { name, source } => eprintln!(
+                "Copying {} onto {}…", source.to_string_lossy(), name
+            ),
+            FlashEvent::PartitionPopulated { name } => eprintln!("Populated {}", name),
+            FlashEvent::MkfsOutput { filesystem, status, stdout, stderr } => {
+                eprintln!("mkfs.{} exited with status code {}. Output:", filesystem, status);
+                eprintln!("{}", stdout);
+                eprintln!("{}", stderr);
+            },
         }
     }
-    sleep(Duration::from_millis(500));
+}
 
-    eprintln!("Starting format, partition count: {}", partitions_to_format.len());
+/// Final result of a run in JSON mode
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    event: &'static str,
+    success: bool,
+    error: Option<String>,
+    partitions: &'a [PartitionInfo],
+    raw_images: &'a [RawImageInfo],
+    reserved: Option<&'a FreeRegion>,
+}
 
-    let cfg = gpt::GptConfig::new()
-        .initialized(true)
-        .writable(false)
-        .logical_block_size(LBA);
+/// Image written outside of the partitions, like U-Boot at sector 0x4000
+#[derive(Serialize)]
+struct RawImageInfo {
+    name: String,
+    offset: u64,
+    size: u64,
+}
 
-    eprintln!("Opening {}…", destination.to_str().unwrap());
-    let disk = cfg.open(destination.clone())
-        .map_err(|err| format!(
-            "Failed to open file {} for reading partition table: {}",
-            destination.to_str().unwrap(), err
-        ))?;
+/// Prints the events of the flashing pipeline as JSON lines on stdout
+#[derive(Default)]
+struct JsonReporter {
+    partitions: Vec<PartitionInfo>,
+    raw_images: Vec<RawImageInfo>,
+    reserved: Option<FreeRegion>,
+}
 
-    for partition_to_format in partitions_to_format {
-        let (_, gpt_part) = disk.partitions().iter().find(
-            |(_, part)| part.name == partition_to_format.partition_name
-        ).ok_or_else(|| format!(
-            "Could not find partition {} to format as {}",
-            partition_to_format.partition_name, partition_to_format.format_as
-        ))?;
-        let part_uuid = gpt_part.part_guid;
-        eprintln!(
-            "Formatting {} as {} (PARTUUID={})",
-            gpt_part.name,
-            partition_to_format.format_as,
-            part_uuid
-        );
-        let device = format!("/dev/disk/by-partuuid/{}", part_uuid.to_string());
-        wait_for_device(
-            PathBuf::from(device.clone()),
-            20, Duration::from_millis(250)
-        )?;
-        let output = run_mkfs(device, partition_to_format.format_as.clone())
-            .map_err(|e| format!(
-                "Failed to run mkfs.{} on partition {} (PARTUUID={}): {}",
-                partition_to_format.format_as,
-                gpt_part.name,
-                part_uuid.to_string(),
-                e
-            ))?;
-        if !output.status.success() {
-            eprintln!(
-                "mkfs.{} exited with status code {}. Output:",
-                partition_to_format.format_as,
-                output.status.code().unwrap_or(-1)
-            );
-            eprintln!("{}", String::from_utf8_lossy(output.stdout.as_slice()));
-            eprintln!("{}", String::from_utf8_lossy(output.stderr.as_slice()));
-            return Err(format!(
-                "Failed to format partition {} (PARTUUID={}) using mkfs.{}:\n{}\n{}",
-                gpt_part.name,
-                part_uuid.to_string(),
-                partition_to_format.format_as,
-                String::from_utf8_lossy(output.stdout.as_slice()),
-                String::from_utf8_lossy(output.stderr.as_slice()),
-            ))
+impl JsonReporter {
+    fn report(&mut self, event: FlashEvent) {
+        match &event {
+            // Progress is only interesting while watching
+            FlashEvent::EraseProgress { .. } | FlashEvent::WipeProgress { .. } |
+            FlashEvent::WriteProgress { .. } | FlashEvent::ClearProgress { .. } |
+            FlashEvent::VerifyProgress { .. } | FlashEvent::RockusbProgress { .. } |
+            FlashEvent::OffsetProgress { .. } => return,
+            FlashEvent::ReservingSpace { size, first_lba, last_lba } =>
+                self.reserved = Some(FreeRegion {
+                    first_lba: *first_lba,
+                    last_lba: *last_lba,
+                    size: *size,
+                }),
+            FlashEvent::AddingRawImage { name, offset, size } =>
+                self.raw_images.push(RawImageInfo {
+                    name: name.clone(),
+                    offset: *offset,
+                    size: *size,
+                }),
+            FlashEvent::PartitionTableWritten { partitions, .. } =>
+                self.partitions = partitions.clone(),
+            FlashEvent::MbrPartitionTableWritten { disk_signature, lba_size, entries } =>
+                self.partitions = entries.iter()
+                    .map(|entry| entry.partition_info(disk_signature, *lba_size))
+                    .collect(),
+            _ => {},
         }
+        print_json(&event);
     }
 
-    Ok(())
+    fn summary(&self, result: &Result<(), FlashError>) {
+        print_json(&JsonSummary {
+            event: "summary",
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+            partitions: &self.partitions,
+            raw_images: &self.raw_images,
+            reserved: self.reserved.as_ref(),
+        });
+    }
 }
 
-fn wait_for_device(device: PathBuf, retries: u32, retry_interval: Duration) -> Result<(), String> {
-    let mut tried = 0;
-    while !(device.exists() &&
-        (device.is_file() || device.is_symlink()) && device.read_link().is_ok()) {
-        if retries == tried {
-            return Err(format!(
-                "Timed out waiting for device {}, retries: {}",
-                device.to_string_lossy(),
-                tried
-            ))
-        }
-        if tried == 0 {
-            eprintln!("Waiting for device {}…", device.to_string_lossy())
-        }
-        tried += 1;
-        sleep(retry_interval)
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string(value) {
+        Ok(line) => println!("{}", line),
+        // Paths that aren't valid UTF-8 can't be represented
+        Err(err) => log::error!("Failed to print JSON: {}", err),
     }
+}
+
+fn confirm_destination(
+    destination: PathBuf,
+    size: u64,
+    size_units: SizeUnits,
+) -> Result<(), FlashError> {
+    let device_name = destination.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    if !io::stdin().is_terminal() {
+        return Err(FlashError::NotConfirmed { path: destination })
+    }
+
+    let device = get_device_info(destination.clone())
+        .map_err(|err| FlashError::DeviceInfo {
+            path: destination.clone(),
+            message: err.to_string(),
+        })?;
+    // block_utils doesn't know about the model, so ask sysfs directly
+    let model = std::fs::read_to_string(format!("/sys/class/block/{}/device/model", device.name))
+        .map(|model| model.trim().to_string())
+        .unwrap_or_else(|_| "unknown".into());
+
+    eprintln!("WARNING: All data on the following device will be destroyed:");
+    eprintln!("  Device: {}", destination.to_str().unwrap_or("<invalid path>"));
+    eprintln!("  Size:   {}", size_units.format(size));
+    eprintln!("  Model:  {}", model);
+    eprintln!("  Serial: {}", device.serial_number.unwrap_or_else(|| "unknown".into()));
+    eprint!("Type 'yes' or '{}' to continue: ", device_name);
+    io::stderr().flush().map_err(FlashError::Prompt)?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).map_err(FlashError::Prompt)?;
+
+    let answer = answer.trim();
+    if answer != "yes" && answer != device_name {
+        return Err(FlashError::Aborted)
+    }
+
     Ok(())
 }
 
-fn run_mkfs(device: String, fs: String) -> io::Result<Output> {
-    Command::new(format!("mkfs.{}", fs))
-        .arg(device)
-        .output()
-}
\ No newline at end of file
@@ -0,0 +1,112 @@
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::checksum::Checksum;
+use crate::event::FlashEvent;
+use crate::table::PartitionInfo;
+
+/// Record of everything written to a destination, to check a deployed device against later
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    /// Version of rockflasher that wrote the destination
+    pub tool_version: String,
+    pub destination: PathBuf,
+    /// Disk GUID, or the disk signature of a DOS partition table
+    pub disk_guid: String,
+    /// Logical block size the partition table was written with
+    pub lba_size: u64,
+    pub partitions: Vec<ManifestPartition>,
+}
+
+/// A partition of the written partition table and the source image written to it, if any
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ManifestPartition {
+    #[serde(flatten)]
+    pub partition: PartitionInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_file: Option<PathBuf>,
+    /// Checksum of the data written, which is what the source decompresses to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<Checksum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_written: Option<u64>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            tool_version: env!("CARGO_PKG_VERSION").into(),
+            destination: PathBuf::new(),
+            disk_guid: String::new(),
+            lba_size: 512,
+            partitions: vec![],
+        }
+    }
+}
+
+impl Manifest {
+    /// Collects what has been written from the events of [crate::flash]
+    pub fn record(&mut self, event: &FlashEvent) {
+        match event {
+            FlashEvent::Destination { path, .. } => self.destination = path.clone(),
+            FlashEvent::PartitionTableWritten { disk_guid, lba_size, partitions } => {
+                self.disk_guid = disk_guid.clone();
+                self.lba_size = *lba_size;
+                self.partitions = partitions.iter()
+                    .map(|partition| ManifestPartition {
+                        partition: partition.clone(),
+                        source_file: None,
+                        sha256: None,
+                        bytes_written: None,
+                    })
+                    .collect();
+            },
+            FlashEvent::MbrPartitionTableWritten { disk_signature, lba_size, entries } => {
+                self.disk_guid = disk_signature.clone();
+                self.lba_size = *lba_size;
+                self.partitions = entries.iter()
+                    .map(|entry| ManifestPartition {
+                        partition: entry.partition_info(disk_signature, *lba_size),
+                        source_file: None,
+                        sha256: None,
+                        bytes_written: None,
+                    })
+                    .collect();
+            },
+            FlashEvent::PartitionWritten { name, written, source_file, sha256, .. } => {
+                let partition = self.partitions.iter_mut()
+                    .find(|partition| partition.partition.name == *name);
+                if let Some(partition) = partition {
+                    partition.source_file = Some(source_file.clone());
+                    partition.sha256 = Some(sha256.clone());
+                    partition.bytes_written = Some(*written);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Reads a manifest written by [Manifest::write]
+    pub fn read(path: impl AsRef<Path>) -> Result<Manifest, String> {
+        let path = path.as_ref();
+        let path_str = path.to_str().unwrap_or("<invalid path>");
+        let contents = read_to_string(path)
+            .map_err(|err| format!("Could not read manifest {}: {}", path_str, err))?;
+        match path.extension().is_some_and(|extension| extension == "toml") {
+            true => toml::from_str(&contents).map_err(|err| err.to_string()),
+            false => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+        }.map_err(|err| format!("Invalid manifest {}: {}", path_str, err))
+    }
+
+    /// Writes the manifest to `path`, as TOML if it ends in .toml and as JSON otherwise
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let contents = match path.extension().is_some_and(|extension| extension == "toml") {
+            true => toml::to_string_pretty(self)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            false => serde_json::to_string_pretty(self)? + "\n",
+        };
+        write(path, contents)
+    }
+}
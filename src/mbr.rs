@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use gpt::disk::LogicalBlockSize;
+use gpt::mbr::{PartRecord, ProtectiveMBR};
+use gpt::partition::Partition;
+use gpt::partition_types;
+use serde::Serialize;
+use crate::error::FlashError;
+use crate::table::PartitionInfo;
+
+/// Number of entries in an MBR, there is no room for more primary partitions
+pub const MAX_MBR_PARTITIONS: usize = 4;
+/// One of the entries is needed for the protective entry
+pub const MAX_HYBRID_PARTITIONS: usize = MAX_MBR_PARTITIONS - 1;
+
+const PROTECTIVE_TYPE: u8 = 0xEE;
+const BOOT_INDICATOR: u8 = 0x80;
+
+// Geometry gdisk uses to make up CHS addresses, disks haven't had a real one for decades
+const HEADS: u64 = 255;
+const SECTORS_PER_TRACK: u64 = 63;
+const MAX_CYLINDER: u64 = 1023;
+
+/// A GPT partition to mirror in a hybrid MBR
+#[derive(Clone, Debug)]
+pub struct HybridMbrPartition {
+    pub name: String,
+    /// MBR partition type, derived from the GPT type if not given
+    pub os_type: Option<u8>,
+}
+
+/// An entry of the MBR in LBA 0
+#[derive(Clone, Debug, Serialize)]
+pub struct MbrEntry {
+    /// Number of the entry, 1 to 4
+    pub index: usize,
+    /// The GPT partition the entry mirrors, if it's known
+    pub name: Option<String>,
+    pub os_type: u8,
+    pub bootable: bool,
+    pub first_lba: u64,
+    pub last_lba: u64,
+}
+
+impl MbrEntry {
+    /// Describes the entry like a GPT partition. Its type is the MBR type in hex and its
+    /// unique GUID the PARTUUID Linux derives from the disk signature.
+    pub fn partition_info(&self, disk_signature: &str, lba_size: u64) -> PartitionInfo {
+        PartitionInfo {
+            index: self.index as u32,
+            name: self.name.clone().unwrap_or_default(),
+            type_guid: format!("{:02X}", self.os_type),
+            unique_guid: format!("{}-{:02x}", disk_signature, self.index),
+            first_lba: self.first_lba,
+            last_lba: self.last_lba,
+            size: (self.last_lba + 1 - self.first_lba) * lba_size,
+            flags: 0,
+        }
+    }
+}
+
+/// MBR type for a GPT partition type, like gdisk suggests them. Basic data partitions usually
+/// hold a FAT filesystem on the boards this is for, so they become FAT32 (LBA) partitions.
+fn mbr_type(partition_type: &partition_types::Type) -> u8 {
+    match *partition_type {
+        partition_types::EFI => 0xEF,
+        partition_types::BASIC => 0x0C,
+        partition_types::LINUX_SWAP => 0x82,
+        partition_types::LINUX_LVM => 0x8E,
+        partition_types::LINUX_RAID => 0xFD,
+        _ => 0x83,
+    }
+}
+
+/// Encodes an LBA as head, sector and cylinder bytes like gdisk does, addresses beyond what CHS
+/// can express are clamped to the largest one
+fn chs(lba: u64) -> (u8, u8, u8) {
+    let cylinder = lba / (HEADS * SECTORS_PER_TRACK);
+    if cylinder > MAX_CYLINDER {
+        return (0xFE, 0xFF, 0xFF)
+    }
+    let head = (lba / SECTORS_PER_TRACK) % HEADS;
+    let sector = lba % SECTORS_PER_TRACK + 1;
+
+    (head as u8, (sector | ((cylinder >> 2) & 0xC0)) as u8, (cylinder & 0xFF) as u8)
+}
+
+fn part_record(entry: &MbrEntry) -> PartRecord {
+    let (start_head, start_sector, start_track) = chs(entry.first_lba);
+    let (end_head, end_sector, end_track) = chs(entry.last_lba);
+    PartRecord {
+        boot_indicator: if entry.bootable { BOOT_INDICATOR } else { 0 },
+        start_head,
+        start_sector,
+        start_track,
+        os_type: entry.os_type,
+        end_head,
+        end_sector,
+        end_track,
+        // Checked to fit when the entries are created
+        lb_start: entry.first_lba as u32,
+        lb_size: (entry.last_lba + 1 - entry.first_lba) as u32,
+    }
+}
+
+/// Creates the MBR entry mirroring `partition`, which must be addressable using 32 bit LBAs
+fn mbr_entry(
+    index: usize,
+    partition: &Partition,
+    os_type: Option<u8>,
+    bootable: &[String],
+) -> Result<MbrEntry, FlashError> {
+    if partition.last_lba > u64::from(u32::MAX) {
+        return Err(FlashError::MbrEntryInvalid {
+            partition: partition.name.clone(),
+            message: "it ends beyond what an MBR can address".into(),
+        })
+    }
+
+    Ok(MbrEntry {
+        index,
+        name: Some(partition.name.clone()),
+        os_type: os_type.unwrap_or_else(|| mbr_type(&partition.part_type_guid)),
+        bootable: bootable.contains(&partition.name),
+        first_lba: partition.first_lba,
+        last_lba: partition.last_lba,
+    })
+}
+
+/// Creates the entries of a DOS partition table, which only has room for 4 primary partitions.
+/// The used partitions are numbered from 1 in the order of the partition table, which closes
+/// gaps in its numbering, and their type is derived from their GPT type.
+pub(crate) fn mbr_entries(
+    partitions: &BTreeMap<u32, Partition>,
+    bootable: &[String],
+) -> Result<Vec<MbrEntry>, FlashError> {
+    let used_partitions: Vec<&Partition> = partitions.values()
+        .filter(|partition| partition.is_used())
+        .collect();
+    if let Some(partition) = used_partitions.get(MAX_MBR_PARTITIONS) {
+        return Err(FlashError::MbrEntryInvalid {
+            partition: partition.name.clone(),
+            message: "at most 4 primary partitions fit".into(),
+        })
+    }
+
+    used_partitions.into_iter()
+        .enumerate()
+        .map(|(index, partition)| mbr_entry(index + 1, partition, None, bootable))
+        .collect()
+}
+
+/// Creates the entries of a hybrid MBR for `hybrid` partitions of the partition table.
+///
+/// Like gdisk does by default, the protective entry comes last and covers everything from LBA 1
+/// up to the first partition, which includes the GPT.
+pub(crate) fn hybrid_mbr_entries(
+    partitions: &BTreeMap<u32, Partition>,
+    hybrid: &[HybridMbrPartition],
+    bootable: &[String],
+) -> Result<Vec<MbrEntry>, FlashError> {
+    if let Some(hybrid_partition) = hybrid.get(MAX_HYBRID_PARTITIONS) {
+        return Err(FlashError::MbrEntryInvalid {
+            partition: hybrid_partition.name.clone(),
+            message: "at most 3 partitions fit next to the protective entry".into(),
+        })
+    }
+
+    let mut entries = vec![];
+    for hybrid_partition in hybrid {
+        let name = hybrid_partition.name.as_str();
+        let partition = partitions.values()
+            .find(|partition| partition.is_used() && partition.name == name)
+            .ok_or_else(|| FlashError::PartitionNotFound { name: name.into() })?;
+        entries.push(mbr_entry(entries.len() + 1, partition, hybrid_partition.os_type, bootable)?);
+    }
+
+    let first_partition_lba = partitions.values()
+        .filter(|partition| partition.is_used())
+        .map(|partition| partition.first_lba)
+        .min()
+        .unwrap_or(2);
+    entries.push(MbrEntry {
+        index: entries.len() + 1,
+        name: None,
+        os_type: PROTECTIVE_TYPE,
+        bootable: false,
+        first_lba: 1,
+        last_lba: first_partition_lba - 1,
+    });
+
+    Ok(entries)
+}
+
+/// Writes an MBR with `entries` to LBA 0
+pub(crate) fn write_mbr(
+    file: &mut File,
+    entries: &[MbrEntry],
+    disk_signature: u32,
+) -> std::io::Result<()> {
+    let mut mbr = ProtectiveMBR::new();
+    mbr.set_disk_signature(disk_signature.to_le_bytes());
+    for index in 0..4 {
+        let record = entries.get(index).map_or_else(PartRecord::zero, part_record);
+        mbr.set_partition(index, record);
+    }
+    mbr.overwrite_lba0(file)?;
+
+    Ok(())
+}
+
+/// Reads the entries of the MBR in LBA 0 of a disk or image file
+pub fn read_mbr(
+    path: impl AsRef<Path>,
+    lba: LogicalBlockSize,
+) -> Result<Vec<MbrEntry>, FlashError> {
+    let path = path.as_ref();
+    let mut file = File::open(path)
+        .map_err(|source| FlashError::GptRead { path: path.into(), source })?;
+    let mbr = ProtectiveMBR::from_disk(&mut file, lba)
+        .map_err(|source| FlashError::GptRead { path: path.into(), source })?;
+
+    Ok((0..4)
+        .filter_map(|index| Some((index, mbr.partition(index)?)))
+        .filter(|(_, record)| record.os_type != 0)
+        .map(|(index, record)| MbrEntry {
+            index: index + 1,
+            name: None,
+            os_type: record.os_type,
+            bootable: record.boot_indicator & BOOT_INDICATOR != 0,
+            first_lba: u64::from(record.lb_start),
+            last_lba: (u64::from(record.lb_start) + u64::from(record.lb_size)).saturating_sub(1),
+        })
+        .collect())
+}
+
+/// Parses a partition for the hybrid MBR, a name optionally followed by a hex MBR type,
+/// e.g. boot:0c
+pub fn parse_hybrid_mbr_partition(partition: &str) -> Result<HybridMbrPartition, String> {
+    let (name, os_type) = match partition.split_once(':') {
+        Some((name, os_type)) => {
+            let hex = os_type.strip_prefix("0x").unwrap_or(os_type);
+            let os_type = u8::from_str_radix(hex, 16)
+                .map_err(|err| format!("Invalid MBR partition type {}: {}", os_type, err))?;
+            if os_type == 0 || os_type == PROTECTIVE_TYPE {
+                return Err(format!("MBR partition type {:#04x} can't be used", os_type))
+            }
+            (name, Some(os_type))
+        },
+        None => (partition, None),
+    };
+    if name.is_empty() {
+        return Err("Partition name must not be empty".into())
+    }
+
+    Ok(HybridMbrPartition { name: name.into(), os_type })
+}
@@ -0,0 +1,200 @@
+use std::fs::{metadata, read_dir, read_to_string};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// A mounted filesystem
+#[derive(Clone, Debug)]
+pub struct Mount {
+    /// Device as listed in the mount table, e.g. /dev/sdb1
+    pub source: String,
+    pub mount_point: PathBuf,
+}
+
+/// A block device, identified by its device number ("major:minor") and name
+struct BlockDevice {
+    dev: String,
+    name: String,
+    /// Number of the partition, if it is one
+    partition: Option<u32>,
+}
+
+/// Mount points whose devices hold the running system
+const SYSTEM_MOUNT_POINTS: [&str; 3] = ["/", "/boot", "/home"];
+
+/// An entry of /proc/self/mountinfo
+struct MountInfo {
+    /// Device number ("major:minor")
+    dev: String,
+    source: String,
+    mount_point: PathBuf,
+}
+
+/// Returns the device number ("major:minor") of the block device at `path`
+fn device_number(path: &Path) -> io::Result<String> {
+    let rdev = metadata(path)?.rdev();
+    Ok(format!("{}:{}", libc::major(rdev), libc::minor(rdev)))
+}
+
+/// Returns the block device at `path` and all of its partitions.
+///
+/// A partition device has no partitions of its own, so only the partition itself is returned.
+fn device_and_partitions(path: &Path) -> io::Result<Vec<BlockDevice>> {
+    let dev = device_number(path)?;
+    let sysfs_dir = Path::new("/sys/dev/block").join(&dev).canonicalize()?;
+
+    let mut devices = vec![BlockDevice {
+        dev,
+        name: sysfs_dir.file_name().unwrap_or_default().to_string_lossy().into(),
+        partition: None,
+    }];
+    for entry in read_dir(&sysfs_dir)? {
+        let entry = entry?;
+        let Ok(partition) = read_to_string(entry.path().join("partition")) else {
+            continue
+        };
+        devices.push(BlockDevice {
+            dev: read_to_string(entry.path().join("dev"))?.trim().into(),
+            name: entry.file_name().to_string_lossy().into(),
+            partition: partition.trim().parse().ok(),
+        });
+    }
+
+    Ok(devices)
+}
+
+/// Undoes the octal escaping of spaces and other special characters in the mount table
+fn unescape_mount_field(field: &str) -> String {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes.get(index + 1..index + 4)
+            .filter(|_| bytes[index] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match escaped {
+            Some(byte) => {
+                unescaped.push(byte);
+                index += 4;
+            },
+            None => {
+                unescaped.push(bytes[index]);
+                index += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into()
+}
+
+fn read_mountinfo() -> io::Result<Vec<MountInfo>> {
+    let mountinfo = read_to_string("/proc/self/mountinfo")?;
+
+    let mut entries = vec![];
+    for line in mountinfo.lines() {
+        // https://www.kernel.org/doc/Documentation/filesystems/proc.txt, section 3.5
+        let Some((mount_fields, source_fields)) = line.split_once(" - ") else {
+            continue
+        };
+        let mount_fields: Vec<&str> = mount_fields.split(' ').collect();
+        let (Some(dev), Some(mount_point), Some(source)) =
+            (mount_fields.get(2), mount_fields.get(4), source_fields.split(' ').nth(1)) else {
+            continue
+        };
+        entries.push(MountInfo {
+            dev: dev.to_string(),
+            source: unescape_mount_field(source),
+            mount_point: unescape_mount_field(mount_point).into(),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Finds all mounts of the block device at `path` and of its partitions
+pub fn find_mounts(path: impl AsRef<Path>) -> io::Result<Vec<Mount>> {
+    mounts_of(&device_and_partitions(path.as_ref())?)
+}
+
+/// Finds all mounts of partition `number` of the block device at `path`
+pub fn find_partition_mounts(path: impl AsRef<Path>, number: u32) -> io::Result<Vec<Mount>> {
+    let devices: Vec<BlockDevice> = device_and_partitions(path.as_ref())?.into_iter()
+        .filter(|device| device.partition == Some(number))
+        .collect();
+    mounts_of(&devices)
+}
+
+fn mounts_of(devices: &[BlockDevice]) -> io::Result<Vec<Mount>> {
+    let mut mounts = vec![];
+    for entry in read_mountinfo()? {
+        // Some filesystems like btrfs report an anonymous device number, so check the source too
+        let source_name = Path::new(&entry.source).canonicalize().ok()
+            .and_then(|source| source.file_name().map(|name| name.to_string_lossy().to_string()));
+        let is_on_device = devices.iter().any(|device|
+            device.dev == entry.dev || source_name.as_deref() == Some(device.name.as_str())
+        );
+        if is_on_device {
+            mounts.push(Mount { source: entry.source, mount_point: entry.mount_point });
+        }
+    }
+
+    Ok(mounts)
+}
+
+/// Collects the device numbers of the block device at `sysfs_dir`, the disk it is a partition
+/// of and everything it is stacked on (device mapper, LVM, md), recursively
+fn collect_backing_devices(sysfs_dir: &Path, devices: &mut Vec<String>) {
+    let Ok(dev) = read_to_string(sysfs_dir.join("dev")) else {
+        return
+    };
+    let dev = dev.trim().to_string();
+    if devices.contains(&dev) {
+        return
+    }
+    devices.push(dev);
+
+    if sysfs_dir.join("partition").exists() {
+        if let Some(disk_dir) = sysfs_dir.parent() {
+            collect_backing_devices(disk_dir, devices);
+        }
+    }
+    if let Ok(slaves) = read_dir(sysfs_dir.join("slaves")) {
+        for slave in slaves.flatten() {
+            if let Ok(slave_dir) = slave.path().canonicalize() {
+                collect_backing_devices(&slave_dir, devices);
+            }
+        }
+    }
+}
+
+/// Finds the mount of the running system (/, /boot or /home) that is stored on the
+/// block device at `path`, either directly or through partitions and stacked devices
+pub fn find_system_mount(path: impl AsRef<Path>) -> io::Result<Option<Mount>> {
+    let destination = device_number(path.as_ref())?;
+
+    for entry in read_mountinfo()? {
+        if !SYSTEM_MOUNT_POINTS.iter().any(|mount_point| entry.mount_point == Path::new(mount_point)) {
+            continue
+        }
+
+        // Filesystems like btrfs report an anonymous device number, use the source device instead
+        let dev = match entry.dev.starts_with("0:") {
+            true => match device_number(Path::new(&entry.source)) {
+                Ok(dev) => dev,
+                Err(_) => continue,
+            },
+            false => entry.dev.clone(),
+        };
+        let Ok(sysfs_dir) = Path::new("/sys/dev/block").join(&dev).canonicalize() else {
+            continue
+        };
+
+        let mut devices = vec![];
+        collect_backing_devices(&sysfs_dir, &mut devices);
+        if devices.contains(&destination) {
+            return Ok(Some(Mount { source: entry.source, mount_point: entry.mount_point }))
+        }
+    }
+
+    Ok(None)
+}
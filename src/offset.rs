@@ -0,0 +1,217 @@
+use std::fs::{File, metadata, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use crate::error::FlashError;
+use crate::event::{FlashEvent, Mismatch};
+use crate::extents::{data_ranges, with_beginning};
+use crate::tempfile::TempFile;
+use crate::{
+    clear_range, device_size, drop_caches, eject_destination, first_differing_byte, flash,
+    flush_destination, logical_block_size, mounts, FlashPlan, FlashSummary, WipeMode,
+};
+
+/// Name the region is written and verified under, it isn't a partition of its own
+const REGION_NAME: &str = "region";
+
+/// Writes `plan` to the region of its destination that starts `offset` bytes in, as if the
+/// region was a disk of its own: the MBR, the GPT and all partitions are placed relative to
+/// `offset` and everything before it stays untouched.
+///
+/// The region is laid out in a sparse image file first, like for rockusb, and everything but
+/// its holes is copied to the destination. The holes are cleared where the destination can do
+/// that without writing zeros, the first 8 MiB of the region are always overwritten. The region
+/// ends at the end of block devices, image files are extended to `plan.size` if it is given.
+/// Offsets in the events, and so in manifests, are relative to `offset`.
+pub fn flash_at_offset(
+    mut plan: FlashPlan,
+    offset: u64,
+    mut on_event: impl FnMut(FlashEvent),
+) -> Result<FlashSummary, FlashError> {
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+    if !plan.keep.is_empty() || plan.append {
+        return Err(FlashError::Unsupported(
+            "Partitions can't be kept or appended at an offset, the region is laid out anew".into()
+        ))
+    }
+    if plan.is_empty() {
+        on_event(FlashEvent::NothingToFlash);
+        return Ok(FlashSummary::default())
+    }
+
+    let destination = plan.destination.clone();
+    let access_error = |source| FlashError::DeviceAccess { path: destination.clone(), source };
+    let invalid = |message: String| FlashError::OffsetInvalid { offset, message };
+    let lba_size = u64::from(logical_block_size(&destination, plan.lba_size)?);
+    if !offset.is_multiple_of(lba_size) {
+        return Err(invalid(format!(
+            "it must be a multiple of the logical block size of {} bytes", lba_size
+        )))
+    }
+    let device_size = device_size(&destination)?;
+    let is_block_device = device_size.is_some();
+    let size = match (device_size, plan.size) {
+        (Some(device_size), _) => device_size.checked_sub(offset).filter(|size| *size > 0)
+            .ok_or_else(|| invalid(format!("the device is only {} bytes large", device_size)))?,
+        (None, 0) => metadata(&destination).map_or(0, |metadata| metadata.len())
+            .checked_sub(offset).filter(|size| *size > 0)
+            .ok_or_else(|| invalid("the image file ends before it, pass a size".into()))?,
+        (None, size) => size,
+    };
+
+    // Filesystems of the destination may live anywhere, also in the region
+    if is_block_device && !plan.force {
+        if let Some(mount) = mounts::find_system_mount(&destination).map_err(access_error)? {
+            return Err(FlashError::SystemDisk {
+                path: destination.clone(),
+                mount_point: mount.mount_point,
+            })
+        }
+        let mounts = mounts::find_mounts(&destination).map_err(access_error)?;
+        if !mounts.is_empty() {
+            return Err(FlashError::DestinationMounted { path: destination.clone(), mounts })
+        }
+    }
+
+    let image = TempFile::new("offset-layout")
+        .map_err(|source| FlashError::DeviceAccess { path: std::env::temp_dir(), source })?;
+    plan.destination = image.path().into();
+    plan.size = size;
+    plan.lba_size = Some(lba_size);
+    plan.wipe = WipeMode::Quick;
+    // Holes of the layout may only be discarded in the region, zero blocks of images need to
+    // be data of the layout to be written
+    plan.sparse_copy = false;
+    let verify = std::mem::replace(&mut plan.verify, false);
+    let eject = std::mem::replace(&mut plan.eject, false);
+    let (block_size, retries, retry_delay) =
+        (plan.block_size, plan.write_retries, plan.retry_delay);
+
+    // The image file is an implementation detail, events name the destination instead
+    let summary = flash(plan, |event| match event {
+        FlashEvent::Destination { size, .. } => on_event(FlashEvent::Destination {
+            path: destination.clone(),
+            size,
+            is_block_device,
+        }),
+        FlashEvent::FlushingDestination { .. } | FlashEvent::FlashComplete => {},
+        event => on_event(event),
+    })?;
+
+    let image_error = |source| FlashError::DeviceAccess { path: image.path().into(), source };
+    let layout = File::open(image.path()).map_err(image_error)?;
+    let ranges = with_beginning(data_ranges(&layout, size).map_err(image_error)?, size);
+    let total = ranges.iter().map(|(start, end)| end - start).sum();
+
+    let target = OpenOptions::new()
+        .write(true)
+        .create(!is_block_device)
+        .truncate(false)
+        .open(&destination)
+        .map_err(access_error)?;
+    if !is_block_device && target.metadata().map_err(access_error)?.len() < offset + size {
+        target.set_len(offset + size).map_err(access_error)?;
+    }
+    // What is empty in the layout is cleared in the region, unless that means writing zeros
+    let mut hole_start = 0;
+    for (start, end) in ranges.iter().copied().chain([(size, size)]) {
+        if start > hole_start {
+            let _ = clear_range(&target, is_block_device, offset + hole_start, start - hole_start);
+        }
+        hole_start = end;
+    }
+
+    on_event(FlashEvent::WritingAtOffset { offset, size: total });
+    let mut buffer = vec![0; block_size];
+    let mut written = 0;
+    for &(start, end) in &ranges {
+        let mut position = start;
+        while position < end {
+            let len = (end - position).min(block_size as u64) as usize;
+            layout.read_exact_at(&mut buffer[..len], position).map_err(image_error)?;
+            target.write_all_at(&buffer[..len], offset + position)
+                .map_err(|source| FlashError::WriteFailed {
+                    partition: REGION_NAME.into(),
+                    offset: offset + position,
+                    source,
+                })?;
+
+            position += len as u64;
+            written += len as u64;
+            on_event(FlashEvent::OffsetProgress { written });
+        }
+    }
+    on_event(FlashEvent::OffsetWritten);
+
+    flush_destination(&destination, retries, retry_delay, on_event)?;
+    if verify {
+        verify_region(&layout, &destination, offset, &ranges, total, block_size, on_event)?;
+    }
+    if eject && is_block_device {
+        eject_destination(&destination, on_event)?;
+    }
+    on_event(FlashEvent::FlashComplete);
+    Ok(summary)
+}
+
+/// Reads the copied ranges back from the destination and compares them to the image file
+fn verify_region(
+    layout: &File,
+    destination: &Path,
+    offset: u64,
+    ranges: &[(u64, u64)],
+    total: u64,
+    block_size: usize,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<(), FlashError> {
+    // Without O_SYNC, reading is a lot faster
+    let file = File::open(destination)
+        .map_err(|source| FlashError::DeviceAccess { path: destination.into(), source })?;
+    drop_caches(&file)
+        .map_err(|source| FlashError::DeviceAccess { path: destination.into(), source })?;
+
+    on_event(FlashEvent::VerifyingPartition { name: REGION_NAME.into(), size: total });
+    let mut expected = vec![0; block_size];
+    let mut actual = vec![0; block_size];
+    let mut verified = 0;
+    let mut mismatch = None;
+    'ranges: for &(start, end) in ranges {
+        let mut position = start;
+        while position < end {
+            let len = (end - position).min(block_size as u64) as usize;
+            let read_error = |source| FlashError::ReadFailed {
+                partition: REGION_NAME.into(),
+                offset: offset + position,
+                source,
+            };
+            layout.read_exact_at(&mut expected[..len], position).map_err(read_error)?;
+            file.read_exact_at(&mut actual[..len], offset + position).map_err(read_error)?;
+            if let Some(index) = first_differing_byte(&expected[..len], &actual[..len]) {
+                mismatch = Some(Mismatch::Byte {
+                    offset: position + index as u64,
+                    expected: expected[index],
+                    actual: actual[index],
+                });
+                break 'ranges
+            }
+
+            position += len as u64;
+            verified += len as u64;
+            on_event(FlashEvent::VerifyProgress { name: REGION_NAME.into(), verified, total });
+        }
+    }
+
+    let failed = mismatch.is_some();
+    match mismatch {
+        None => on_event(FlashEvent::PartitionVerified { name: REGION_NAME.into(), verified }),
+        Some(mismatch) => on_event(FlashEvent::PartitionVerifyFailed {
+            name: REGION_NAME.into(),
+            mismatch,
+        }),
+    }
+    on_event(FlashEvent::VerifyFinished);
+
+    if failed {
+        return Err(FlashError::VerifyFailed { partitions: vec![REGION_NAME.into()] })
+    }
+    Ok(())
+}
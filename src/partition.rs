@@ -0,0 +1,617 @@
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use gpt::partition_types;
+use uuid::Uuid;
+use crate::alignment::checked_align_up;
+use crate::checksum::Checksum;
+use crate::compression::{Compression, detect_compression, open_decompressed, uncompressed_size};
+use crate::error::FlashError;
+use crate::sparse::read_sparse_header;
+use crate::{FIRST_PART_ALIGNMENT, PART_ALIGNMENT};
+
+/// A partition to create, optionally filled with the contents of a source image
+#[derive(Clone, Debug)]
+pub struct PartitionDefinition {
+    pub partition_name: String,
+    pub partition_type: Option<partition_types::Type>,
+    pub source_file: Option<PathBuf>,
+    pub compression: Compression,
+    pub sparse: bool,
+    pub size: u64,
+    /// Expected checksum of the source file, checked before anything is written
+    pub checksum: Option<Checksum>,
+    /// Unique GUID (PARTUUID) of the partition, a random one is used otherwise
+    pub part_guid: Option<Uuid>,
+    /// GPT attribute flags, derived from the name if not given
+    pub flags: Option<u64>,
+    /// Offset of the partition in bytes, it's placed after the previous one if not given
+    pub start: Option<u64>,
+    /// Boundary the start is aligned to in bytes, see [PartitionDefinition::effective_alignment]
+    pub alignment: Option<u64>,
+    /// Create NAME_a and NAME_b with the same size and contents instead, see [expand_slots]
+    pub ab_slots: bool,
+    /// Size given for a source read from stdin, which fails once the stream gets longer
+    pub declared_size: Option<u64>,
+}
+
+/// Source file name that stands for stdin, as in `system:-:size`
+pub const STDIN_SOURCE: &str = "-";
+
+/// Whether the source image is streamed from stdin rather than read from a file
+pub fn is_stdin(source_file: &Path) -> bool {
+    source_file == Path::new(STDIN_SOURCE)
+}
+
+impl PartitionDefinition {
+    /// Creates a partition that is filled with the given source image.
+    ///
+    /// Compression and Android sparse images are detected automatically. The size of the
+    /// partition is derived from the source unless `explicit_size` is given.
+    ///
+    /// A source of [STDIN_SOURCE] is streamed from stdin as it is, without detecting anything,
+    /// so it needs `explicit_size`.
+    pub fn from_source(
+        partition_name: String,
+        source_file: PathBuf,
+        explicit_size: Option<u64>,
+    ) -> Result<Self, FlashError> {
+        let stdin = is_stdin(&source_file);
+        let (compression, sparse, source_size, image_size) = if stdin {
+            let size = explicit_size.ok_or_else(|| FlashError::StdinSourceInvalid {
+                partition: partition_name.clone(),
+                message: format!("its size must be given, e.g. {}:-:2G", partition_name),
+            })?;
+            (Compression::None, false, size, None)
+        } else {
+            match source_file.try_exists() {
+                Err(err) => Err(FlashError::source_access(source_file.clone(), err)),
+                Ok(false) => Err(FlashError::SourceMissing { path: source_file.clone() }),
+                _ => Ok(())
+            }?;
+            let compression = detect_compression(source_file.clone())
+                .map_err(|err| FlashError::source_access(source_file.clone(), err))?;
+            let sparse_header = open_decompressed(source_file.clone(), compression)
+                .and_then(|mut reader| read_sparse_header(&mut reader))
+                .map_err(|err| FlashError::source_access(source_file.clone(), err))?;
+            // The file only contains the used blocks, the image is as big as all blocks together
+            let image_size = match sparse_header {
+                Some(header) => Ok(header.expanded_size()),
+                None => uncompressed_size(source_file.clone(), compression),
+            };
+            // An explicit size doesn't need the size of the image, it is only checked if known
+            let (source_size, image_size) = match (explicit_size, image_size) {
+                (Some(size), image_size) => (size, image_size.ok()),
+                (None, Ok(image_size)) => (image_size, Some(image_size)),
+                (None, Err(source)) =>
+                    return Err(FlashError::SourceSize { path: source_file, source }),
+            };
+            (compression, sparse_header.is_some(), source_size, image_size)
+        };
+        let part_size = checked_align_up(source_size, FIRST_PART_ALIGNMENT)
+            .ok_or_else(|| FlashError::SizeOverflow {
+                partition: partition_name.clone(),
+                size: source_size,
+            })?;
+        // An explicit size makes room to grow, it must not cut off the image. Better found out
+        // now than after writing most of it.
+        if let Some(image_size) = image_size.filter(|image_size| *image_size > source_size) {
+            return Err(FlashError::ImageTooLarge {
+                partition: partition_name,
+                path: source_file,
+                size: Some(image_size),
+                available: source_size,
+            })
+        }
+
+        Ok(PartitionDefinition {
+            partition_name,
+            partition_type: None,
+            source_file: Some(source_file),
+            compression,
+            sparse,
+            size: part_size,
+            checksum: None,
+            part_guid: None,
+            flags: None,
+            start: None,
+            alignment: None,
+            ab_slots: false,
+            declared_size: stdin.then_some(source_size),
+        })
+    }
+
+    /// Creates a partition without contents
+    pub fn empty(partition_name: String, size: u64) -> Self {
+        PartitionDefinition {
+            partition_name,
+            partition_type: None,
+            source_file: None,
+            compression: Compression::None,
+            sparse: false,
+            size,
+            checksum: None,
+            part_guid: None,
+            flags: None,
+            start: None,
+            alignment: None,
+            ab_slots: false,
+            declared_size: None,
+        }
+    }
+
+    /// The explicitly requested partition type or the one derived from the name
+    pub fn effective_type(&self) -> partition_types::Type {
+        self.partition_type.clone()
+            .unwrap_or_else(|| partition_name_to_type(self.partition_name.clone()))
+    }
+
+    /// The explicitly requested alignment, otherwise 8 MiB for the first partition, which
+    /// leaves room for the loaders, and 1 MiB for the others
+    pub fn effective_alignment(&self, is_first: bool) -> u64 {
+        self.alignment
+            .unwrap_or(if is_first { FIRST_PART_ALIGNMENT } else { PART_ALIGNMENT })
+    }
+
+    /// The explicitly requested attribute flags or the ones derived from the type
+    pub fn effective_flags(&self) -> u64 {
+        self.flags.unwrap_or_else(|| partition_type_to_flags(&self.effective_type()))
+    }
+}
+
+/// An image that is written to a fixed offset without a partition table entry,
+/// like U-Boot and the trust image on Rockchip
+#[derive(Clone, Debug)]
+pub struct RawImage {
+    pub name: String,
+    pub source_file: PathBuf,
+    /// Offset in bytes
+    pub offset: u64,
+}
+
+/// The partition that takes up the space after the last partition
+#[derive(Clone, Debug)]
+pub struct FillPartition {
+    pub name: String,
+    pub partition_type: partition_types::Type,
+    /// Space beyond this many bytes is left free
+    pub max_size: Option<u64>,
+}
+
+impl Default for FillPartition {
+    fn default() -> Self {
+        FillPartition {
+            name: "userdata".into(),
+            partition_type: partition_types::ANDROID_DATA,
+            max_size: None,
+        }
+    }
+}
+
+/// A partition to create a filesystem on after flashing
+#[derive(Clone, Debug)]
+pub struct FormatPartitionDefinition {
+    pub partition_name: String,
+    pub format_as: String,
+    /// Extra arguments for mkfs, passed before the device
+    pub mkfs_args: Vec<String>,
+    /// Directory whose contents are copied onto the new filesystem
+    pub populate_from: Option<PathBuf>,
+}
+
+/// Splits mkfs options into arguments at whitespace like a shell would, so quotes and
+/// backslashes can be used for arguments containing spaces, e.g. `-L "my data"`
+pub fn split_mkfs_args(options: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = options.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            },
+            (Some('\''), c) => arg.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => arg.get_or_insert_with(String::new).push(escaped),
+                None => return Err("Trailing backslash in mkfs options".into()),
+            },
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (_, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(open) = quote {
+        return Err(format!("Unterminated {} in mkfs options", open))
+    }
+    args.extend(arg);
+
+    Ok(args)
+}
+
+/// Short names for common partition types and their names in [partition_types]
+const PARTITION_TYPE_ALIASES: [(&str, &str); 9] = [
+    ("efi", "EFI"),
+    ("esp", "EFI"),
+    ("linux", "LINUX_FS"),
+    ("home", "LINUX_HOME"),
+    ("swap", "LINUX_SWAP"),
+    ("lvm", "LINUX_LVM"),
+    ("raid", "LINUX_RAID"),
+    ("luks", "LINUX_LUKS"),
+    ("basic", "BASIC"),
+];
+
+/// Partition types that are shown by name, the ones rockflasher assigns and takes as aliases
+const NAMED_PARTITION_TYPES: [(&str, partition_types::Type); 21] = [
+    ("ANDROID_BOOT", partition_types::ANDROID_BOOT),
+    ("ANDROID_BOOTLOADER", partition_types::ANDROID_BOOTLOADER),
+    ("ANDROID_BOOTLOADER2", partition_types::ANDROID_BOOTLOADER2),
+    ("ANDROID_CACHE", partition_types::ANDROID_CACHE),
+    ("ANDROID_DATA", partition_types::ANDROID_DATA),
+    ("ANDROID_FACTORY", partition_types::ANDROID_FACTORY),
+    ("ANDROID_FASTBOOT", partition_types::ANDROID_FASTBOOT),
+    ("ANDROID_META", partition_types::ANDROID_META),
+    ("ANDROID_MISC", partition_types::ANDROID_MISC),
+    ("ANDROID_OEM", partition_types::ANDROID_OEM),
+    ("ANDROID_PERSISTENT", partition_types::ANDROID_PERSISTENT),
+    ("ANDROID_RECOVERY", partition_types::ANDROID_RECOVERY),
+    ("ANDROID_SYSTEM", partition_types::ANDROID_SYSTEM),
+    ("BASIC", partition_types::BASIC),
+    ("EFI", partition_types::EFI),
+    ("LINUX_FS", partition_types::LINUX_FS),
+    ("LINUX_HOME", partition_types::LINUX_HOME),
+    ("LINUX_LUKS", partition_types::LINUX_LUKS),
+    ("LINUX_LVM", partition_types::LINUX_LVM),
+    ("LINUX_RAID", partition_types::LINUX_RAID),
+    ("LINUX_SWAP", partition_types::LINUX_SWAP),
+];
+
+/// Name of a partition type GUID like ANDROID_BOOT, `None` for types without one here
+pub fn partition_type_name(type_guid: &str) -> Option<&'static str> {
+    NAMED_PARTITION_TYPES.iter()
+        .find(|(_, partition_type)| partition_type.guid.eq_ignore_ascii_case(type_guid))
+        .map(|(name, _)| *name)
+}
+
+/// Looks up a partition type by an alias (efi, linux, …), its name in [partition_types]
+/// (e.g. LINUX_FS or android-boot) or its GUID, which doesn't have to be a known one
+pub fn parse_partition_type(type_string: &str) -> Result<partition_types::Type, String> {
+    let keyword = type_string.to_lowercase();
+    let type_name = match PARTITION_TYPE_ALIASES.iter().find(|(alias, _)| *alias == keyword) {
+        Some((_, type_name)) => type_name.to_string(),
+        None => keyword.replace('-', "_"),
+    };
+    if let Ok(partition_type) = partition_types::Type::from_name(&type_name) {
+        return Ok(partition_type)
+    }
+
+    if is_guid(type_string) {
+        // Types are static in gpt, this only happens once per partition argument
+        return Ok(partition_types::Type {
+            guid: Box::leak(type_string.to_uppercase().into_boxed_str()),
+            os: partition_types::OperatingSystem::None,
+        })
+    }
+
+    let aliases: Vec<&str> = PARTITION_TYPE_ALIASES.iter().map(|(alias, _)| *alias).collect();
+    Err(format!(
+        "Unknown partition type: {} (use one of {}, a type name like android-boot or LINUX_FS, \
+        or a GUID)",
+        type_string, aliases.join(", ")
+    ))
+}
+
+/// Names of GPT attribute bits and their position
+const PARTITION_FLAGS: [(&str, u32); 8] = [
+    // Needed for the platform to work, must not be deleted
+    ("required", 0),
+    // Firmware must not read the partition
+    ("no-block-io", 1),
+    ("legacy-boot", 2),
+    // Slot metadata of ChromeOS kernel partitions, also used by some A/B bootloaders,
+    // along with priority (bits 48–51) and tries (bits 52–55)
+    ("successful", 56),
+    // Microsoft basic data partitions
+    ("read-only", 60),
+    ("shadow-copy", 61),
+    ("hidden", 62),
+    ("no-automount", 63),
+];
+
+/// Multi-bit attribute fields given as name=value and their lowest bit and width
+const PARTITION_FLAG_FIELDS: [(&str, u32, u32); 2] = [
+    ("priority", 48, 4),
+    ("tries", 52, 4),
+];
+
+/// Parses GPT attribute flags, either a raw value like 0x1 or a comma-separated list of
+/// named bits (required, legacy-boot, …) and fields (priority=15, tries=0) which are combined
+pub fn parse_partition_flags(flags_string: &str) -> Result<u64, String> {
+    let mut flags = 0;
+    for flag in flags_string.split(',').map(str::trim) {
+        flags |= match flag.split_once('=') {
+            Some((name, value_string)) => {
+                let (_, shift, width) = PARTITION_FLAG_FIELDS.iter()
+                    .find(|(field, _, _)| *field == name)
+                    .ok_or_else(|| format!("Unknown partition flag field {}", name))?;
+                let max = (1 << width) - 1;
+                let value: u64 = value_string.parse().ok().filter(|value| *value <= max)
+                    .ok_or_else(|| format!(
+                        "Invalid value {} for partition flag field {} (0 to {})",
+                        value_string, name, max
+                    ))?;
+                value << shift
+            },
+            None => match PARTITION_FLAGS.iter().find(|(name, _)| *name == flag) {
+                Some((_, bit)) => 1 << bit,
+                None => parse_flags_value(flag).ok_or_else(|| {
+                    let names: Vec<&str> = PARTITION_FLAGS.iter().map(|(name, _)| *name)
+                        .chain(PARTITION_FLAG_FIELDS.iter().map(|(field, _, _)| *field))
+                        .collect();
+                    format!(
+                        "Unknown partition flag {} (use one of {}, or a number like 0x1)",
+                        flag, names.join(", ")
+                    )
+                })?,
+            },
+        };
+    }
+
+    Ok(flags)
+}
+
+/// Formats GPT attribute flags the way [parse_partition_flags] reads them, e.g.
+/// "required,priority=15". Bits without a name are added as a number, no flags are "-".
+pub fn format_partition_flags(flags: u64) -> String {
+    let mut remaining = flags;
+    let mut names = vec![];
+    for (name, bit) in PARTITION_FLAGS {
+        if flags & 1 << bit != 0 {
+            names.push(name.to_string());
+            remaining &= !(1 << bit);
+        }
+    }
+    for (field, shift, width) in PARTITION_FLAG_FIELDS {
+        let mask = ((1 << width) - 1) << shift;
+        if flags & mask != 0 {
+            names.push(format!("{}={}", field, (flags & mask) >> shift));
+            remaining &= !mask;
+        }
+    }
+    if remaining != 0 {
+        names.push(format!("{:#x}", remaining));
+    }
+
+    match names.is_empty() {
+        true => "-".into(),
+        false => names.join(","),
+    }
+}
+
+/// Parses a raw flags value in hex (0x…) or decimal
+fn parse_flags_value(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parses the unique GUID of a partition, which must not be the nil GUID marking unused entries
+pub fn parse_partition_guid(guid_string: &str) -> Result<Uuid, String> {
+    match Uuid::parse_str(guid_string) {
+        Ok(guid) if guid.is_nil() =>
+            Err(format!("Invalid partition GUID {}, it must not be all zeros", guid_string)),
+        Ok(guid) => Ok(guid),
+        Err(err) => Err(format!("Invalid partition GUID {}: {}", guid_string, err)),
+    }
+}
+
+/// Whether a string looks like a GUID, e.g. 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+fn is_guid(guid: &str) -> bool {
+    guid.len() == 36 && guid.char_indices().all(|(index, c)| match index {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit(),
+    })
+}
+
+/// Moves bootloader partitions to the front, keeping the order otherwise
+pub fn reorder_partitions(partitions: Vec<PartitionDefinition>) -> Vec<PartitionDefinition> {
+    let bootloader_partitions = partitions.clone().into_iter()
+        .filter(|part| part.effective_type() == partition_types::ANDROID_BOOTLOADER);
+
+    let all_other_partitions = partitions.into_iter()
+        .filter(|part| part.effective_type() != partition_types::ANDROID_BOOTLOADER);
+
+    bootloader_partitions.chain(all_other_partitions).collect()
+}
+
+/// Strips the slot suffix of A/B partitions, e.g. "boot_a" becomes "boot"
+fn slot_base_name(name: &str) -> &str {
+    name.strip_suffix("_a")
+        .or_else(|| name.strip_suffix("_b"))
+        .filter(|base_name| !base_name.is_empty())
+        .unwrap_or(name)
+}
+
+/// Partitions of Android devices that exist once per slot, they are duplicated by --ab
+pub const AB_PARTITIONS: [&str; 15] = [
+    "boot", "init_boot", "vendor_boot", "dtbo", "vbmeta", "vbmeta_system", "vbmeta_vendor",
+    "super", "system", "vendor", "product", "odm", "system_dlkm", "vendor_dlkm", "odm_dlkm",
+];
+
+/// Slot of A/B partitions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Slot::A => "_a",
+            Slot::B => "_b",
+        }
+    }
+}
+
+/// Attribute flags of the active slot: highest priority and known to boot
+pub const ACTIVE_SLOT_FLAGS: u64 = 15 << 48 | 1 << 56;
+/// Attribute flags of the other slot, which holds the same images and is booted as a fallback
+pub const INACTIVE_SLOT_FLAGS: u64 = 14 << 48 | 1 << 56;
+
+/// Replaces partitions with `ab_slots` by a NAME_a and a NAME_b partition with the same size
+/// and source image, so both are laid out and written like any other partition.
+///
+/// Slots without explicit flags are marked with [ACTIVE_SLOT_FLAGS] and [INACTIVE_SLOT_FLAGS]
+/// if an `active_slot` is given. An explicit unique GUID is only used for slot a.
+pub fn expand_slots(
+    partitions: Vec<PartitionDefinition>,
+    active_slot: Option<Slot>,
+) -> Vec<PartitionDefinition> {
+    partitions.into_iter()
+        .flat_map(|partition| match partition.ab_slots {
+            false => vec![partition],
+            true => [Slot::A, Slot::B].into_iter()
+                .map(|slot| {
+                    let slot_flags = active_slot.map(|active_slot| match active_slot == slot {
+                        true => ACTIVE_SLOT_FLAGS,
+                        false => INACTIVE_SLOT_FLAGS,
+                    });
+                    PartitionDefinition {
+                        partition_name: format!("{}{}", partition.partition_name, slot.suffix()),
+                        part_guid: partition.part_guid.filter(|_| slot == Slot::A),
+                        flags: partition.flags.or(slot_flags),
+                        ab_slots: false,
+                        ..partition.clone()
+                    }
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Other names partitions commonly go by and the names they are treated as
+const PARTITION_NAME_ALIASES: [(&str, &str); 3] = [
+    ("data", "userdata"),
+    ("rootfs", "system"),
+    ("u-boot", "uboot"),
+];
+
+/// Derives the type of a partition from its name, ignoring case, slot suffixes and aliases
+pub fn partition_name_to_type(name: String) -> partition_types::Type {
+    let name = name.to_lowercase();
+    let base_name = slot_base_name(name.as_str());
+    let base_name = PARTITION_NAME_ALIASES.iter()
+        .find(|(alias, _)| *alias == base_name)
+        .map_or(base_name, |(_, name)| name);
+    match base_name {
+        "system" | "vendor" | "super" | "product" | "odm" => partition_types::ANDROID_SYSTEM,
+        "cache" => partition_types::ANDROID_CACHE,
+        "userdata" => partition_types::ANDROID_DATA,
+        "boot" | "vendor_boot" | "system_dlkm" | "vendor_dlkm" | "odm_dlkm" |
+        "dtb" | "dtbo" | "vbmeta" | "security" | "init_boot" => partition_types::ANDROID_BOOT,
+        "recovery" => partition_types::ANDROID_RECOVERY,
+        "misc" => partition_types::ANDROID_MISC,
+        "metadata" => partition_types::ANDROID_META,
+        "factory" | "backup" => partition_types::ANDROID_FACTORY,
+        "uboot" | "bootloader" | "loader" | "trust" | "idbloader" =>
+            partition_types::ANDROID_BOOTLOADER,
+        "stage2" | "bootloader2" | "loader2" => partition_types::ANDROID_BOOTLOADER2,
+        "fastboot" => partition_types::ANDROID_FASTBOOT,
+        "oem" => partition_types::ANDROID_OEM,
+        "persist" => partition_types::ANDROID_PERSISTENT,
+        _ => partition_types::BASIC
+    }
+}
+
+/// Reads a TOML file that maps partition names to types, e.g. `rootfs = "linux"`. The types
+/// are parsed like [parse_partition_type], the names are matched ignoring case.
+pub fn read_type_map(
+    path: impl AsRef<Path>,
+) -> Result<BTreeMap<String, partition_types::Type>, String> {
+    let path = path.as_ref();
+    let path_str = path.to_str().unwrap_or("<invalid path>");
+    let contents = read_to_string(path)
+        .map_err(|err| format!("Could not read type map {}: {}", path_str, err))?;
+    let type_map: BTreeMap<String, String> = toml::from_str(&contents)
+        .map_err(|err| format!("Invalid type map {}: {}", path_str, err))?;
+
+    type_map.into_iter()
+        .map(|(name, type_string)| {
+            let partition_type = parse_partition_type(&type_string).map_err(|err| format!(
+                "Invalid type of partition {} in type map {}: {}", name, path_str, err
+            ))?;
+            Ok((name.to_lowercase(), partition_type))
+        })
+        .collect()
+}
+
+/// Gives partitions without an explicit type the one `type_map` has for their name, which
+/// wins over the built-in types. Like those, A/B partitions are also found by their base name.
+pub fn apply_type_map(
+    partitions: Vec<PartitionDefinition>,
+    type_map: &BTreeMap<String, partition_types::Type>,
+) -> Vec<PartitionDefinition> {
+    partitions.into_iter()
+        .map(|mut partition| {
+            let name = partition.partition_name.to_lowercase();
+            let mapped_type = type_map.get(&name)
+                .or_else(|| type_map.get(slot_base_name(&name)));
+            if partition.partition_type.is_none() {
+                partition.partition_type = mapped_type.cloned();
+            }
+            partition
+        })
+        .collect()
+}
+
+/// Attribute bit of partitions the platform needs to work
+pub const REQUIRED_FLAG: u64 = 1 << 0;
+
+/// Attribute flags of partitions without explicit ones: the loaders are required, as the
+/// board doesn't boot without them
+pub fn partition_type_to_flags(part_type: &partition_types::Type) -> u64 {
+    let is_loader = *part_type == partition_types::ANDROID_BOOTLOADER
+        || *part_type == partition_types::ANDROID_BOOTLOADER2;
+    match is_loader {
+        true => REQUIRED_FLAG,
+        false => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slotted_names_get_the_type_of_their_base_name() {
+        assert_eq!(partition_name_to_type("boot_a".into()), partition_types::ANDROID_BOOT);
+        assert_eq!(partition_name_to_type("super_b".into()), partition_types::ANDROID_SYSTEM);
+        assert_eq!(partition_name_to_type("boot".into()), partition_types::ANDROID_BOOT);
+    }
+
+    #[test]
+    fn loader_partitions_are_required() {
+        for name in ["uboot", "trust", "idbloader", "loader", "loader2"] {
+            let def = PartitionDefinition::empty(name.into(), 4 * 1024 * 1024);
+            assert_eq!(def.effective_flags(), REQUIRED_FLAG, "{}", name);
+        }
+        let mut def = PartitionDefinition::empty("boot".into(), 4 * 1024 * 1024);
+        assert_eq!(def.effective_flags(), 0);
+        def.partition_type = Some(partition_types::ANDROID_BOOTLOADER);
+        assert_eq!(def.effective_flags(), REQUIRED_FLAG);
+        def.flags = Some(0);
+        assert_eq!(def.effective_flags(), 0);
+    }
+
+    #[test]
+    fn names_are_matched_ignoring_case() {
+        assert_eq!(partition_name_to_type("Boot".into()), partition_types::ANDROID_BOOT);
+        assert_eq!(partition_name_to_type("USERDATA".into()), partition_types::ANDROID_DATA);
+        assert_eq!(partition_name_to_type("rootfs".into()), partition_types::ANDROID_SYSTEM);
+        assert_eq!(partition_name_to_type("foobar".into()), partition_types::BASIC);
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+use std::io::Read;
+
+/// Wraps a reader and reports the total number of bytes read so far to a callback.
+///
+/// The callback is invoked at most once every `interval` bytes to keep the overhead low.
+pub struct ProgressReader<R: Read, F: FnMut(u64)> {
+    inner: R,
+    bytes_read: u64,
+    last_reported: u64,
+    interval: u64,
+    on_progress: F,
+}
+
+impl<R: Read, F: FnMut(u64)> ProgressReader<R, F> {
+    pub fn new(inner: R, interval: u64, on_progress: F) -> Self {
+        ProgressReader {
+            inner,
+            bytes_read: 0,
+            last_reported: 0,
+            interval,
+            on_progress,
+        }
+    }
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read += read as u64;
+        if self.bytes_read - self.last_reported >= self.interval {
+            self.last_reported = self.bytes_read;
+            (self.on_progress)(self.bytes_read);
+        }
+        Ok(read)
+    }
+}
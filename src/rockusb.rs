@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::Command;
+use crate::error::FlashError;
+use crate::extents::{data_ranges, with_beginning};
+use crate::event::FlashEvent;
+use crate::tempfile::TempFile;
+use crate::{fat, find_program, flash, FlashPlan, FlashSummary, WipeMode};
+
+/// Destination that selects a board in loader or maskrom mode on USB instead of a block device
+pub const ROCKUSB_DESTINATION: &str = "rockusb:";
+
+const RKDEVELOPTOOL: &str = "rkdeveloptool";
+/// rkdeveloptool addresses the flash in sectors of 512 bytes
+const SECTOR_SIZE: u64 = 512;
+/// Data is handed to rkdeveloptool in files of at most this size
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Runs rkdeveloptool and returns what it printed
+fn rkdeveloptool(args: &[&str]) -> Result<String, FlashError> {
+    log::debug!("Running {} {}", RKDEVELOPTOOL, args.join(" "));
+    let output = Command::new(RKDEVELOPTOOL).args(args).output()
+        .map_err(|err| FlashError::RockusbFailed {
+            message: format!("Failed to run {}: {}", RKDEVELOPTOOL, err),
+        })?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    // It doesn't always exit with an error, but always says so
+    if !output.status.success() || stdout.contains("failed") {
+        return Err(FlashError::RockusbFailed {
+            message: format!(
+                "{} {} failed: {}{}",
+                RKDEVELOPTOOL, args.join(" "), stdout.trim(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        })
+    }
+
+    Ok(stdout)
+}
+
+/// Size of the flash of the board in bytes, from a line like "Flash Size: 30535680 Sectors"
+fn flash_size() -> Result<u64, FlashError> {
+    let info = rkdeveloptool(&["rfi"])?;
+    info.lines()
+        .filter_map(|line| line.trim().strip_prefix("Flash Size:")?.trim().strip_suffix("Sectors"))
+        .find_map(|sectors| sectors.trim().parse::<u64>().ok())
+        .map(|sectors| sectors * SECTOR_SIZE)
+        .ok_or_else(|| FlashError::RockusbFailed {
+            message: format!("Could not read the flash size, pass --size: {}", info.trim()),
+        })
+}
+
+/// Writes `plan` to the flash of a board in loader or maskrom mode through rkdeveloptool.
+///
+/// The destination is laid out in a sparse image file first, exactly like a block device
+/// would be. The whole flash is erased and everything but the holes of the image is written to
+/// the same offsets of the flash, so the holes end up erased like the zero blocks and cleared
+/// space they stand for. A `loader` (e.g. rk3399_loader_v1.30.130.bin) is downloaded first for
+/// boards in maskrom mode.
+pub fn flash_rockusb(
+    mut plan: FlashPlan,
+    loader: Option<&Path>,
+    mut on_event: impl FnMut(FlashEvent),
+) -> Result<FlashSummary, FlashError> {
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+    if !plan.keep.is_empty() || plan.append {
+        return Err(FlashError::Unsupported(
+            "Partitions can't be kept or appended over rockusb, the flash can't be read".into()
+        ))
+    }
+    let needs_device = plan.partitions_to_format.iter()
+        .any(|def| !fat::is_builtin_format(def) || def.populate_from.is_some());
+    if needs_device {
+        return Err(FlashError::Unsupported(
+            "Only FAT filesystems can be created over rockusb, without mkfs options".into()
+        ))
+    }
+    if !find_program(RKDEVELOPTOOL) {
+        return Err(FlashError::MissingTools { tools: vec![RKDEVELOPTOOL.into()] })
+    }
+
+    if let Some(loader) = loader {
+        on_event(FlashEvent::DownloadingLoader { path: loader.into() });
+        rkdeveloptool(&["db", &loader.to_string_lossy()])?;
+    }
+    let size = match plan.size {
+        0 => flash_size()?,
+        size => size,
+    };
+
+    let temp_error = |source| FlashError::DeviceAccess { path: std::env::temp_dir(), source };
+    let image = TempFile::new("rockusb-layout").map_err(temp_error)?;
+    let destination = std::mem::replace(&mut plan.destination, image.path().into());
+    let reset = std::mem::replace(&mut plan.eject, false);
+    // The flash is erased below, whatever the plan asks for
+    plan.wipe = WipeMode::Quick;
+    plan.size = size;
+
+    // The image file is an implementation detail, events name the board instead
+    let summary = flash(plan, |event| match event {
+        FlashEvent::Destination { size, .. } => on_event(FlashEvent::Destination {
+            path: destination.clone(),
+            size,
+            is_block_device: false,
+        }),
+        FlashEvent::FlushingDestination { .. } | FlashEvent::FlashComplete => {},
+        event => on_event(event),
+    })?;
+
+    let image_error = |source| FlashError::DeviceAccess { path: image.path().into(), source };
+    let file = File::open(image.path()).map_err(image_error)?;
+    let ranges = with_beginning(data_ranges(&file, size).map_err(image_error)?, size);
+    let total = ranges.iter().map(|(start, end)| end - start).sum();
+
+    // Only once the layout is complete, so a plan that fails leaves the board as it is
+    on_event(FlashEvent::ErasingFlash { size });
+    rkdeveloptool(&["ef"])?;
+    on_event(FlashEvent::WritingRockusb { size: total });
+
+    let chunk_file = TempFile::new("rockusb-chunk").map_err(temp_error)?;
+    let chunk_error = |source| FlashError::DeviceAccess { path: chunk_file.path().into(), source };
+    let mut written = 0;
+    let mut buffer = vec![];
+    for (start, end) in ranges {
+        let mut offset = start;
+        while offset < end {
+            let len = (end - offset).min(MAX_CHUNK_SIZE);
+            buffer.resize(len as usize, 0);
+            file.read_exact_at(&mut buffer, offset).map_err(image_error)?;
+            std::fs::write(chunk_file.path(), &buffer).map_err(chunk_error)?;
+            rkdeveloptool(&[
+                "wl", &(offset / SECTOR_SIZE).to_string(), &chunk_file.path().to_string_lossy()
+            ])?;
+
+            offset += len;
+            written += len;
+            on_event(FlashEvent::RockusbProgress { written });
+        }
+    }
+
+    on_event(FlashEvent::RockusbWritten);
+
+    if reset {
+        on_event(FlashEvent::ResettingDevice);
+        rkdeveloptool(&["rd"])?;
+    }
+    on_event(FlashEvent::FlashComplete);
+    Ok(summary)
+}
@@ -0,0 +1,318 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A size given by the user, e.g. "64MiB", "1.5GB" or "4096".
+///
+/// IEC units (KiB, MiB, …) are binary, SI units (kB, MB, …) are decimal, just like the
+/// sizes displayed by [BinarySize] and [DecimalSize]. A bare prefix like "M" could mean
+/// either and is read as binary, which is recorded in `assumed_binary`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParsedSize {
+    pub bytes: u64,
+    /// The unit was ambiguous and has been interpreted as binary
+    pub assumed_binary: bool,
+}
+
+const PREFIXES: [char; 8] = ['K', 'M', 'G', 'T', 'P', 'E', 'Z', 'Y'];
+
+/// Units of [RoundedBinarySize] and [RoundedDecimalSize] from bytes upwards
+const BINARY_UNITS: [&str; 9] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB", "ZiB", "YiB"];
+const DECIMAL_UNITS: [&str; 9] = ["B", "kB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
+
+/// How a size is rounded to the decimal places it is displayed with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to the nearest value, ties go to the even neighbour (1.125 becomes 1.12)
+    #[default]
+    HalfEven,
+    /// Round to the nearest value, ties go up (1.125 becomes 1.13)
+    HalfUp,
+    /// Cut off the remaining digits, so a size is never shown larger than it is
+    Down,
+}
+
+/// Size in bytes, displayed with binary units
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BinarySize(pub u128);
+
+impl BinarySize {
+    /// Displays the size in the largest unit it reaches, with two decimal places
+    pub fn rounded(self) -> RoundedBinarySize {
+        RoundedBinarySize {
+            bytes: self.0,
+            decimal_places: 2,
+            rounding: RoundingMode::default(),
+        }
+    }
+}
+
+impl From<u64> for BinarySize {
+    fn from(bytes: u64) -> Self {
+        BinarySize(bytes as u128)
+    }
+}
+
+impl fmt::Display for BinarySize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} B", self.0)
+    }
+}
+
+pub struct RoundedBinarySize {
+    bytes: u128,
+    decimal_places: usize,
+    rounding: RoundingMode,
+}
+
+impl RoundedBinarySize {
+    pub fn rounding(self, rounding: RoundingMode) -> Self {
+        RoundedBinarySize { rounding, ..self }
+    }
+}
+
+impl fmt::Display for RoundedBinarySize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_rounded(f, self.bytes, 1024, &BINARY_UNITS, self.decimal_places, self.rounding)
+    }
+}
+
+/// Size in bytes, displayed with decimal units like storage vendors use them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DecimalSize(pub u128);
+
+impl DecimalSize {
+    /// Displays the size in the largest unit it reaches, with two decimal places
+    pub fn rounded(self) -> RoundedDecimalSize {
+        RoundedDecimalSize {
+            bytes: self.0,
+            decimal_places: 2,
+            rounding: RoundingMode::default(),
+        }
+    }
+}
+
+impl From<u64> for DecimalSize {
+    fn from(bytes: u64) -> Self {
+        DecimalSize(bytes as u128)
+    }
+}
+
+impl fmt::Display for DecimalSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} B", self.0)
+    }
+}
+
+pub struct RoundedDecimalSize {
+    bytes: u128,
+    decimal_places: usize,
+    rounding: RoundingMode,
+}
+
+impl RoundedDecimalSize {
+    pub fn rounding(self, rounding: RoundingMode) -> Self {
+        RoundedDecimalSize { rounding, ..self }
+    }
+}
+
+impl fmt::Display for RoundedDecimalSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_rounded(f, self.bytes, 1000, &DECIMAL_UNITS, self.decimal_places, self.rounding)
+    }
+}
+
+/// Writes `bytes` in the largest of `units` it reaches, each `base` times the previous one.
+///
+/// The number is rounded exactly instead of through a float. If rounding reaches the next unit,
+/// e.g. 1023.996 MiB, the size is shown in that unit instead (1.00 GiB, not 1024.00 MiB).
+fn write_rounded(
+    f: &mut fmt::Formatter<'_>,
+    bytes: u128,
+    base: u128,
+    units: &[&str],
+    decimal_places: usize,
+    rounding: RoundingMode,
+) -> fmt::Result {
+    let scale = 10_u128.pow(decimal_places as u32);
+    let mut exponent = (1..units.len())
+        .take_while(|exponent| bytes >= base.pow(*exponent as u32))
+        .last()
+        .unwrap_or(0);
+    if exponent == 0 {
+        return write!(f, "{} {}", bytes, units[0])
+    }
+
+    loop {
+        let unit = base.pow(exponent as u32);
+        // Digits of the number including the decimal places, before rounding
+        let fraction = bytes % unit * scale;
+        let mut digits = (bytes / unit).saturating_mul(scale) + fraction / unit;
+        let remainder = fraction % unit;
+        let round_up = match rounding {
+            RoundingMode::HalfEven =>
+                remainder * 2 > unit || (remainder * 2 == unit && digits % 2 == 1),
+            RoundingMode::HalfUp => remainder * 2 >= unit,
+            RoundingMode::Down => false,
+        };
+        if round_up {
+            digits += 1;
+        }
+
+        if digits >= base * scale && exponent + 1 < units.len() {
+            exponent += 1;
+            continue
+        }
+        return match decimal_places {
+            0 => write!(f, "{} {}", digits, units[exponent]),
+            _ => write!(
+                f, "{}.{:0width$} {}",
+                digits / scale, digits % scale, units[exponent], width = decimal_places
+            ),
+        }
+    }
+}
+
+impl FromStr for ParsedSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bytes, assumed_binary) = parse_bytes(s, 1024)?;
+        let bytes = u64::try_from(bytes)
+            .map_err(|_| format!("Size {} is too large", s.trim()))?;
+
+        Ok(ParsedSize { bytes, assumed_binary })
+    }
+}
+
+/// Parses sizes like [ParsedSize] does, a bare prefix like "M" is read as binary
+impl FromStr for BinarySize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_bytes(s, 1024).map(|(bytes, _)| BinarySize(bytes))
+    }
+}
+
+/// Parses sizes like [ParsedSize] does, but a bare prefix like "M" is read as decimal
+impl FromStr for DecimalSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_bytes(s, 1000).map(|(bytes, _)| DecimalSize(bytes))
+    }
+}
+
+/// Parses a size into bytes, reading bare prefixes with the `ambiguous_base`. Also returns
+/// whether the unit was ambiguous.
+fn parse_bytes(s: &str, ambiguous_base: u128) -> Result<(u128, bool), String> {
+    let s = s.trim();
+    let number_len = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(number_len);
+    let unit = unit.trim_start();
+
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(format!("Missing number in size {}", s))
+    }
+    let digits: u128 = format!("{}{}", whole, fraction).parse()
+        .map_err(|_| format!("Invalid number in size {}", s))?;
+    let divisor = 10_u128.checked_pow(fraction.len() as u32)
+        .ok_or_else(|| format!("Too many decimal places in size {}", s))?;
+
+    let (multiplier, ambiguous) = parse_unit(unit, ambiguous_base)
+        .ok_or_else(|| format!("Unknown unit {} in size {}", unit, s))?;
+
+    let bytes = digits.checked_mul(multiplier)
+        .map(|bytes| bytes / divisor)
+        .ok_or_else(|| format!("Size {} is too large", s))?;
+
+    Ok((bytes, ambiguous))
+}
+
+/// Returns the multiplier of a unit and whether it was ambiguous
+fn parse_unit(unit: &str, ambiguous_base: u128) -> Option<(u128, bool)> {
+    let mut chars = unit.chars();
+    let prefix = match chars.next() {
+        None => return Some((1, false)),
+        Some('b') | Some('B') if chars.as_str().is_empty() => return Some((1, false)),
+        Some(prefix) => prefix.to_ascii_uppercase(),
+    };
+    let exponent = PREFIXES.iter().position(|&known| known == prefix)? as u32 + 1;
+
+    match chars.as_str() {
+        "" => Some((ambiguous_base.pow(exponent), true)),
+        "i" | "iB" | "ib" | "IB" | "Ib" => Some((1024_u128.pow(exponent), false)),
+        "B" | "b" => Some((1000_u128.pow(exponent), false)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+    const GIB: u64 = 1024 * MIB;
+    const TIB: u64 = 1024 * GIB;
+    const PIB: u64 = 1024 * TIB;
+
+    #[test]
+    fn rounded_pib_has_unit() {
+        let rounded = BinarySize::from(2 * PIB).rounded().to_string();
+        assert!(rounded.ends_with(" PiB"), "{}", rounded);
+        assert_eq!(rounded, "2.00 PiB");
+    }
+
+    #[test]
+    fn parses_what_is_displayed() {
+        assert_eq!(BinarySize::from_str("1.00 GiB").unwrap().0, GIB as u128);
+        for bytes in [512, 8 * KIB, 3 * GIB / 2, 2 * TIB] {
+            let displayed = BinarySize::from(bytes).rounded().to_string();
+            assert_eq!(BinarySize::from_str(&displayed), Ok(BinarySize::from(bytes)));
+        }
+        let displayed = DecimalSize::from(16_000_000_000).rounded().to_string();
+        assert_eq!(displayed, "16.00 GB");
+        assert_eq!(DecimalSize::from_str(&displayed), Ok(DecimalSize::from(16_000_000_000)));
+    }
+
+    #[test]
+    fn parses_units_ignoring_case_and_whitespace() {
+        assert_eq!(BinarySize::from_str("512"), Ok(BinarySize(512)));
+        assert_eq!(BinarySize::from_str(" 8kib "), Ok(BinarySize::from(8 * KIB)));
+        assert_eq!(BinarySize::from_str("16GB"), Ok(BinarySize(16_000_000_000)));
+        assert_eq!(DecimalSize::from_str("2 TiB"), Ok(DecimalSize::from(2 * TIB)));
+    }
+
+    #[test]
+    fn rejects_unknown_units_and_overflow() {
+        assert!(BinarySize::from_str("8 XiB").is_err());
+        assert!(BinarySize::from_str("1000000000000000 YiB").is_err());
+    }
+
+    #[test]
+    fn rounding_up_to_the_next_unit_promotes_it() {
+        // 1023.995 KiB and MiB round to 1024.00, which is shown as the next unit
+        let just_below_mib = 1023 * KIB + 1019;
+        assert_eq!(BinarySize::from(just_below_mib).rounded().to_string(), "1.00 MiB");
+        let just_below_gib = 1023 * MIB + 1019 * KIB;
+        assert_eq!(BinarySize::from(just_below_gib).rounded().to_string(), "1.00 GiB");
+        assert_eq!(BinarySize::from(GIB - 1).rounded().to_string(), "1.00 GiB");
+
+        // Rounding down stays below the boundary
+        let rounded = BinarySize::from(just_below_gib).rounded().rounding(RoundingMode::Down);
+        assert_eq!(rounded.to_string(), "1023.99 MiB");
+        assert_eq!(BinarySize::from(1023 * KIB).rounded().to_string(), "1023.00 KiB");
+        assert_eq!(BinarySize::from(MIB).rounded().to_string(), "1.00 MiB");
+    }
+
+    #[test]
+    fn ties_round_to_even() {
+        // 1.125 and 1.375 MiB are exact ties at two decimal places
+        let one_and_an_eighth = MIB + MIB / 8;
+        assert_eq!(BinarySize::from(one_and_an_eighth).rounded().to_string(), "1.12 MiB");
+        let rounded = BinarySize::from(one_and_an_eighth).rounded().rounding(RoundingMode::HalfUp);
+        assert_eq!(rounded.to_string(), "1.13 MiB");
+        assert_eq!(BinarySize::from(MIB + 3 * MIB / 8).rounded().to_string(), "1.38 MiB");
+    }
+}
@@ -0,0 +1,222 @@
+use std::cmp::min;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+
+// https://android.googlesource.com/platform/system/core/+/master/libsparse/sparse_format.h
+const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SparseHeader {
+    pub file_header_size: u16,
+    pub chunk_header_size: u16,
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub total_chunks: u32,
+}
+
+impl SparseHeader {
+    /// Size of the image once all chunks have been expanded
+    pub fn expanded_size(&self) -> u64 {
+        self.total_blocks as u64 * self.block_size as u64
+    }
+}
+
+fn invalid_sparse(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid sparse image: {}", message))
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn skip(reader: &mut impl Read, count: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(count), &mut io::sink())?;
+    if skipped != count {
+        return Err(Error::from(ErrorKind::UnexpectedEof))
+    }
+    Ok(())
+}
+
+/// Reads the sparse header from the start of an image.
+/// Returns `None` if the image is not an Android sparse image.
+pub fn read_sparse_header(reader: &mut impl Read) -> io::Result<Option<SparseHeader>> {
+    let mut header = [0_u8; SPARSE_HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {},
+        // Too short to be a sparse image
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    if le_u32(&header[0..4]) != SPARSE_HEADER_MAGIC {
+        return Ok(None)
+    }
+
+    let major_version = le_u16(&header[4..6]);
+    if major_version != 1 {
+        return Err(invalid_sparse(format!("unsupported major version {}", major_version)))
+    }
+
+    let header = SparseHeader {
+        file_header_size: le_u16(&header[8..10]),
+        chunk_header_size: le_u16(&header[10..12]),
+        block_size: le_u32(&header[12..16]),
+        total_blocks: le_u32(&header[16..20]),
+        total_chunks: le_u32(&header[20..24]),
+    };
+
+    if (header.file_header_size as usize) < SPARSE_HEADER_SIZE ||
+        (header.chunk_header_size as usize) < CHUNK_HEADER_SIZE {
+        return Err(invalid_sparse("header sizes are too small".into()))
+    }
+    if header.block_size == 0 || !header.block_size.is_multiple_of(4) {
+        return Err(invalid_sparse(format!("invalid block size {}", header.block_size)))
+    }
+
+    Ok(Some(header))
+}
+
+enum Chunk {
+    Raw { remaining: u64 },
+    Fill { pattern: [u8; 4], offset: u64, remaining: u64 },
+    DontCare { remaining: u64 },
+}
+
+/// Expands an Android sparse image into its raw contents while reading.
+///
+/// DONT_CARE chunks are expanded to zeros so the output always has the full size.
+pub struct SparseReader<R: Read> {
+    inner: R,
+    header: SparseHeader,
+    chunks_left: u32,
+    current: Option<Chunk>,
+}
+
+impl<R: Read> SparseReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let header = read_sparse_header(&mut inner)?
+            .ok_or_else(|| invalid_sparse("missing sparse header".into()))?;
+        skip(&mut inner, header.file_header_size as u64 - SPARSE_HEADER_SIZE as u64)?;
+
+        Ok(SparseReader {
+            inner,
+            header,
+            chunks_left: header.total_chunks,
+            current: None,
+        })
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Chunk>> {
+        while self.chunks_left > 0 {
+            self.chunks_left -= 1;
+
+            let mut chunk_header = [0_u8; CHUNK_HEADER_SIZE];
+            self.inner.read_exact(&mut chunk_header)?;
+            skip(
+                &mut self.inner,
+                self.header.chunk_header_size as u64 - CHUNK_HEADER_SIZE as u64
+            )?;
+
+            let chunk_type = le_u16(&chunk_header[0..2]);
+            let chunk_size = le_u32(&chunk_header[4..8]) as u64 * self.header.block_size as u64;
+            let data_size = (le_u32(&chunk_header[8..12]) as u64)
+                .checked_sub(self.header.chunk_header_size as u64)
+                .ok_or_else(|| invalid_sparse("chunk is smaller than its header".into()))?;
+
+            match chunk_type {
+                CHUNK_TYPE_RAW => {
+                    if data_size != chunk_size {
+                        return Err(invalid_sparse(format!(
+                            "raw chunk has {} bytes of data, expected {}", data_size, chunk_size
+                        )))
+                    }
+                    return Ok(Some(Chunk::Raw { remaining: chunk_size }))
+                },
+                CHUNK_TYPE_FILL => {
+                    let mut pattern = [0_u8; 4];
+                    self.inner.read_exact(&mut pattern)?;
+                    return Ok(Some(Chunk::Fill { pattern, offset: 0, remaining: chunk_size }))
+                },
+                CHUNK_TYPE_DONT_CARE => {
+                    return Ok(Some(Chunk::DontCare { remaining: chunk_size }))
+                },
+                CHUNK_TYPE_CRC32 => {
+                    // The checksum doesn't contribute any data
+                    skip(&mut self.inner, data_size)?;
+                },
+                _ => return Err(invalid_sparse(format!("unknown chunk type {:#x}", chunk_type))),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+
+        loop {
+            let chunk = match self.current.as_mut() {
+                Some(chunk) => chunk,
+                None => match self.next_chunk()? {
+                    Some(chunk) => self.current.insert(chunk),
+                    None => return Ok(0),
+                }
+            };
+
+            let read = match chunk {
+                Chunk::Raw { remaining } => {
+                    let len = min(buf.len() as u64, *remaining) as usize;
+                    let read = self.inner.read(&mut buf[..len])?;
+                    if read == 0 && len > 0 {
+                        return Err(Error::from(ErrorKind::UnexpectedEof))
+                    }
+                    *remaining -= read as u64;
+                    read
+                },
+                Chunk::Fill { pattern, offset, remaining } => {
+                    let len = min(buf.len() as u64, *remaining) as usize;
+                    for (i, byte) in buf[..len].iter_mut().enumerate() {
+                        *byte = pattern[((*offset + i as u64) % 4) as usize];
+                    }
+                    *offset += len as u64;
+                    *remaining -= len as u64;
+                    len
+                },
+                Chunk::DontCare { remaining } => {
+                    let len = min(buf.len() as u64, *remaining) as usize;
+                    buf[..len].fill(0);
+                    *remaining -= len as u64;
+                    len
+                },
+            };
+
+            let finished = match chunk {
+                Chunk::Raw { remaining } |
+                Chunk::Fill { remaining, .. } |
+                Chunk::DontCare { remaining } => *remaining == 0,
+            };
+            if finished {
+                self.current = None;
+            }
+
+            if read > 0 {
+                return Ok(read)
+            }
+        }
+    }
+}
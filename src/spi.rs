@@ -0,0 +1,228 @@
+use std::fs::{read, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::path::{Path, PathBuf};
+use crate::error::FlashError;
+use crate::event::FlashEvent;
+use crate::idbloader;
+use crate::partition::RawImage;
+
+/// Where the SPL looks for U-Boot in SPI NOR flash on most boards, e.g. the RK3399 and RK3568
+pub const DEFAULT_SPI_UBOOT_OFFSET: u64 = 0x60000;
+/// Size of the SPI NOR flash most boards have
+pub const DEFAULT_SPI_SIZE: u64 = 16 * 1024 * 1024;
+
+/// NOR flash erases to all ones, anything else would have to be erased again first
+const ERASED_BYTE: u8 = 0xFF;
+
+const IDBLOADER_NAME: &str = "idbloader";
+/// The boot ROM only reads the first half of every 4 KiB of SPI flash
+const SPI_SECTOR_SIZE: usize = 2048;
+
+// <mtd/mtd-abi.h>
+const MEMGETINFO: u64 = 0x80204d01;
+const MEMERASE: u64 = 0x40084d02;
+
+#[repr(C)]
+#[derive(Default)]
+struct MtdInfoUser {
+    mtd_type: u8,
+    flags: u32,
+    size: u32,
+    erase_size: u32,
+    write_size: u32,
+    oob_size: u32,
+    padding: u64,
+}
+
+#[repr(C)]
+struct EraseInfoUser {
+    start: u32,
+    length: u32,
+}
+
+/// Everything that should end up in SPI NOR flash, which has no partition table
+#[derive(Clone, Debug)]
+pub struct SpiPlan {
+    /// Image file or MTD character device (e.g. /dev/mtd0) to write to
+    pub destination: PathBuf,
+    /// Size of the image file, MTD devices have their own
+    pub size: u64,
+    /// Written to offset 0, where the boot ROM looks for it in SPI flash
+    pub idbloader: PathBuf,
+    /// Refuse an idbloader that doesn't start with a Rockchip boot header
+    pub check_idbloader: bool,
+    /// Images written after the idbloader, e.g. U-Boot at [DEFAULT_SPI_UBOOT_OFFSET]
+    pub raw_images: Vec<RawImage>,
+}
+
+impl SpiPlan {
+    pub fn new(destination: PathBuf, idbloader: PathBuf) -> Self {
+        SpiPlan {
+            destination,
+            size: DEFAULT_SPI_SIZE,
+            idbloader,
+            check_idbloader: true,
+            raw_images: vec![],
+        }
+    }
+}
+
+/// Size of the MTD device at `file`
+fn mtd_size(file: &File) -> io::Result<u64> {
+    let mut info = MtdInfoUser::default();
+    match unsafe { libc::ioctl(file.as_raw_fd(), MEMGETINFO as _, &mut info) } {
+        0 => Ok(u64::from(info.size)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Size of the MTD device at `path`, `None` if it is an image file
+pub fn mtd_device_size(path: &Path) -> Result<Option<u64>, FlashError> {
+    let is_mtd = path.metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_char_device());
+    if !is_mtd {
+        return Ok(None)
+    }
+
+    File::open(path)
+        .and_then(|file| mtd_size(&file))
+        .map(Some)
+        .map_err(|source| FlashError::DeviceAccess { path: path.into(), source })
+}
+
+/// Erases the whole MTD device, NOR flash can only clear bits when it is written
+fn erase_mtd(file: &File, size: u64) -> io::Result<()> {
+    let erase_info = EraseInfoUser { start: 0, length: size as u32 };
+    match unsafe { libc::ioctl(file.as_raw_fd(), MEMERASE as _, &erase_info) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Builds the flat image of `size` bytes with the idbloader and all raw images at their offsets
+/// and the rest erased
+fn spi_image(
+    plan: &SpiPlan,
+    size: u64,
+    on_event: &mut dyn FnMut(FlashEvent),
+) -> Result<Vec<u8>, FlashError> {
+    let idbloader = RawImage {
+        name: IDBLOADER_NAME.into(),
+        source_file: plan.idbloader.clone(),
+        offset: 0,
+    };
+    let mut images: Vec<&RawImage> = std::iter::once(&idbloader)
+        .chain(&plan.raw_images)
+        .collect();
+    images.sort_by_key(|image| image.offset);
+
+    let mut spi_image = vec![ERASED_BYTE; size as usize];
+    let mut end = 0;
+    let mut previous: Option<&RawImage> = None;
+    for image in images {
+        let mut contents = read(&image.source_file)
+            .map_err(|err| FlashError::source_access(image.source_file.clone(), err))?;
+        if image.name == IDBLOADER_NAME && is_sd_idbloader(&contents) {
+            on_event(FlashEvent::SpreadingIdbloader);
+            contents = spi_idbloader(&contents);
+        }
+        if let Some(previous) = previous.filter(|_| image.offset < end) {
+            return Err(FlashError::PartitionOverlap {
+                partition: image.name.clone(),
+                other: previous.name.clone(),
+            })
+        }
+        let image_end = image.offset.checked_add(contents.len() as u64)
+            .filter(|image_end| *image_end <= size)
+            .ok_or_else(|| FlashError::ImageTooLarge {
+                partition: image.name.clone(),
+                path: image.source_file.clone(),
+                size: Some(contents.len() as u64),
+                available: size.saturating_sub(image.offset),
+            })?;
+
+        on_event(FlashEvent::AddingRawImage {
+            name: image.name.clone(),
+            offset: image.offset,
+            size: contents.len() as u64,
+        });
+        spi_image[image.offset as usize..image_end as usize].copy_from_slice(&contents);
+        end = image_end;
+        previous = Some(image);
+    }
+
+    Ok(spi_image)
+}
+
+/// Writes the idbloader and U-Boot of `plan` to SPI NOR flash or an image of it. There is no
+/// partition table, the images are placed at their offsets and the space in between is left
+/// erased.
+///
+/// MTD devices are erased and written as a whole, image files are created with the size
+/// given in the plan.
+pub fn flash_spi(plan: SpiPlan, mut on_event: impl FnMut(FlashEvent)) -> Result<(), FlashError> {
+    let on_event: &mut dyn FnMut(FlashEvent) = &mut on_event;
+    let destination = plan.destination.clone();
+    let device_error = |source| FlashError::DeviceAccess { path: destination.clone(), source };
+
+    if plan.check_idbloader {
+        idbloader::check_idbloader(&plan.idbloader)?;
+    }
+
+    let is_mtd = destination.metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_char_device());
+    let mtd = match is_mtd {
+        true => Some(OpenOptions::new().write(true).open(&destination).map_err(device_error)?),
+        false => None,
+    };
+    let size = match &mtd {
+        Some(mtd) => mtd_size(mtd).map_err(device_error)?,
+        None if plan.size == 0 => return Err(FlashError::MissingImageSize {
+            path: destination.clone(),
+            required: None,
+        }),
+        None => plan.size,
+    };
+    on_event(FlashEvent::Destination { path: destination.clone(), size, is_block_device: false });
+
+    // An image file is only replaced once everything is known to fit
+    let spi_image = spi_image(&plan, size, on_event)?;
+    let file = match mtd {
+        Some(mtd) => {
+            on_event(FlashEvent::ErasingFlash { size });
+            erase_mtd(&mtd, size).map_err(device_error)?;
+            mtd
+        },
+        None => File::create(&destination).map_err(device_error)?,
+    };
+    on_event(FlashEvent::WritingSpiImage { size });
+    file.write_all_at(&spi_image, 0).map_err(device_error)?;
+
+    on_event(FlashEvent::FlushingDestination { path: destination.clone() });
+    file.sync_all().map_err(device_error)?;
+
+    on_event(FlashEvent::FlashComplete);
+    Ok(())
+}
+
+/// Spreads an idbloader for SD cards and eMMC out for SPI flash, where the boot ROM only reads
+/// the first 2 KiB of every 4 KiB, like `mkimage -T rkspi` does
+pub fn spi_idbloader(idbloader: &[u8]) -> Vec<u8> {
+    idbloader.chunks(SPI_SECTOR_SIZE)
+        .flat_map(|sector| {
+            let mut spread = sector.to_vec();
+            spread.resize(2 * SPI_SECTOR_SIZE, 0);
+            spread
+        })
+        .collect()
+}
+
+/// Whether `idbloader` is laid out for SD cards and eMMC (`mkimage -T rksd`). Its first stage
+/// follows the 2 KiB header right away, while the 2 KiB after the header are left empty when
+/// it is spread out for SPI flash.
+pub fn is_sd_idbloader(idbloader: &[u8]) -> bool {
+    idbloader.get(SPI_SECTOR_SIZE..2 * SPI_SECTOR_SIZE)
+        .is_some_and(|sector| sector.iter().any(|byte| *byte != 0))
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use flate2::Crc;
+use gpt::disk::LogicalBlockSize;
+use gpt::partition::Partition;
+use serde::{Deserialize, Serialize};
+use crate::error::FlashError;
+
+/// A partition as it is stored in the partition table
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PartitionInfo {
+    /// Number of the partition, starting at 1
+    pub index: u32,
+    pub name: String,
+    pub type_guid: String,
+    pub unique_guid: String,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    /// Size in bytes
+    pub size: u64,
+    /// GPT attribute flags, manifests written before they were recorded have none
+    #[serde(default)]
+    pub flags: u64,
+}
+
+/// A partition table read from a disk or image file, with the space between the partitions
+#[derive(Clone, Debug, Serialize)]
+pub struct DiskInfo {
+    pub disk_guid: String,
+    pub lba_size: u64,
+    /// Range of LBAs partitions can be placed in, outside of both partition tables
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub partitions: Vec<PartitionInfo>,
+    pub free_regions: Vec<FreeRegion>,
+}
+
+/// Usable space that isn't part of any partition
+#[derive(Clone, Debug, Serialize)]
+pub struct FreeRegion {
+    pub first_lba: u64,
+    pub last_lba: u64,
+    /// Size in bytes
+    pub size: u64,
+}
+
+pub(crate) fn partition_infos(
+    partitions: &BTreeMap<u32, Partition>,
+    lba: LogicalBlockSize,
+) -> Vec<PartitionInfo> {
+    partitions.iter()
+        .filter(|(_, partition)| partition.is_used())
+        .map(|(index, partition)| PartitionInfo {
+            index: *index,
+            name: partition.name.clone(),
+            type_guid: partition.part_type_guid.guid.to_uppercase(),
+            unique_guid: partition.part_guid.to_string().to_uppercase(),
+            first_lba: partition.first_lba,
+            last_lba: partition.last_lba,
+            size: (partition.last_lba + 1 - partition.first_lba) * u64::from(lba),
+            flags: partition.flags,
+        })
+        .collect()
+}
+
+/// Reads the partition table of a disk or image file without changing anything
+pub fn read_partition_table(
+    path: impl AsRef<Path>,
+    lba: LogicalBlockSize,
+) -> Result<Vec<PartitionInfo>, FlashError> {
+    let path = path.as_ref();
+    let cfg = gpt::GptConfig::new()
+        .initialized(true)
+        .writable(false)
+        .logical_block_size(lba);
+
+    let disk = cfg.open(path)
+        .map_err(|source| FlashError::GptRead { path: path.into(), source })?;
+
+    Ok(partition_infos(disk.partitions(), lba))
+}
+
+/// Reads the partition table of a disk or image file along with the disk GUID and the free
+/// space between the partitions, without changing anything
+pub fn read_disk_info(
+    path: impl AsRef<Path>,
+    lba: LogicalBlockSize,
+) -> Result<DiskInfo, FlashError> {
+    let path = path.as_ref();
+    let lba_size = u64::from(lba);
+    let cfg = gpt::GptConfig::new()
+        .initialized(true)
+        .writable(false)
+        .logical_block_size(lba);
+
+    let disk = cfg.open(path)
+        .map_err(|source| FlashError::GptRead { path: path.into(), source })?;
+    let header = disk.primary_header().or_else(|| disk.backup_header())
+        .ok_or_else(|| FlashError::InvalidPartitionTable {
+            path: path.into(),
+            message: "neither the primary nor the backup header could be read".into(),
+        })?;
+    let free_regions = disk.find_free_sectors().into_iter()
+        .filter(|(_, length)| *length > 0)
+        .map(|(first_lba, length)| FreeRegion {
+            first_lba,
+            last_lba: first_lba + length - 1,
+            size: length * lba_size,
+        })
+        .collect();
+
+    Ok(DiskInfo {
+        disk_guid: disk.guid().to_string().to_uppercase(),
+        lba_size,
+        first_usable_lba: header.first_usable,
+        last_usable_lba: header.last_usable,
+        partitions: partition_infos(disk.partitions(), lba),
+        free_regions,
+    })
+}
+
+/// The fields of a GPT header that are needed to check it
+struct GptHeader {
+    current_lba: u64,
+    backup_lba: u64,
+    disk_guid: [u8; 16],
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buffer)
+}
+
+/// Reads the GPT header at `header_lba` and checks its signature and both checksums
+fn read_gpt_header(
+    file: &mut File,
+    header_lba: u64,
+    lba_size: u64,
+    which: &str,
+) -> Result<GptHeader, String> {
+    let mut block = vec![0; lba_size as usize];
+    read_at(file, header_lba * lba_size, &mut block)
+        .map_err(|err| format!("failed to read the {} header: {}", which, err))?;
+
+    let u32_at = |offset: usize| u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap());
+
+    if &block[0..8] != b"EFI PART" {
+        return Err(format!("the {} header is missing", which))
+    }
+    let header_size = u32_at(12) as usize;
+    if !(92..=block.len()).contains(&header_size) {
+        return Err(format!("the {} header has an invalid size of {} bytes", which, header_size))
+    }
+    let mut header_bytes = block[..header_size].to_vec();
+    header_bytes[16..20].fill(0);
+    if crc32(&header_bytes) != u32_at(16) {
+        return Err(format!("the checksum of the {} header doesn't match", which))
+    }
+
+    let header = GptHeader {
+        current_lba: u64_at(24),
+        backup_lba: u64_at(32),
+        disk_guid: block[56..72].try_into().unwrap(),
+        entries_lba: u64_at(72),
+        entry_count: u32_at(80),
+        entry_size: u32_at(84),
+        entries_crc: u32_at(88),
+    };
+    if header.current_lba != header_lba {
+        return Err(format!(
+            "the {} header at LBA {} claims to be at LBA {}", which, header_lba, header.current_lba
+        ))
+    }
+
+    let mut entries = vec![0; header.entry_count as usize * header.entry_size as usize];
+    read_at(file, header.entries_lba * lba_size, &mut entries)
+        .map_err(|err| format!("failed to read the {} partition entries: {}", which, err))?;
+    if crc32(&entries) != header.entries_crc {
+        return Err(format!("the checksum of the {} partition entries doesn't match", which))
+    }
+
+    Ok(header)
+}
+
+/// Checks that both the primary GPT and its backup in the last LBA of the disk or image file
+/// are intact and describe the same partitions
+pub fn validate_partition_table(
+    path: impl AsRef<Path>,
+    lba: LogicalBlockSize,
+) -> Result<(), FlashError> {
+    let path = path.as_ref();
+    let lba_size = u64::from(lba);
+    let invalid = |message: String| FlashError::InvalidPartitionTable { path: path.into(), message };
+
+    let mut file = File::open(path)
+        .map_err(|source| FlashError::GptRead { path: path.into(), source })?;
+    // Block devices report their size when seeking to the end, just like files
+    let last_lba = (file.seek(SeekFrom::End(0))
+        .map_err(|source| FlashError::GptRead { path: path.into(), source })? / lba_size)
+        .checked_sub(1)
+        .ok_or_else(|| invalid("the destination is empty".into()))?;
+
+    let primary = read_gpt_header(&mut file, 1, lba_size, "primary").map_err(invalid)?;
+    let backup = read_gpt_header(&mut file, last_lba, lba_size, "backup").map_err(invalid)?;
+
+    if primary.backup_lba != last_lba {
+        return Err(invalid(format!(
+            "the primary header expects the backup at LBA {} instead of {}",
+            primary.backup_lba, last_lba
+        )))
+    }
+    if backup.backup_lba != 1 {
+        return Err(invalid(format!(
+            "the backup header expects the primary header at LBA {}", backup.backup_lba
+        )))
+    }
+    if primary.disk_guid != backup.disk_guid || primary.entries_crc != backup.entries_crc {
+        return Err(invalid("the primary and backup partition tables differ".into()))
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::fs::{remove_file, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A file in the temporary directory that is removed again when it goes out of scope
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Creates an empty file that only the current user can access. The name is random and
+    /// the file must not exist yet, so a symlink planted in the temporary directory can't
+    /// redirect what is written to it, which matters as rockflasher usually runs as root.
+    pub fn new(purpose: &str) -> io::Result<Self> {
+        loop {
+            let path = std::env::temp_dir()
+                .join(format!("rockflasher-{}-{}.img", purpose, Uuid::new_v4().simple()));
+            match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+                Ok(_) => return Ok(TempFile(path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn temp_files_are_private_and_removed_again() {
+        let file = TempFile::new("test-private").unwrap();
+        let path = file.path().to_path_buf();
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        assert_ne!(TempFile::new("test-private").unwrap().path(), path);
+
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,39 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Keeps the average rate of writes at or below a limit using a token bucket.
+///
+/// Every written byte takes a token, tokens are refilled at the limit. Up to a quarter
+/// second worth of tokens can be saved up, so short pauses don't slow down the writes
+/// around them, but also don't allow a burst far beyond the limit afterwards.
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: u64,
+    /// Can go negative when a write is larger than the tokens left, the next one waits then
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes the tokens for writing `bytes`, waiting until they're paid off if there aren't enough
+    pub fn acquire(&mut self, bytes: u64) {
+        let rate = self.rate as f64;
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * rate)
+            .min(rate / 4.0);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-self.tokens / rate));
+        }
+    }
+}